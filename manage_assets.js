@@ -67,6 +67,7 @@ function uploadFile(filePath) {
     content_type = \\"${contentType}\\";
     data = blob \\"${base64Content}\\";
     description = opt \\"Asset for ${options.projectName}\\";
+    encoding = opt variant { Base64 };
   })"`;
   
   try {
@@ -107,6 +108,7 @@ function updateAndUploadSvg(filePath) {
     content_type = \\"image/svg+xml\\";
     data = blob \\"${base64Content}\\";
     description = opt \\"Updated SVG for ${options.projectName}\\";
+    encoding = opt variant { Base64 };
   })"`;
   
   try {
@@ -0,0 +1,158 @@
+// ICRC-3 archive canister
+//
+// Holds a contiguous range of blocks moved out of the token canister's log.
+// Only the token canister that installed it may append; anyone may read.
+
+use candid::{CandidType, Deserialize, Func, Int, Nat, Principal};
+use ic_cdk::api::caller;
+use ic_cdk_macros::*;
+use serde::Serialize;
+use std::cell::RefCell;
+
+const MAX_BLOCKS_PER_REQUEST: u64 = 100;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+enum Value {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(Vec<u8>),
+    Map(Vec<(String, Value)>),
+    Array(Vec<Value>),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ArchiveInitArgs {
+    ledger_id: Principal,  // Token canister allowed to append blocks
+    first_index: u64,      // Global index of the first block stored here
+    max_blocks: u64,       // Capacity of this archive
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, Default)]
+struct ArchiveState {
+    ledger_id: Option<Principal>,
+    first_index: u64,
+    max_blocks: u64,
+    blocks: Vec<Value>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct GetBlocksArgs {
+    start: Nat,
+    length: Nat,
+}
+
+#[derive(Clone, Debug, CandidType)]
+struct BlockWithId {
+    id: Nat,
+    block: Value,
+}
+
+#[derive(Clone, Debug, CandidType)]
+struct ArchivedBlocks {
+    args: Vec<GetBlocksArgs>,
+    callback: Func,
+}
+
+#[derive(Clone, Debug, CandidType)]
+struct GetBlocksResult {
+    log_length: Nat,
+    blocks: Vec<BlockWithId>,
+    archived_blocks: Vec<ArchivedBlocks>, // Always empty: archives never point further back
+}
+
+thread_local! {
+    static STATE: RefCell<ArchiveState> = RefCell::new(ArchiveState::default());
+}
+
+#[init]
+fn init(args: ArchiveInitArgs) {
+    STATE.with(|state| {
+        *state.borrow_mut() = ArchiveState {
+            ledger_id: Some(args.ledger_id),
+            first_index: args.first_index,
+            max_blocks: args.max_blocks,
+            blocks: Vec::new(),
+        };
+    });
+}
+
+// Append blocks to the end of the archived range. Returns the new archived length.
+#[update]
+fn append_blocks(blocks: Vec<Value>) -> Result<u64, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        
+        if state.ledger_id != Some(caller()) {
+            return Err("Only the token canister can append blocks".to_string());
+        }
+        
+        if state.blocks.len() as u64 + blocks.len() as u64 > state.max_blocks {
+            return Err(format!("Archive is full ({} blocks max)", state.max_blocks));
+        }
+        
+        state.blocks.extend(blocks);
+        Ok(state.blocks.len() as u64)
+    })
+}
+
+#[query]
+fn remaining_capacity() -> u64 {
+    STATE.with(|state| {
+        let state = state.borrow();
+        state.max_blocks.saturating_sub(state.blocks.len() as u64)
+    })
+}
+
+#[query]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let end_index = state.first_index + state.blocks.len() as u64;
+        let mut budget = MAX_BLOCKS_PER_REQUEST;
+        let mut blocks = Vec::new();
+        
+        for range in args {
+            let start: u64 = range.start.0.try_into().unwrap_or(u64::MAX);
+            let length: u64 = range.length.0.try_into().unwrap_or(u64::MAX);
+            // Clamp to the blocks held here only after the requested end is known
+            let end = start.saturating_add(length).min(end_index);
+            let start = start.max(state.first_index);
+            let end = end.min(start.saturating_add(budget));
+            
+            for id in start..end {
+                blocks.push(BlockWithId {
+                    id: Nat::from(id),
+                    block: state.blocks[(id - state.first_index) as usize].clone(),
+                });
+            }
+            budget -= end.saturating_sub(start);
+            if budget == 0 {
+                break;
+            }
+        }
+        
+        GetBlocksResult {
+            log_length: Nat::from(end_index),
+            blocks,
+            archived_blocks: Vec::new(),
+        }
+    })
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    STATE.with(|state| {
+        ic_cdk::storage::stable_save((state.borrow().clone(),)).unwrap();
+    });
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // Starting empty would silently drop the archived blocks, so refuse the upgrade instead
+    let (saved,) = ic_cdk::storage::stable_restore::<(ArchiveState,)>()
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to restore archive state: {}; refusing to upgrade", e)));
+    STATE.with(|state| {
+        *state.borrow_mut() = saved;
+    });
+}
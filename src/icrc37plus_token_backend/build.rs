@@ -0,0 +1,23 @@
+// Embed the archive canister module so the token canister can spawn archives.
+//
+// dfx builds `icrc37plus_archive` first (it is a dependency in dfx.json); a
+// prebuilt module can also be supplied through ARCHIVE_WASM_PATH. When neither
+// exists an empty module is embedded and archiving stays disabled.
+
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=ARCHIVE_WASM_PATH");
+    
+    let wasm_path = env::var("ARCHIVE_WASM_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap())
+                .join("../../target/wasm32-unknown-unknown/release/icrc37plus_archive.wasm")
+        });
+    println!("cargo:rerun-if-changed={}", wasm_path.display());
+    
+    let wasm = fs::read(&wasm_path).unwrap_or_default();
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("archive.wasm");
+    fs::write(out_path, wasm).unwrap();
+}
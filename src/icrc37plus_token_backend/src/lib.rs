@@ -1,10 +1,19 @@
 // ICRC-37 Compliant NFT Canister with Minting and Whitelist Functionality
 
-use candid::{CandidType, Deserialize, Principal, Nat};
+use candid::{CandidType, Deserialize, Func, Int, Principal, Nat};
 use serde::Serialize;
-use ic_cdk::api::{caller, time};
+// Unit tests run natively, where the system API is unavailable
+#[cfg(not(test))]
+use ic_cdk::api::{caller, data_certificate, id as canister_id, instruction_counter, set_certified_data, time};
+#[cfg(test)]
+use tests::system::{caller, canister_id, data_certificate, instruction_counter, set_certified_data, time};
 use ic_cdk_macros::*;
-use std::{cell::RefCell, collections::HashMap, cmp::Ordering};
+use ic_certified_map::{fork, fork_hash, labeled, labeled_hash, leaf_hash, AsHashTree, Hash, HashTree, RbTree};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, Memory as _, StableBTreeMap, StableCell, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell, collections::{BTreeSet, HashMap, HashSet}, ops::Bound};
 // use std::convert::TryInto;  // Commented out unused import
 
 // Define admin types
@@ -36,6 +45,25 @@ pub struct MintSchedule {
     pub end_time: Option<u64>,           // End time in nanoseconds since epoch (None = no end restriction)
     pub active: bool,                    // Whether this schedule is currently active
     pub whitelist_only: bool,            // Whether this schedule is only for whitelisted users
+    pub payment_ledger: Option<Principal>, // ICRC-1/ICRC-2 ledger for this schedule (None = collection default)
+    pub payment_symbol: Option<String>,  // Display symbol of the payment token (e.g. "ckBTC")
+    pub payment_decimals: Option<u8>,    // Decimals of the payment token (None = 8, as for ICP)
+}
+
+// Payment token accepted by a mint schedule
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct PaymentCurrency {
+    pub ledger: Principal,
+    pub symbol: Option<String>,
+    pub decimals: u8,
+}
+
+// A bundle price together with the token it is paid in
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PricedBundle {
+    pub quantity: u64,
+    pub price: Nat,
+    pub currency: PaymentCurrency,
 }
 
 // Collection metadata and configuration
@@ -47,42 +75,86 @@ pub struct CollectionDetails {
     pub max_supply: Option<u64>,
     pub base_url: String,
     pub logo: Option<String>,
+    // Token name template, "{collection}" and "{id}" are substituted at mint time
+    pub token_name_template: Option<String>,
     // Pricing
     pub pricing_enabled: bool,
+    pub payment_ledger: Option<Principal>,        // Ledger used for mint payments (None = ICP ledger)
+    pub treasury_subaccount: Option<Vec<u8>>,     // Subaccount of this canister receiving payments
+    pub admin_burn_enabled: Option<bool>,         // Let system admins burn any token for moderation (None = off)
+    pub max_query_batch_size: Option<u16>,        // None = MAX_QUERY_BATCH_SIZE
+    pub max_update_batch_size: Option<u16>,       // None = MAX_UPDATE_BATCH_SIZE
+    pub metadata_frozen_at: Option<u64>,          // Set once by freeze_metadata, never cleared
     // Schedules collection instead of individual time fields
     pub mint_schedules: Vec<MintSchedule>,
 }
 
-// NFT Counter for tracking token IDs
-struct Counter {
-    counter: u64,
-}
-
-impl Counter {
-    fn new() -> Self {
-        Self { counter: 0 }
-    }
+// Storage: large collections live in stable structures, small configuration on the heap
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
     
-    fn get(&self) -> u64 {
-        self.counter
-    }
+    static NFTS: RefCell<StableBTreeMap<u64, NFT, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(NFTS_MEMORY_ID)));
+    // Transfer history per token, keyed by (token_id, sequence number)
+    static TRANSFER_HISTORY: RefCell<StableBTreeMap<(u64, u64), TransferRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(TRANSFER_HISTORY_MEMORY_ID)));
+    // Ownership index: (owner account, token_id)
+    static OWNER_TOKENS: RefCell<StableBTreeMap<(Account, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(OWNER_TOKENS_MEMORY_ID)));
+    static TOKEN_ASSETS: RefCell<StableBTreeMap<u64, AssetKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(TOKEN_ASSETS_MEMORY_ID)));
+    // Asset metadata, with the content split into ASSET_CHUNK_SIZE chunks
+    static ASSETS: RefCell<StableBTreeMap<AssetKey, AssetMetadata, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(ASSETS_MEMORY_ID)));
+    static ASSET_CHUNKS: RefCell<StableBTreeMap<(AssetKey, u32), AssetChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(ASSET_CHUNKS_MEMORY_ID)));
+    // Assets that have been minted already
+    static MINTED_ASSETS: RefCell<StableBTreeMap<AssetKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(MINTED_ASSETS_MEMORY_ID)));
+    // ICRC-37 approvals: (token_id, spender) and (owner, spender)
+    static TOKEN_APPROVALS: RefCell<StableBTreeMap<(u64, Account), ApprovalInfo, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(TOKEN_APPROVALS_MEMORY_ID)));
+    static COLLECTION_APPROVALS: RefCell<StableBTreeMap<(Account, Account), ApprovalInfo, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(COLLECTION_APPROVALS_MEMORY_ID)));
+    // ICRC-3 blocks still held locally, keyed by block index
+    static BLOCKS: RefCell<StableBTreeMap<u64, Value, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(BLOCKS_MEMORY_ID)));
+    // Ledger blocks already redeemed for a mint: (ledger, block index)
+    static USED_PAYMENT_BLOCKS: RefCell<StableBTreeMap<(Blob<29>, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(USED_PAYMENT_BLOCKS_MEMORY_ID)));
+    // Recent transactions that set created_at_time: tx hash -> block index,
+    // plus (created_at_time, tx hash) so expired entries can be pruned in order
+    static TX_DEDUP: RefCell<StableBTreeMap<Blob<32>, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(TX_DEDUP_MEMORY_ID)));
+    static TX_DEDUP_EXPIRY: RefCell<StableBTreeMap<(u64, Blob<32>), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(TX_DEDUP_EXPIRY_MEMORY_ID)));
+    // Tokens whose metadata has been frozen individually
+    static FROZEN_TOKENS: RefCell<StableBTreeMap<u64, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(FROZEN_TOKENS_MEMORY_ID)));
+    // Assets whose bytes are locked because a frozen token references them
+    static FROZEN_ASSETS: RefCell<StableBTreeMap<AssetKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(FROZEN_ASSETS_MEMORY_ID)));
+    // Open chunked upload sessions and their staged content, keyed by (batch_id, piece)
+    static UPLOAD_BATCHES: RefCell<StableBTreeMap<u64, UploadBatch, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(UPLOAD_BATCHES_MEMORY_ID)));
+    static UPLOAD_CHUNKS: RefCell<StableBTreeMap<(u64, u32), AssetChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(UPLOAD_CHUNKS_MEMORY_ID)));
+    // HTTP cache policies by asset key prefix
+    static CACHE_POLICIES: RefCell<StableBTreeMap<AssetKey, CachePolicy, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(CACHE_POLICIES_MEMORY_ID)));
+    // Precompressed asset variants, keyed by ((asset key, encoding id), chunk index)
+    static ENCODED_CHUNKS: RefCell<StableBTreeMap<(EncodedAssetKey, u32), AssetChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(ENCODED_CHUNKS_MEMORY_ID)));
     
-    fn increment(&mut self) -> u64 {
-        self.counter += 1;
-        self.counter
-    }
-}
-
-// In-memory storage using thread_local
-thread_local! {
-    static TOKEN_ID_COUNTER: RefCell<u64> = RefCell::new(0);
-    static NFTS: RefCell<HashMap<u64, NFT>> = RefCell::new(HashMap::new());
-    static TOKENS: RefCell<HashMap<u64, Principal>> = RefCell::new(HashMap::new());
-    static TOKEN_ASSETS: RefCell<HashMap<u64, String>> = RefCell::new(HashMap::new());
-    static OWNER_TOKENS: RefCell<HashMap<Principal, Vec<u64>>> = RefCell::new(HashMap::new());
+    static TOKEN_ID_COUNTER: RefCell<u64> = const { RefCell::new(0) };
+    static NEXT_UPLOAD_BATCH_ID: RefCell<u64> = const { RefCell::new(0) };
+    // Certified asset responses, rebuilt from ASSETS after an upgrade
+    static ASSET_CERT_TREE: RefCell<RbTree<Vec<u8>, CertNode>> = const { RefCell::new(RbTree::new()) };
+    // Tokens destroyed so far; TOKEN_ID_COUNTER keeps counting every token ever minted
+    static BURNED_COUNT: RefCell<u64> = const { RefCell::new(0) };
     static WHITELIST: RefCell<HashMap<Principal, bool>> = RefCell::new(HashMap::new());
     static ADMINS: RefCell<HashMap<Principal, AdminType>> = RefCell::new(HashMap::new());
-    static NFT_COUNTER: RefCell<Counter> = RefCell::new(Counter::new());
     static COLLECTION_DETAILS: RefCell<CollectionDetails> = RefCell::new(CollectionDetails {
         name: "ICRC-37+ NFT".to_string(),
         symbol: "ICRC37+".to_string(),
@@ -90,6 +162,7 @@ thread_local! {
         max_supply: Some(1000),
         base_url: "https://example.com/api".to_string(),
         logo: None,
+        token_name_template: None,
         mint_schedules: vec![
             MintSchedule {
                 name: "Standard".to_string(),
@@ -98,6 +171,9 @@ thread_local! {
                 end_time: None,
                 active: false,
                 whitelist_only: false,
+                payment_ledger: None,
+                payment_symbol: None,
+                payment_decimals: None,
             },
             MintSchedule {
                 name: "Whitelist".to_string(),
@@ -106,22 +182,375 @@ thread_local! {
                 end_time: None,
                 active: false,
                 whitelist_only: true,
+                payment_ledger: None,
+                payment_symbol: None,
+                payment_decimals: None,
             },
         ],
         // Initialize pricing
         pricing_enabled: false,
+        payment_ledger: None,
+        treasury_subaccount: None,
+        admin_burn_enabled: None,
+        max_query_batch_size: None,
+        max_update_batch_size: None,
+        metadata_frozen_at: None,
     });
-    // Simple asset storage implementation
-    static ASSETS: RefCell<HashMap<String, Asset>> = RefCell::new(HashMap::new());
-    // Track which assets have been minted already
-    static MINTED_ASSETS: RefCell<HashMap<String, bool>> = RefCell::new(HashMap::new());
-    // ICRC-37 Approvals storage
-    static TOKEN_APPROVALS: RefCell<HashMap<u64, HashMap<Principal, ApprovalInfo>>> = RefCell::new(HashMap::new());
-    static COLLECTION_APPROVALS: RefCell<HashMap<Principal, HashMap<Principal, ApprovalInfo>>> = RefCell::new(HashMap::new());
-    // ICRC-3 Transaction log storage
-    static TRANSACTIONS: RefCell<Vec<Transaction>> = RefCell::new(Vec::new());
-    static TRANSACTION_ID_COUNTER: RefCell<u64> = RefCell::new(0);
+    // Assets held for mints that are waiting on a ledger call (not persisted)
+    static MINT_RESERVATIONS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+    // Position and hash of the ICRC-3 log tip
+    static LOG_TIP: RefCell<LogTip> = RefCell::new(LogTip::default());
     static ARCHIVES: RefCell<Vec<ArchiveInfo>> = RefCell::new(Vec::new());
+    static ARCHIVE_STATE: RefCell<ArchiveState> = RefCell::new(ArchiveState::default());
+    // Set while blocks are being moved to an archive (not persisted)
+    static ARCHIVING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+// ==== STABLE STORAGE ====
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+// An asset key paired with a ContentEncoding id
+type EncodedAssetKey = (AssetKey, u8);
+
+// Stable memory regions; ids must never be reused or renumbered
+const HEAP_STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
+const NFTS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const TRANSFER_HISTORY_MEMORY_ID: MemoryId = MemoryId::new(2);
+const OWNER_TOKENS_MEMORY_ID: MemoryId = MemoryId::new(3);
+const TOKEN_ASSETS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const ASSETS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const ASSET_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(6);
+const MINTED_ASSETS_MEMORY_ID: MemoryId = MemoryId::new(7);
+const TOKEN_APPROVALS_MEMORY_ID: MemoryId = MemoryId::new(8);
+const COLLECTION_APPROVALS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const BLOCKS_MEMORY_ID: MemoryId = MemoryId::new(10);
+const USED_PAYMENT_BLOCKS_MEMORY_ID: MemoryId = MemoryId::new(11);
+const STATE_HEADER_MEMORY_ID: MemoryId = MemoryId::new(12);
+const TX_DEDUP_MEMORY_ID: MemoryId = MemoryId::new(13);
+const TX_DEDUP_EXPIRY_MEMORY_ID: MemoryId = MemoryId::new(14);
+const FROZEN_TOKENS_MEMORY_ID: MemoryId = MemoryId::new(15);
+const FROZEN_ASSETS_MEMORY_ID: MemoryId = MemoryId::new(16);
+const UPLOAD_BATCHES_MEMORY_ID: MemoryId = MemoryId::new(17);
+const UPLOAD_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(18);
+const CACHE_POLICIES_MEMORY_ID: MemoryId = MemoryId::new(19);
+const ENCODED_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(20);
+
+// Layout versions of the persisted state, oldest first. Versions 1-5 are
+// stable_save snapshots that predate the state header.
+const STATE_VERSION_ASSETS_ONLY: u32 = 1;
+const STATE_VERSION_ASSETS_ADMINS: u32 = 2;
+const STATE_VERSION_ASSETS_ADMINS_MINTED: u32 = 3;
+const STATE_VERSION_PRINCIPAL_KEYED: u32 = 4;
+const STATE_VERSION_ACCOUNT_KEYED: u32 = 5;
+const STATE_VERSION_STABLE_STRUCTURES: u32 = 6;
+const STATE_VERSION_CANONICAL_ASSETS: u32 = 7;
+// Version written by this build
+const STATE_VERSION: u32 = STATE_VERSION_CANONICAL_ASSETS;
+
+const MAX_ASSET_KEY_LENGTH: usize = 256;
+const MAX_ASSET_DESCRIPTION_LENGTH: usize = 4096;
+const MAX_CONTENT_TYPE_LENGTH: usize = 256;
+const ASSET_CHUNK_SIZE: usize = 32 * 1024;
+const MAX_ASSET_SIZE: usize = 64 * 1024 * 1024;
+// Largest chunk accepted by upload_chunk, leaving room for the rest of the ingress message
+const MAX_UPLOAD_CHUNK_SIZE: usize = 56 * ASSET_CHUNK_SIZE;
+const UPLOAD_BATCH_EXPIRY_NANOS: u64 = 60 * 60 * 1_000_000_000;
+// Assets larger than this are served through http_request_streaming_callback
+const STREAMING_CHUNK_SIZE: usize = 32 * ASSET_CHUNK_SIZE;
+
+fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+// Candid-encoded values with an upper bound on their encoded size
+macro_rules! candid_storable {
+    ($type:ty, $max_size:expr) => {
+        impl Storable for $type {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(candid::encode_one(self).expect("failed to encode stable value"))
+            }
+            
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                candid::decode_one(&bytes).expect("failed to decode stable value")
+            }
+        }
+        
+        impl BoundedStorable for $type {
+            const MAX_SIZE: u32 = $max_size;
+            const IS_FIXED_SIZE: bool = false;
+        }
+    };
+}
+
+candid_storable!(NFT, 16 * 1024);
+candid_storable!(TransferRecord, 256);
+candid_storable!(Account, 128);
+candid_storable!(ApprovalInfo, 256);
+candid_storable!(AssetMetadata, 8 * 1024);
+candid_storable!(Value, 8 * 1024);
+candid_storable!(UploadBatch, 8 * 1024);
+
+// Asset key as stored in the asset maps (UTF-8, at most MAX_ASSET_KEY_LENGTH bytes)
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct AssetKey(String);
+
+impl AssetKey {
+    fn new(key: &str) -> AssetKey {
+        AssetKey(key.to_string())
+    }
+}
+
+impl Storable for AssetKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+    
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        AssetKey(String::from_utf8(bytes.into_owned()).expect("asset keys are UTF-8"))
+    }
+}
+
+impl BoundedStorable for AssetKey {
+    const MAX_SIZE: u32 = MAX_ASSET_KEY_LENGTH as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// One ASSET_CHUNK_SIZE slice of an asset's content
+struct AssetChunk(Vec<u8>);
+
+impl Storable for AssetChunk {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+    
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        AssetChunk(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for AssetChunk {
+    const MAX_SIZE: u32 = ASSET_CHUNK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Heap-resident state, written to stable memory as a single cell on upgrade
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct HeapState {
+    token_id_counter: u64,
+    burned_count: Option<u64>, // Absent in state written before burns existed
+    next_upload_batch_id: Option<u64>, // Absent in state written before chunked uploads existed
+    whitelist: HashMap<Principal, bool>,
+    admins: HashMap<Principal, AdminType>,
+    collection_details: CollectionDetails,
+    archives: Vec<ArchiveInfo>,
+    archive_state: ArchiveState,
+    log_tip: LogTip,
+}
+
+impl Storable for HeapState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode heap state"))
+    }
+    
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode heap state")
+    }
+}
+
+fn snapshot_heap_state() -> HeapState {
+    HeapState {
+        token_id_counter: TOKEN_ID_COUNTER.with(|c| *c.borrow()),
+        burned_count: Some(BURNED_COUNT.with(|b| *b.borrow())),
+        next_upload_batch_id: Some(NEXT_UPLOAD_BATCH_ID.with(|n| *n.borrow())),
+        whitelist: WHITELIST.with(|w| w.borrow().clone()),
+        admins: ADMINS.with(|a| a.borrow().clone()),
+        collection_details: COLLECTION_DETAILS.with(|c| c.borrow().clone()),
+        archives: ARCHIVES.with(|a| a.borrow().clone()),
+        archive_state: ARCHIVE_STATE.with(|a| a.borrow().clone()),
+        log_tip: LOG_TIP.with(|t| t.borrow().clone()),
+    }
+}
+
+fn restore_heap_state(state: HeapState) {
+    TOKEN_ID_COUNTER.with(|c| *c.borrow_mut() = state.token_id_counter);
+    BURNED_COUNT.with(|b| *b.borrow_mut() = state.burned_count.unwrap_or(0));
+    NEXT_UPLOAD_BATCH_ID.with(|n| *n.borrow_mut() = state.next_upload_batch_id.unwrap_or(0));
+    WHITELIST.with(|w| *w.borrow_mut() = state.whitelist);
+    ADMINS.with(|a| *a.borrow_mut() = state.admins);
+    COLLECTION_DETAILS.with(|c| *c.borrow_mut() = state.collection_details);
+    ARCHIVES.with(|a| *a.borrow_mut() = state.archives);
+    ARCHIVE_STATE.with(|a| *a.borrow_mut() = state.archive_state);
+    LOG_TIP.with(|t| *t.borrow_mut() = state.log_tip);
+}
+
+// Tokens held by an account, in token ID order
+fn owned_token_ids(account: &Account) -> Vec<u64> {
+    OWNER_TOKENS.with(|owner_tokens| {
+        owner_tokens.borrow()
+            .range((account.clone(), 0)..=(account.clone(), u64::MAX))
+            .map(|((_, token_id), _)| token_id)
+            .collect()
+    })
+}
+
+// Transfer history of a token, oldest first
+fn transfer_history(token_id: u64) -> Vec<TransferRecord> {
+    TRANSFER_HISTORY.with(|history| {
+        history.borrow()
+            .range((token_id, 0)..=(token_id, u64::MAX))
+            .map(|(_, record)| record)
+            .collect()
+    })
+}
+
+fn push_transfer_record(token_id: u64, record: TransferRecord) {
+    TRANSFER_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let next = history.iter_upper_bound(&(token_id, u64::MAX))
+            .next()
+            .filter(|((id, _), _)| *id == token_id)
+            .map_or(0, |((_, seq), _)| seq + 1);
+        history.insert((token_id, next), record);
+    });
+}
+
+// The stored NFT record together with its transfer history
+fn load_nft(token_id: u64) -> Option<NFT> {
+    let mut nft = NFTS.with(|nfts| nfts.borrow().get(&token_id))?;
+    nft.transfer_history = transfer_history(token_id);
+    Some(nft)
+}
+
+fn asset_metadata(key: &str) -> Option<AssetMetadata> {
+    ASSETS.with(|assets| assets.borrow().get(&AssetKey::new(key)))
+}
+
+fn asset_exists(key: &str) -> bool {
+    ASSETS.with(|assets| assets.borrow().contains_key(&AssetKey::new(key)))
+}
+
+fn asset_keys() -> Vec<String> {
+    ASSETS.with(|assets| assets.borrow().iter().map(|(key, _)| key.0).collect())
+}
+
+fn first_asset_chunk(key: &str) -> Vec<u8> {
+    ASSET_CHUNKS.with(|chunks| chunks.borrow().get(&(AssetKey::new(key), 0)))
+        .map_or_else(Vec::new, |chunk| chunk.0)
+}
+
+// Reassemble an asset from its metadata and content chunks
+fn load_asset(key: &str) -> Option<Asset> {
+    let metadata = asset_metadata(key)?;
+    let asset_key = AssetKey::new(key);
+    
+    let mut data = Vec::with_capacity(metadata.size);
+    ASSET_CHUNKS.with(|chunks| {
+        for (_, chunk) in chunks.borrow().range((asset_key.clone(), 0)..=(asset_key.clone(), u32::MAX)) {
+            data.extend_from_slice(&chunk.0);
+        }
+    });
+    
+    Some(Asset {
+        key: metadata.key,
+        content_type: metadata.content_type,
+        data,
+        description: metadata.description,
+        uploaded_by: metadata.uploaded_by,
+        created_at: metadata.created_at,
+        modified_at: metadata.modified_at,
+    })
+}
+
+// Store an asset, replacing any previous content under the same key. Returns the new version.
+fn store_asset(asset: Asset) -> u64 {
+    let key = asset.key.clone();
+    let asset_key = AssetKey::new(&asset.key);
+    let version = next_asset_version(&key);
+    remove_asset_chunks(&asset_key);
+    remove_encoded_chunks(&key, None);
+    
+    ASSET_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for (index, chunk) in asset.data.chunks(ASSET_CHUNK_SIZE).enumerate() {
+            chunks.insert((asset_key.clone(), index as u32), AssetChunk(chunk.to_vec()));
+        }
+    });
+    
+    let metadata = AssetMetadata {
+        key: asset.key,
+        content_type: asset.content_type,
+        size: asset.data.len(),
+        created_at: asset.created_at,
+        modified_at: asset.modified_at,
+        description: asset.description,
+        uploaded_by: asset.uploaded_by,
+        sha256: Some(Sha256::digest(&asset.data).to_vec()),
+        version: Some(version),
+        encodings: None,
+    };
+    ASSETS.with(|assets| {
+        assets.borrow_mut().insert(asset_key, metadata);
+    });
+    certify_asset(&key);
+    version
+}
+
+fn remove_asset_chunks(asset_key: &AssetKey) {
+    ASSET_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        let keys: Vec<(AssetKey, u32)> = chunks
+            .range((asset_key.clone(), 0)..=(asset_key.clone(), u32::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            chunks.remove(&key);
+        }
+    });
+}
+
+// Describes the layout of everything else in stable memory
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StateHeader {
+    version: u32,
+}
+
+impl Storable for StateHeader {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode state header"))
+    }
+    
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode state header")
+    }
+}
+
+fn write_state_header() {
+    StableCell::new(memory(STATE_HEADER_MEMORY_ID), StateHeader { version: STATE_VERSION })
+        .expect("failed to write state header");
+}
+
+fn stored_state_version() -> u32 {
+    let header_memory = memory(STATE_HEADER_MEMORY_ID);
+    
+    // The first stable-structures layout was written before the header existed
+    if header_memory.size() == 0 {
+        return STATE_VERSION_STABLE_STRUCTURES;
+    }
+    
+    StableCell::init(header_memory, StateHeader { version: STATE_VERSION })
+        .expect("failed to read state header")
+        .get()
+        .version
+}
+
+// Legacy stable_save layouts start with a candid header; the memory manager writes "MGR"
+fn stable_memory_is_managed() -> bool {
+    if ic_cdk::api::stable::stable64_size() == 0 {
+        return false;
+    }
+    let mut magic = [0u8; 3];
+    ic_cdk::api::stable::stable64_read(0, &mut magic);
+    &magic == b"MGR"
 }
 
 // Define ICRC-37 compatible NFT type
@@ -129,6 +558,7 @@ thread_local! {
 struct NFT {
     token_id: u64,
     owner: Principal,
+    owner_subaccount: Option<Vec<u8>>, // None means the default subaccount
     metadata: NFTMetadata,
     created_at: u64,
     transfer_history: Vec<TransferRecord>,
@@ -154,13 +584,63 @@ struct TransferRecord {
     timestamp: u64,
 }
 
+impl NFT {
+    fn owner_account(&self) -> Account {
+        Account {
+            owner: self.owner,
+            subaccount: self.owner_subaccount.clone(),
+        }
+    }
+}
+
 // Define ICRC-7/37 compatible types
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 struct Account {
     owner: Principal,
     subaccount: Option<Vec<u8>>, // Changed from [u8; 32] to Vec<u8> for easier serialization
 }
 
+const SUBACCOUNT_LENGTH: usize = 32;
+
+// Smallest account in the derived ordering, used as a lower range bound
+const MIN_ACCOUNT: Account = Account { owner: Principal::management_canister(), subaccount: None };
+
+// Required for accounts inside stable map tuple keys
+impl Default for Account {
+    fn default() -> Self {
+        MIN_ACCOUNT
+    }
+}
+
+impl Account {
+    // Canonical form used for ownership and approval lookups: subaccounts must be
+    // exactly 32 bytes and the all-zero subaccount is the same as no subaccount
+    fn normalized(&self) -> Result<Account, String> {
+        Ok(Account {
+            owner: self.owner,
+            subaccount: normalize_subaccount(&self.subaccount)?,
+        })
+    }
+}
+
+fn normalize_subaccount(subaccount: &Option<Vec<u8>>) -> Result<Option<Vec<u8>>, String> {
+    match subaccount {
+        None => Ok(None),
+        Some(bytes) if bytes.len() != SUBACCOUNT_LENGTH => Err(format!(
+            "Invalid subaccount: expected {} bytes, got {}", SUBACCOUNT_LENGTH, bytes.len()
+        )),
+        Some(bytes) if bytes.iter().all(|b| *b == 0) => Ok(None),
+        Some(bytes) => Ok(Some(bytes.clone())),
+    }
+}
+
+fn invalid_account_error(message: String) -> TransferError {
+    TransferError::GenericError {
+        error_code: Nat::from(2u8),
+        message,
+    }
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 struct TransferArgs {
     from_subaccount: Option<Vec<u8>>, // Changed from [u8; 32] to Vec<u8>
@@ -182,12 +662,15 @@ enum TransferError {
     TemporarilyUnavailable,
     Unauthorized,
     NotFound,
+    InvalidRecipient,
+    GenericBatchError { error_code: Nat, message: String },
+    TooManyRequests,
 }
 
-// ICRC-3 data structures for transaction logs
+// Flattened view of a block, kept for clients of icrc3_get_transactions
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
 struct Transaction {
-    kind: String,  // "transfer", "mint", "burn", "approve", etc.
+    kind: String,  // Block type: "7xfer", "7mint", "37approve", etc.
     timestamp: u64,
     token_id: u64,
     from: Principal,
@@ -203,10 +686,11 @@ struct GetTransactionsRequest {
     length: Option<u16>, // Maximum number of transactions to return
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+#[derive(Clone, Debug, CandidType, Deserialize)]
 struct GetTransactionsResponse {
     transactions: Vec<Transaction>,
     total: u64,  // Total number of transactions available
+    archived_transactions: Vec<ArchivedBlocks>, // Older ranges to fetch from archives as blocks
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
@@ -222,6 +706,13 @@ struct ApprovalInfo {
     token_id: u64,
     expires_at: Option<u64>,
     created_at: u64,
+    memo: Option<Vec<u8>>, // Absent in approvals granted before memos were kept
+}
+
+impl ApprovalInfo {
+    fn is_active(&self) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > time())
+    }
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
@@ -243,6 +734,57 @@ struct ApprovalCollectionArgs {
     created_at_time: Option<u64>,
 }
 
+// Revoke approvals on several tokens; no spender revokes every spender
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct RevokeTokenApprovalsArgs {
+    from_subaccount: Option<Vec<u8>>,
+    spender: Option<Account>,
+    token_ids: Vec<u64>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// Revoke collection approvals; no spender revokes every spender
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct RevokeCollectionApprovalsArgs {
+    from_subaccount: Option<Vec<u8>>,
+    spender: Option<Account>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum RevokeTokenApprovalError {
+    ApprovalDoesNotExist,
+    Unauthorized,
+    NonExistingTokenId,
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum RevokeCollectionApprovalError {
+    ApprovalDoesNotExist,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct TokenApproval {
+    token_id: u64,
+    spender: Account,
+    expires_at: Option<u64>,
+    memo: Option<Vec<u8>>,
+    created_at_time: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct CollectionApproval {
+    spender: Account,
+    expires_at: Option<u64>,
+    memo: Option<Vec<u8>>,
+    created_at_time: u64,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
 struct TransferFromArgs {
     spender_subaccount: Option<Vec<u8>>,
@@ -259,12 +801,15 @@ struct Standard {
     url: String,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+// ICRC-3 generic value
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
 enum Value {
     Nat(Nat),
-    Int(i64),
+    Int(Int),
     Text(String),
     Blob(Vec<u8>),
+    Map(Vec<(String, Value)>),
+    Array(Vec<Value>),
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -275,26 +820,45 @@ struct UpdateCollectionDetailsArgs {
     max_supply: Option<u64>,
     base_url: Option<String>,
     logo: Option<String>,
+    token_name_template: Option<String>,
     pricing_enabled: Option<bool>,
     mint_schedules: Option<Vec<MintSchedule>>,
+    payment_ledger: Option<Principal>,
+    treasury_subaccount: Option<Vec<u8>>,
+    admin_burn_enabled: Option<bool>,
+    max_query_batch_size: Option<u16>,
+    max_update_batch_size: Option<u16>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq, Ord, PartialOrd)]
 pub struct BundlePrice {
     pub quantity: u64,  // Number of NFTs in the bundle
-    pub price: Nat,     // Price in ICP (e8s)
+    pub price: Nat,     // Price in the smallest unit of the schedule's payment token (e8s for ICP)
 }
 
 // Arguments for minting NFTs
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct MintArgs {
     pub asset_id: String,
+    pub payment: Option<PaymentMethod>, // Defaults to an ICRC-2 approval from the caller's default account
+    pub schedule: Option<String>,       // Schedule to buy from; required when active schedules use different tokens
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct MintBundleArgs {
     pub quantity: u64,
     pub asset_ids: Vec<String>,
+    pub payment: Option<PaymentMethod>,
+    pub schedule: Option<String>,
+}
+
+// How the buyer pays the mint price
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum PaymentMethod {
+    // The canister pulls the price with icrc2_transfer_from (the buyer approves it first)
+    Icrc2Approval { from_subaccount: Option<Vec<u8>> },
+    // The buyer already sent the price to the treasury with icrc1_transfer in this block
+    Icrc1Transfer { block_index: Nat },
 }
 
 // Arguments for setting standard prices
@@ -316,10 +880,23 @@ fn get_mint_schedules() -> Vec<MintSchedule> {
 const COLLECTION_NAME: &str = "";
 const COLLECTION_SYMBOL: &str = "";
 const COLLECTION_DESCRIPTION: &str = "";
+// Default batch limits, overridable through CollectionDetails
 const MAX_QUERY_BATCH_SIZE: u16 = 100;
 const MAX_UPDATE_BATCH_SIZE: u16 = 20;
+// Instructions a batch may use before the remaining items are refused,
+// leaving headroom below the per-message limits
+const UPDATE_INSTRUCTION_BUDGET: u64 = 15_000_000_000;
+const QUERY_INSTRUCTION_BUDGET: u64 = 4_000_000_000;
 const DEFAULT_TAKE_VALUE: u64 = 10;
 const MAX_TAKE_VALUE: u64 = 100;
+const MAX_APPROVALS_PER_TOKEN_OR_COLLECTION: u64 = 100;
+const MAX_MEMO_LENGTH: usize = 32;
+// Transactions carrying created_at_time are deduplicated within this window (nanoseconds)
+const TX_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Allowed clock difference between the caller and the canister (nanoseconds)
+const PERMITTED_DRIFT_NANOS: u64 = 2 * 60 * 1_000_000_000;
+// Expired dedup entries removed per transaction
+const MAX_DEDUP_PRUNE_PER_CALL: usize = 100;
 
 // Initialize the canister
 #[init]
@@ -335,1799 +912,5162 @@ fn init() {
     WHITELIST.with(|whitelist| {
         whitelist.borrow_mut().insert(caller_principal, true);
     });
+    
+    certify_metadata_routes();
+    write_state_header();
 }
 
 // ==== ICRC-3 METHODS ====
-#[query]
-fn icrc3_get_transactions(request: GetTransactionsRequest) -> GetTransactionsResponse {
-    let start = request.start.unwrap_or(0);
-    let length = request.length.unwrap_or(10).min(100) as usize; // Cap at 100 transactions per request
-    
-    let transactions = TRANSACTIONS.with(|txs| {
-        let txs = txs.borrow();
-        let total = txs.len() as u64;
-        let transactions = txs.iter()
-            .skip(start as usize)
-            .take(length)
-            .cloned()
-            .collect::<Vec<_>>();
-        
-        GetTransactionsResponse {
-            transactions,
-            total,
-        }
-    });
-    
-    transactions
-}
 
-#[query]
-fn icrc3_get_archives() -> Vec<ArchiveInfo> {
-    ARCHIVES.with(|archives| archives.borrow().clone())
-}
+// Block types written to the log
+const BTYPE_MINT: &str = "7mint";
+const BTYPE_TRANSFER: &str = "7xfer";
+const BTYPE_BURN: &str = "7burn";
+const BTYPE_APPROVE: &str = "37approve";
+const BTYPE_APPROVE_COLLECTION: &str = "37approve_coll";
+const BTYPE_TRANSFER_FROM: &str = "37xfer";
+const BTYPE_REVOKE: &str = "37revoke";
+const BTYPE_REVOKE_COLLECTION: &str = "37revoke_coll";
+const BTYPE_ASSET_UPLOAD: &str = "37plus_asset_upload";
+const BTYPE_ASSET_DELETE: &str = "37plus_asset_delete";
+const BTYPE_ASSET_RENAME: &str = "37plus_asset_rename";
+const BTYPE_METADATA_UPDATE: &str = "37plus_metadata_update";
+const BTYPE_METADATA_FREEZE: &str = "37plus_metadata_freeze";
 
-#[query]
-fn icrc3_get_transaction(transaction_id: u64) -> Option<Transaction> {
-    TRANSACTIONS.with(|txs| {
-        txs.borrow().iter()
-            .find(|tx| tx.transaction_id == transaction_id)
-            .cloned()
-    })
+const MAX_BLOCKS_PER_REQUEST: u64 = 100;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, Default)]
+struct LogTip {
+    first_index: u64, // Index of the oldest block held locally; earlier ones live in archives
+    last_hash: Option<Vec<u8>>, // Hash of the newest block, chained into the next one as phash
 }
 
-// ==== ICRC-7 BASE METHODS ====
-#[query]
-fn icrc7_collection_metadata() -> Vec<(String, Value)> {
-    let total_supply = NFTS.with(|nfts| nfts.borrow().len() as u64);
-    
-    let mut metadata = COLLECTION_DETAILS.with(|details| {
-        let details = details.borrow();
-        let mut metadata = vec![
-            ("icrc7:name".to_string(), Value::Text(details.name.clone())),
-            ("icrc7:symbol".to_string(), Value::Text(details.symbol.clone())),
-            ("icrc7:description".to_string(), Value::Text(details.description.clone())),
-            ("icrc7:total_supply".to_string(), Value::Nat(Nat::from(total_supply))),
-            ("icrc7:max_query_batch_size".to_string(), Value::Nat(Nat::from(MAX_QUERY_BATCH_SIZE))),
-            ("icrc7:max_update_batch_size".to_string(), Value::Nat(Nat::from(MAX_UPDATE_BATCH_SIZE))),
-            ("icrc7:default_take_value".to_string(), Value::Nat(Nat::from(DEFAULT_TAKE_VALUE))),
-            ("icrc7:max_take_value".to_string(), Value::Nat(Nat::from(MAX_TAKE_VALUE))),
-            ("logo".to_string(), Value::Text("https://example.com/logo.png".to_string())),
-        ];
-        
-        // Add max_supply if set
-        if let Some(max_supply) = details.max_supply {
-            metadata.push(("max_supply".to_string(), Value::Nat(Nat::from(max_supply))));
-        }
-        
-        metadata
-    });
-    
-    // Add admin information
-    let admins = ADMINS.with(|admins| {
-        admins.borrow()
-            .iter()
-            .filter(|(_, admin_type)| **admin_type == AdminType::System)
-            .map(|(principal, _)| principal.to_string())
-            .collect::<Vec<String>>()
-            .join(", ")
-    });
-    
-    metadata.push(("owner".to_string(), Value::Text(admins)));
-    
-    metadata
+// Heap block log written by pre_upgrade before blocks moved to stable memory
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, Default)]
+struct BlockLog {
+    first_index: u64,
+    blocks: Vec<Value>,
+    last_hash: Option<Vec<u8>>,
 }
 
-#[query]
-fn icrc7_name() -> String {
-    COLLECTION_DETAILS.with(|details| details.borrow().name.clone())
+// Total number of blocks ever written, archived ones included
+fn log_length() -> u64 {
+    let first_index = LOG_TIP.with(|tip| tip.borrow().first_index);
+    first_index + BLOCKS.with(|blocks| blocks.borrow().len())
 }
 
-#[query]
-fn icrc7_symbol() -> String {
-    COLLECTION_DETAILS.with(|details| details.borrow().symbol.clone())
+fn local_block_count() -> u64 {
+    BLOCKS.with(|blocks| blocks.borrow().len())
 }
 
-#[query]
-fn icrc7_description() -> Option<String> {
-    Some(COLLECTION_DETAILS.with(|details| details.borrow().description.clone()))
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct GetBlocksArgs {
+    start: Nat,
+    length: Nat,
 }
 
-#[query]
-fn icrc7_total_supply() -> u64 {
-    NFTS.with(|nfts| nfts.borrow().len() as u64)
+#[derive(Clone, Debug, CandidType, Serialize)]
+struct BlockWithId {
+    id: Nat,
+    block: Value,
 }
 
-#[query]
-fn icrc7_supported_standards() -> Vec<Standard> {
-    vec![
-        Standard {
-            name: "ICRC-3".to_string(),
-            url: "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-3".to_string(),
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ArchivedBlocks {
+    args: Vec<GetBlocksArgs>,
+    callback: Func,
+}
+
+#[derive(Clone, Debug, CandidType)]
+struct GetBlocksResult {
+    log_length: Nat,
+    blocks: Vec<BlockWithId>,
+    archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize)]
+struct DataCertificate {
+    certificate: Vec<u8>,
+    hash_tree: Vec<u8>, // CBOR-encoded hash tree with last_block_index and last_block_hash
+}
+
+#[derive(Clone, Debug, CandidType, Serialize)]
+struct SupportedBlockType {
+    block_type: String,
+    url: String,
+}
+
+// Representation-independent hash of a value, as specified by ICRC-3
+fn hash_value(value: &Value) -> Hash {
+    let mut hasher = Sha256::new();
+    match value {
+        Value::Nat(n) => {
+            let mut buf = Vec::new();
+            n.encode(&mut buf).expect("encoding a nat into a buffer cannot fail");
+            hasher.update(&buf);
+        }
+        Value::Int(i) => {
+            let mut buf = Vec::new();
+            i.encode(&mut buf).expect("encoding an int into a buffer cannot fail");
+            hasher.update(&buf);
+        }
+        Value::Text(text) => hasher.update(text.as_bytes()),
+        Value::Blob(bytes) => hasher.update(bytes),
+        Value::Array(items) => {
+            for item in items {
+                hasher.update(hash_value(item));
+            }
+        }
+        Value::Map(entries) => {
+            let mut pairs: Vec<Vec<u8>> = entries.iter()
+                .map(|(key, value)| {
+                    let mut pair = Sha256::digest(key.as_bytes()).to_vec();
+                    pair.extend_from_slice(&hash_value(value));
+                    pair
+                })
+                .collect();
+            pairs.sort();
+            for pair in pairs {
+                hasher.update(pair);
+            }
+        }
+    }
+    hasher.finalize().into()
+}
+
+// ICRC-3 encoding of an account: [owner, subaccount?]
+fn account_value(account: &Account) -> Value {
+    let mut parts = vec![Value::Blob(account.owner.as_slice().to_vec())];
+    if let Some(subaccount) = &account.subaccount {
+        parts.push(Value::Blob(subaccount.clone()));
+    }
+    Value::Array(parts)
+}
+
+fn nat_value(n: u64) -> Value {
+    Value::Nat(Nat::from(n))
+}
+
+// Optional tx fields shared by most block types
+fn push_memo_and_time(tx: &mut Vec<(String, Value)>, memo: Option<Vec<u8>>, created_at_time: Option<u64>) {
+    if let Some(memo) = memo {
+        tx.push(("memo".to_string(), Value::Blob(memo)));
+    }
+    if let Some(created_at_time) = created_at_time {
+        tx.push(("ts".to_string(), nat_value(created_at_time)));
+    }
+}
+
+// Append a block to the log and re-certify the tip. Returns the block index.
+fn record_block(btype: &str, tx: Vec<(String, Value)>) -> u64 {
+    let index = log_length();
+    LOG_TIP.with(|tip| {
+        let mut tip = tip.borrow_mut();
+        let block = new_block(tip.last_hash.clone(), btype, time(), tx);
+        tip.last_hash = Some(hash_value(&block).to_vec());
+        BLOCKS.with(|blocks| blocks.borrow_mut().insert(index, block));
+    });
+    
+    update_certified_data();
+    maybe_archive_blocks();
+    index
+}
+
+fn new_block(phash: Option<Vec<u8>>, btype: &str, timestamp: u64, tx: Vec<(String, Value)>) -> Value {
+    let mut fields = Vec::with_capacity(4);
+    if let Some(phash) = phash {
+        fields.push(("phash".to_string(), Value::Blob(phash)));
+    }
+    fields.push(("btype".to_string(), Value::Text(btype.to_string())));
+    fields.push(("ts".to_string(), nat_value(timestamp)));
+    fields.push(("tx".to_string(), Value::Map(tx)));
+    Value::Map(fields)
+}
+
+fn leb128_u64(mut n: u64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            buf.push(byte);
+            return buf;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+// Tip of the log as a hash tree: last_block_hash and last_block_index (LEB128)
+fn with_tip_tree<R>(f: impl FnOnce(Option<HashTree>) -> R) -> R {
+    let last_hash = match LOG_TIP.with(|tip| tip.borrow().last_hash.clone()) {
+        Some(hash) => hash,
+        None => return f(None),
+    };
+    let last_index = leb128_u64(log_length() - 1);
+    let tree = fork(
+        labeled(b"last_block_hash", HashTree::Leaf(last_hash.into())),
+        labeled(b"last_block_index", HashTree::Leaf(last_index.into())),
+    );
+    f(Some(tree))
+}
+
+// Certified data covers the asset responses under "http_expr" and the log tip
+fn update_certified_data() {
+    let http_hash = ASSET_CERT_TREE.with(|tree| labeled_hash(b"http_expr", &tree.borrow().root_hash()));
+    let root_hash = match with_tip_tree(|tree| tree.map(|tree| tree.reconstruct())) {
+        Some(tip_hash) => fork_hash(&http_hash, &tip_hash),
+        None => http_hash,
+    };
+    set_certified_data(&root_hash);
+}
+
+fn value_field<'a>(map: &'a Value, key: &str) -> Option<&'a Value> {
+    match map {
+        Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+        _ => None,
+    }
+}
+
+fn value_u64(value: Option<&Value>) -> Option<u64> {
+    match value {
+        Some(Value::Nat(n)) => n.0.clone().try_into().ok(),
+        _ => None,
+    }
+}
+
+fn value_principal(value: Option<&Value>) -> Option<Principal> {
+    match value {
+        Some(Value::Array(parts)) => match parts.first() {
+            Some(Value::Blob(bytes)) => Principal::try_from_slice(bytes).ok(),
+            _ => None,
         },
-        Standard {
-            name: "ICRC-7".to_string(),
-            url: "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-7".to_string(),
+        _ => None,
+    }
+}
+
+// ICRC-3 account encoding: [owner] or [owner, subaccount]
+fn value_account(value: Option<&Value>) -> Option<Account> {
+    let subaccount = match value {
+        Some(Value::Array(parts)) => match parts.get(1) {
+            Some(Value::Blob(bytes)) => Some(bytes.clone()),
+            _ => None,
         },
-        Standard {
-            name: "ICRC-37".to_string(),
-            url: "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-37".to_string(),
-        }
-    ]
+        _ => None,
+    };
+    Some(Account { owner: value_principal(value)?, subaccount })
 }
 
-// Token query methods
-#[query]
-fn icrc7_token_metadata(token_ids: Vec<u64>) -> Vec<Option<Vec<(String, Value)>>> {
-    token_ids.into_iter().map(|token_id| {
-        NFTS.with(|nfts| {
-            nfts.borrow().get(&token_id).cloned().map(|nft| {
-                vec![
-                    ("name".to_string(), Value::Text(nft.metadata.name.clone())),
-                    ("description".to_string(), Value::Text(nft.metadata.description.clone())),
-                    ("image".to_string(), Value::Text(nft.metadata.image_url.clone())),
-                ]
-            })
-        })
-    }).collect()
+// Render a block in the flattened legacy Transaction shape
+fn block_to_transaction(index: u64, block: &Value) -> Transaction {
+    let btype = match value_field(block, "btype") {
+        Some(Value::Text(btype)) => btype.clone(),
+        _ => String::new(),
+    };
+    let tx = value_field(block, "tx");
+    let tx_field = |key: &str| tx.and_then(|tx| value_field(tx, key));
+    let canister = canister_id();
+    
+    Transaction {
+        kind: btype.clone(),
+        timestamp: value_u64(value_field(block, "ts")).unwrap_or(0),
+        token_id: value_u64(tx_field("tid")).unwrap_or(0),
+        from: value_principal(tx_field("from")).unwrap_or(canister),
+        to: value_principal(tx_field("to"))
+            .or_else(|| value_principal(tx_field("spender")))
+            .unwrap_or(canister),
+        memo: match tx_field("memo") {
+            Some(Value::Blob(memo)) => Some(memo.clone()),
+            _ => None,
+        },
+        operation: btype,
+        transaction_id: index,
+    }
+}
+
+// Convert a log written before blocks existed, re-chaining the hashes
+fn migrate_legacy_transactions(transactions: Vec<Transaction>) -> BlockLog {
+    let mut log = BlockLog::default();
+    for tx in transactions {
+        let from = Account { owner: tx.from, subaccount: None };
+        let to = Account { owner: tx.to, subaccount: None };
+        let (btype, mut fields) = match (tx.kind.as_str(), tx.operation.as_str()) {
+            ("transfer", "transfer_from") => (BTYPE_TRANSFER_FROM, vec![
+                ("tid".to_string(), nat_value(tx.token_id)),
+                ("from".to_string(), account_value(&from)),
+                ("to".to_string(), account_value(&to)),
+            ]),
+            ("transfer", _) => (BTYPE_TRANSFER, vec![
+                ("tid".to_string(), nat_value(tx.token_id)),
+                ("from".to_string(), account_value(&from)),
+                ("to".to_string(), account_value(&to)),
+            ]),
+            ("approve", "collection_approval") => (BTYPE_APPROVE_COLLECTION, vec![
+                ("from".to_string(), account_value(&from)),
+                ("spender".to_string(), account_value(&to)),
+            ]),
+            ("approve", _) => (BTYPE_APPROVE, vec![
+                ("tid".to_string(), nat_value(tx.token_id)),
+                ("from".to_string(), account_value(&from)),
+                ("spender".to_string(), account_value(&to)),
+            ]),
+            ("mint", _) | ("mint_bundle", _) => (BTYPE_MINT, vec![
+                ("tid".to_string(), nat_value(tx.token_id)),
+                ("from".to_string(), account_value(&from)),
+                ("to".to_string(), account_value(&to)),
+            ]),
+            _ => (BTYPE_ASSET_UPLOAD, vec![
+                ("from".to_string(), account_value(&from)),
+                ("key".to_string(), Value::Text(tx.operation.trim_start_matches("upload_file:").to_string())),
+            ]),
+        };
+        push_memo_and_time(&mut fields, tx.memo, None);
+        
+        let block = new_block(log.last_hash.clone(), btype, tx.timestamp, fields);
+        log.last_hash = Some(hash_value(&block).to_vec());
+        log.blocks.push(block);
+    }
+    log
 }
 
 #[query]
-fn icrc7_owner_of(token_ids: Vec<u64>) -> Vec<Option<Account>> {
-    token_ids.into_iter().map(|token_id| {
-        NFTS.with(|nfts| {
-            nfts.borrow().get(&token_id).cloned().map(|nft| {
-                Account {
-                    owner: nft.owner,
-                    subaccount: None,
-                }
-            })
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    let ranges: Vec<(u64, u64)> = args.iter()
+        .map(|range| {
+            let start: u64 = range.start.0.clone().try_into().unwrap_or(u64::MAX);
+            let length: u64 = range.length.0.clone().try_into().unwrap_or(u64::MAX);
+            (start, start.saturating_add(length))
         })
-    }).collect()
+        .collect();
+    
+    let first_index = LOG_TIP.with(|tip| tip.borrow().first_index);
+    let log_length = log_length();
+    let mut budget = MAX_BLOCKS_PER_REQUEST;
+    let mut blocks = Vec::new();
+    
+    // Serve the locally held part of each range
+    BLOCKS.with(|stored| {
+        let stored = stored.borrow();
+        for &(start, end) in &ranges {
+            let start = start.max(first_index);
+            let end = end.min(log_length).min(start.saturating_add(budget));
+            if start >= end {
+                continue;
+            }
+            
+            for (id, block) in stored.range(start..end) {
+                blocks.push(BlockWithId {
+                    id: Nat::from(id),
+                    block,
+                });
+            }
+            budget -= end - start;
+            if budget == 0 {
+                break;
+            }
+        }
+    });
+    
+    GetBlocksResult {
+        log_length: Nat::from(log_length),
+        blocks,
+        archived_blocks: archived_ranges(&ranges, "icrc3_get_blocks"),
+    }
 }
 
 #[query]
-fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<u64> {
-    accounts.into_iter().map(|account| {
-        OWNER_TOKENS.with(|owner_tokens| {
-            owner_tokens.borrow().get(&account.owner).map_or(0, |tokens| tokens.len() as u64)
+fn icrc3_get_tip_certificate() -> Option<DataCertificate> {
+    let certificate = data_certificate()?;
+    
+    let http_hash = ASSET_CERT_TREE.with(|tree| labeled_hash(b"http_expr", &tree.borrow().root_hash()));
+    with_tip_tree(|tree| {
+        let tree = fork(HashTree::Pruned(http_hash), tree?);
+        Some(DataCertificate {
+            certificate,
+            hash_tree: cbor_self_described(&tree)?,
         })
-    }).collect()
+    })
 }
 
 #[query]
-fn icrc7_tokens(prev: Option<u64>, take: Option<u64>) -> Vec<u64> {
-    let take_amount = take.unwrap_or(DEFAULT_TAKE_VALUE).min(MAX_TAKE_VALUE) as usize;
-    let start_id = prev.unwrap_or(0);
+fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    let icrc7 = "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-7/ICRC-7.md";
+    let icrc37 = "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-37/ICRC-37.md";
+    let icrc37plus = "https://github.com/chimeraware/icrc37plus_token";
     
-    NFTS.with(|nfts| {
-        nfts.borrow()
-            .iter()
-            .filter(|(id, _)| **id >= start_id)
-            .take(take_amount)
-            .map(|(id, _)| *id)
-            .collect()
+    [
+        (BTYPE_MINT, icrc7),
+        (BTYPE_TRANSFER, icrc7),
+        (BTYPE_BURN, icrc7),
+        (BTYPE_APPROVE, icrc37),
+        (BTYPE_APPROVE_COLLECTION, icrc37),
+        (BTYPE_TRANSFER_FROM, icrc37),
+        (BTYPE_REVOKE, icrc37),
+        (BTYPE_REVOKE_COLLECTION, icrc37),
+        (BTYPE_ASSET_UPLOAD, icrc37plus),
+        (BTYPE_ASSET_DELETE, icrc37plus),
+        (BTYPE_ASSET_RENAME, icrc37plus),
+        (BTYPE_METADATA_UPDATE, icrc37plus),
+        (BTYPE_METADATA_FREEZE, icrc37plus),
+    ]
+    .iter()
+    .map(|(block_type, url)| SupportedBlockType {
+        block_type: block_type.to_string(),
+        url: url.to_string(),
     })
+    .collect()
 }
 
 #[query]
-fn icrc7_tokens_of(account: Account, prev: Option<u64>, take: Option<u64>) -> Vec<u64> {
-    let take_amount = take.unwrap_or(DEFAULT_TAKE_VALUE).min(MAX_TAKE_VALUE) as usize;
-    let start_id = prev.unwrap_or(0);
+fn icrc3_get_transactions(request: GetTransactionsRequest) -> GetTransactionsResponse {
+    let start = request.start.unwrap_or(0);
+    let length = request.length.unwrap_or(10).min(100) as usize; // Cap at 100 transactions per request
     
-    OWNER_TOKENS.with(|owner_tokens| {
-        owner_tokens.borrow()
-            .get(&account.owner)
-            .cloned()
-            .unwrap_or_default()
-            .into_iter()
-            .filter(|&id| id > start_id)
-            .take(take_amount)
-            .collect()
+    let total = log_length();
+    let end = start.saturating_add(length as u64);
+    let transactions = BLOCKS.with(|blocks| {
+        blocks.borrow()
+            .range(start..end.max(start))
+            .map(|(index, block)| block_to_transaction(index, &block))
+            .collect::<Vec<_>>()
+    });
+    
+    GetTransactionsResponse {
+        transactions,
+        total,
+        archived_transactions: archived_ranges(&[(start, end)], "icrc3_get_blocks"),
+    }
+}
+
+#[query]
+fn icrc3_get_archives() -> Vec<ArchiveInfo> {
+    ARCHIVES.with(|archives| archives.borrow().clone())
+}
+
+#[query]
+fn icrc3_get_transaction(transaction_id: u64) -> Option<Transaction> {
+    BLOCKS.with(|blocks| {
+        blocks.borrow().get(&transaction_id)
+            .map(|block| block_to_transaction(transaction_id, &block))
     })
 }
 
-// Transfer method
-#[update]
-fn icrc7_transfer(args: Vec<TransferArgs>) -> Vec<Result<u64, TransferError>> {
-    args.into_iter().map(|arg| {
-        let caller = caller();
-        let token_id = arg.token_id;
-        let to_owner = arg.to.owner;
-        
-        // Verify token exists and ownership
-        let mut nft = match NFTS.with(|nfts| nfts.borrow().get(&token_id).cloned()) {
-            Some(nft) => nft,
-            None => return Err(TransferError::NotFound),
-        };
-        
-        if nft.owner != caller {
-            return Err(TransferError::Unauthorized);
+// ==== ICRC-3 ARCHIVING ====
+
+// Archive canister module, embedded by build.rs (empty when it was not built)
+const ARCHIVE_WASM: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/archive.wasm"));
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct ArchiveConfig {
+    threshold: u64,                   // Archive once this many blocks are held locally
+    num_blocks_to_archive: u64,       // Blocks moved per archiving run
+    max_blocks_per_archive: u64,      // Capacity of each spawned archive
+    cycles_for_archive_creation: u64, // Cycles sent along with create_canister
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        ArchiveConfig {
+            threshold: 2_000,
+            num_blocks_to_archive: 1_000,
+            max_blocks_per_archive: 100_000,
+            cycles_for_archive_creation: 1_000_000_000_000,
         }
-        
-        // Process the transfer
-        let timestamp = arg.created_at_time.unwrap_or_else(time);
-        
-        // Update transfer history
-        nft.transfer_history.push(TransferRecord {
-            from: caller,
-            to: to_owner,
-            timestamp,
-        });
-        
-        // Update owner
-        nft.owner = to_owner;
-        
-        // Update storage
-        NFTS.with(|nfts| {
-            nfts.borrow_mut().insert(token_id, nft);
-        });
-        
-        // Update previous owner's records
-        OWNER_TOKENS.with(|owner_tokens| {
-            let mut tokens = owner_tokens.borrow_mut();
-            if let Some(token_vec) = tokens.get_mut(&caller) {
-                token_vec.retain(|&id| id != token_id);
-            }
-        });
-        
-        // Update new owner's records
-        OWNER_TOKENS.with(|owner_tokens| {
-            let mut tokens = owner_tokens.borrow_mut();
-            tokens.entry(to_owner)
-                .or_insert_with(Vec::new)
-                .push(token_id);
-        });
-        
-        // Record the transfer in the transaction log
-        let _transaction_id = record_transaction("transfer", token_id, caller, to_owner, 
-                                               arg.memo, "standard_transfer".to_string());
-        
-        Ok(timestamp)
-    }).collect()
+    }
 }
 
-// ==== ICRC-37 EXTENSION METHODS ====
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct UpdateArchiveConfigArgs {
+    threshold: Option<u64>,
+    num_blocks_to_archive: Option<u64>,
+    max_blocks_per_archive: Option<u64>,
+    cycles_for_archive_creation: Option<u64>,
+}
 
-// ICRC-37 methods for token approvals
-#[update]
-fn icrc37_approve_tokens(args: Vec<ApprovalArgs>) -> Vec<Result<u64, TransferError>> {
-    args.into_iter().map(|arg| {
-        let caller_principal = caller();
-        let token_id = arg.token_id;
-        let spender_principal = arg.spender.owner;
-        
-        // Check if token exists
-        if !NFTS.with(|nfts| nfts.borrow().contains_key(&token_id)) {
-            return Err(TransferError::NotFound);
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, Default)]
+struct ArchiveState {
+    config: ArchiveConfig,
+    empty_archive: Option<Principal>, // Spawned archive that has not received blocks yet
+    pending_archive: Option<Principal>, // Created archive canister whose code install has not succeeded yet
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ArchiveInitArgs {
+    ledger_id: Principal,
+    first_index: u64,
+    max_blocks: u64,
+}
+
+// Callbacks for the parts of the requested ranges held by archives
+fn archived_ranges(ranges: &[(u64, u64)], method: &str) -> Vec<ArchivedBlocks> {
+    ARCHIVES.with(|archives| {
+        archives.borrow().iter()
+            .filter_map(|archive| {
+                let args: Vec<GetBlocksArgs> = ranges.iter()
+                    .filter_map(|&(start, end)| {
+                        let start = start.max(archive.start);
+                        let end = end.min(archive.end.saturating_add(1));
+                        (start < end).then(|| GetBlocksArgs {
+                            start: Nat::from(start),
+                            length: Nat::from(end - start),
+                        })
+                    })
+                    .collect();
+                
+                (!args.is_empty()).then(|| ArchivedBlocks {
+                    args,
+                    callback: Func {
+                        principal: archive.canister_id,
+                        method: method.to_string(),
+                    },
+                })
+            })
+            .collect()
+    })
+}
+
+// Kick off an archiving run when the local log has grown past the threshold
+fn maybe_archive_blocks() {
+    let threshold = ARCHIVE_STATE.with(|state| state.borrow().config.threshold);
+    let local_blocks = local_block_count();
+    
+    if ARCHIVE_WASM.is_empty() || local_blocks <= threshold || ARCHIVING.with(|a| *a.borrow()) {
+        return;
+    }
+    
+    ARCHIVING.with(|a| *a.borrow_mut() = true);
+    ic_cdk::spawn(async {
+        if let Err(e) = archive_blocks().await {
+            ic_cdk::println!("Archiving failed: {}", e);
         }
-        
-        // Check if caller owns the token
-        let token_owner = NFTS.with(|nfts| {
-            nfts.borrow().get(&token_id).map(|nft| nft.owner).unwrap_or_else(|| Principal::anonymous())
-        });
-        
-        if token_owner != caller_principal {
-            return Err(TransferError::Unauthorized);
+        ARCHIVING.with(|a| *a.borrow_mut() = false);
+    });
+}
+
+// Move the oldest local blocks into the current archive, spawning one if needed
+async fn archive_blocks() -> Result<(), String> {
+    let config = ARCHIVE_STATE.with(|state| state.borrow().config.clone());
+    let first_index = LOG_TIP.with(|tip| tip.borrow().first_index);
+    let local_blocks = local_block_count();
+    
+    let (archive_id, capacity) = archive_with_capacity(first_index, &config).await?;
+    let count = config.num_blocks_to_archive.min(local_blocks).min(capacity);
+    if count == 0 {
+        return Ok(());
+    }
+    
+    let archived_range = first_index..first_index + count;
+    let blocks: Vec<Value> = BLOCKS.with(|blocks| {
+        blocks.borrow().range(archived_range.clone()).map(|(_, block)| block).collect()
+    });
+    let (result,): (Result<u64, String>,) = ic_cdk::call(archive_id, "append_blocks", (blocks,))
+        .await
+        .map_err(|(code, msg)| format!("append_blocks failed: {:?} {}", code, msg))?;
+    result?;
+    
+    // Only this task removes blocks, so the archived prefix is still in place
+    BLOCKS.with(|blocks| {
+        let mut blocks = blocks.borrow_mut();
+        for index in archived_range {
+            blocks.remove(&index);
         }
-        
-        // Record timestamp
-        let timestamp = time();
-        
-        // Create approval info
-        let approval_info = ApprovalInfo {
-            spender: spender_principal,
-            token_id,
-            expires_at: arg.expires_at,
-            created_at: timestamp,
-        };
-        
-        // Add to approvals
-        TOKEN_APPROVALS.with(|approvals| {
-            approvals.borrow_mut()
-                .entry(token_id)
-                .or_insert_with(HashMap::new)
-                .insert(spender_principal, approval_info);
-        });
-        
-        // Record the approval in the transaction log
-        let _transaction_id = record_transaction("approve", token_id, caller_principal, spender_principal, 
-                                               arg.memo, "token_approval".to_string());
-        
-        Ok(timestamp)
-    }).collect()
+    });
+    LOG_TIP.with(|tip| tip.borrow_mut().first_index += count);
+    
+    let last_index = first_index + count - 1;
+    ARCHIVES.with(|archives| {
+        let mut archives = archives.borrow_mut();
+        match archives.last_mut() {
+            Some(archive) if archive.canister_id == archive_id => archive.end = last_index,
+            _ => archives.push(ArchiveInfo {
+                canister_id: archive_id,
+                start: first_index,
+                end: last_index,
+            }),
+        }
+    });
+    ARCHIVE_STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.empty_archive == Some(archive_id) {
+            state.empty_archive = None;
+        }
+    });
+    
+    Ok(())
 }
 
-#[update]
-fn icrc37_approve_collection(args: ApprovalCollectionArgs) -> Result<u64, TransferError> {
-    let caller_principal = caller();
-    let spender_principal = args.spender.owner;
+// The newest archive if it continues at first_index and has room, else a fresh one
+async fn archive_with_capacity(first_index: u64, config: &ArchiveConfig) -> Result<(Principal, u64), String> {
+    let current = ARCHIVES.with(|archives| {
+        archives.borrow().last()
+            .filter(|archive| archive.end + 1 == first_index)
+            .map(|archive| archive.canister_id)
+    });
     
-    // Check for self-approval (unnecessary but could be problematic)
-    if caller_principal == spender_principal {
-        return Err(TransferError::GenericError {
-            error_code: Nat::from(1u8),
-            message: "Self-approval is unnecessary".to_string(),
-        });
+    if let Some(archive_id) = current {
+        let (capacity,): (u64,) = ic_cdk::call(archive_id, "remaining_capacity", ())
+            .await
+            .map_err(|(code, msg)| format!("remaining_capacity failed: {:?} {}", code, msg))?;
+        if capacity > 0 {
+            return Ok((archive_id, capacity));
+        }
     }
     
-    // Record timestamp
-    let timestamp = time();
+    // Reuse an archive spawned by an earlier run that failed before appending
+    if let Some(archive_id) = ARCHIVE_STATE.with(|state| state.borrow().empty_archive) {
+        return Ok((archive_id, config.max_blocks_per_archive));
+    }
     
-    // Create a dummy approval info (token_id is not relevant for collection approval)
-    let approval_info = ApprovalInfo {
-        spender: spender_principal,
-        token_id: 0, // Not used for collection approval
+    let archive_id = spawn_archive(first_index, config).await?;
+    Ok((archive_id, config.max_blocks_per_archive))
+}
+
+async fn spawn_archive(first_index: u64, config: &ArchiveConfig) -> Result<Principal, String> {
+    use ic_cdk::api::management_canister::main::{
+        create_canister_with_extra_cycles, install_code, CanisterInstallMode,
+        CanisterSettings, CreateCanisterArgument, InstallCodeArgument,
+    };
+    
+    // A canister left over from a failed install is retried instead of paying for a new one
+    let pending = ARCHIVE_STATE.with(|state| state.borrow().pending_archive);
+    let (archive_id, mode) = match pending {
+        Some(archive_id) => (archive_id, CanisterInstallMode::Reinstall),
+        None => {
+            let settings = CanisterSettings {
+                controllers: Some(vec![canister_id()]),
+                compute_allocation: None,
+                memory_allocation: None,
+                freezing_threshold: None,
+            };
+            let (record,) = create_canister_with_extra_cycles(
+                CreateCanisterArgument { settings: Some(settings) },
+                config.cycles_for_archive_creation as u128,
+            )
+            .await
+            .map_err(|(code, msg)| format!("create_canister failed: {:?} {}", code, msg))?;
+            ARCHIVE_STATE.with(|state| state.borrow_mut().pending_archive = Some(record.canister_id));
+            (record.canister_id, CanisterInstallMode::Install)
+        }
+    };
+    
+    let init_args = ArchiveInitArgs {
+        ledger_id: canister_id(),
+        first_index,
+        max_blocks: config.max_blocks_per_archive,
+    };
+    install_code(InstallCodeArgument {
+        mode,
+        canister_id: archive_id,
+        wasm_module: ARCHIVE_WASM.to_vec(),
+        arg: candid::encode_one(init_args).map_err(|e| e.to_string())?,
+    })
+    .await
+    .map_err(|(code, msg)| format!("install_code failed: {:?} {}", code, msg))?;
+    
+    ARCHIVE_STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.pending_archive = None;
+        state.empty_archive = Some(archive_id);
+    });
+    ic_cdk::println!("Spawned archive {} starting at block {}", archive_id, first_index);
+    Ok(archive_id)
+}
+
+#[query]
+fn get_archive_config() -> ArchiveConfig {
+    ARCHIVE_STATE.with(|state| state.borrow().config.clone())
+}
+
+// Tune archiving - system admin only
+#[update]
+fn update_archive_config(args: UpdateArchiveConfigArgs) -> Result<(), String> {
+    if !is_system_admin(caller()) {
+        return Err("Unauthorized: Only system admins can update the archive configuration".to_string());
+    }
+    
+    ARCHIVE_STATE.with(|state| {
+        let mut config = state.borrow().config.clone();
+        if let Some(threshold) = args.threshold {
+            config.threshold = threshold;
+        }
+        if let Some(num_blocks) = args.num_blocks_to_archive {
+            config.num_blocks_to_archive = num_blocks;
+        }
+        if let Some(max_blocks) = args.max_blocks_per_archive {
+            config.max_blocks_per_archive = max_blocks;
+        }
+        if let Some(cycles) = args.cycles_for_archive_creation {
+            config.cycles_for_archive_creation = cycles;
+        }
+        
+        if config.num_blocks_to_archive == 0 || config.max_blocks_per_archive == 0 {
+            return Err("Block counts must be greater than zero".to_string());
+        }
+        if config.num_blocks_to_archive > config.threshold {
+            return Err("num_blocks_to_archive cannot exceed threshold".to_string());
+        }
+        
+        state.borrow_mut().config = config;
+        Ok(())
+    })
+}
+
+// ==== BATCH LIMITS ====
+
+fn max_query_batch_size(details: &CollectionDetails) -> u16 {
+    details.max_query_batch_size.unwrap_or(MAX_QUERY_BATCH_SIZE)
+}
+
+fn max_update_batch_size(details: &CollectionDetails) -> u16 {
+    details.max_update_batch_size.unwrap_or(MAX_UPDATE_BATCH_SIZE)
+}
+
+fn check_batch_size(len: usize, max: impl Into<u64>) -> Result<(), String> {
+    let max = max.into();
+    if len as u64 > max {
+        return Err(format!("Batch of {} items exceeds the limit of {}", len, max));
+    }
+    Ok(())
+}
+
+// Run an update batch item by item. An oversize batch is refused as a whole with
+// a single GenericBatchError; items reached after the instruction budget is spent
+// fail with TooManyRequests and can be resubmitted.
+fn update_batch<A, T>(args: Vec<A>, mut process: impl FnMut(A) -> Result<T, TransferError>) -> Vec<Result<T, TransferError>> {
+    let max = COLLECTION_DETAILS.with(|details| max_update_batch_size(&details.borrow()));
+    if let Err(message) = check_batch_size(args.len(), max) {
+        return vec![Err(TransferError::GenericBatchError {
+            error_code: Nat::from(1u8),
+            message,
+        })];
+    }
+    
+    args.into_iter().map(|arg| {
+        if instruction_counter() > UPDATE_INSTRUCTION_BUDGET {
+            return Err(TransferError::TooManyRequests);
+        }
+        process(arg)
+    }).collect()
+}
+
+// Queries have no per-item error to report, so an oversize or too expensive batch traps
+fn query_batch<A, T>(args: Vec<A>, mut process: impl FnMut(A) -> T) -> Vec<T> {
+    let max = COLLECTION_DETAILS.with(|details| max_query_batch_size(&details.borrow()));
+    if let Err(message) = check_batch_size(args.len(), max) {
+        ic_cdk::trap(&format!("GenericBatchError: {}", message));
+    }
+    
+    // Checked before each item so the query stops while it is still below the hard limit
+    args.into_iter().map(|arg| {
+        if instruction_counter() > QUERY_INSTRUCTION_BUDGET {
+            ic_cdk::trap("TooManyRequests: batch exceeded the query instruction budget");
+        }
+        process(arg)
+    }).collect()
+}
+
+// ==== ICRC-7 BASE METHODS ====
+#[query]
+fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    // total_supply counts circulating tokens; max_supply caps tokens ever minted, burned ones included
+    let total_supply = NFTS.with(|nfts| nfts.borrow().len());
+    let minted_count = TOKEN_ID_COUNTER.with(|counter| *counter.borrow());
+    let burned_count = BURNED_COUNT.with(|burned| *burned.borrow());
+    
+    let mut metadata = COLLECTION_DETAILS.with(|details| {
+        let details = details.borrow();
+        let mut metadata = vec![
+            ("icrc7:name".to_string(), Value::Text(details.name.clone())),
+            ("icrc7:symbol".to_string(), Value::Text(details.symbol.clone())),
+            ("icrc7:description".to_string(), Value::Text(details.description.clone())),
+            ("icrc7:total_supply".to_string(), Value::Nat(Nat::from(total_supply))),
+            ("minted_count".to_string(), Value::Nat(Nat::from(minted_count))),
+            ("burned_count".to_string(), Value::Nat(Nat::from(burned_count))),
+            ("icrc7:max_query_batch_size".to_string(), Value::Nat(Nat::from(max_query_batch_size(&details)))),
+            ("icrc7:max_update_batch_size".to_string(), Value::Nat(Nat::from(max_update_batch_size(&details)))),
+            ("icrc7:default_take_value".to_string(), Value::Nat(Nat::from(DEFAULT_TAKE_VALUE))),
+            ("icrc7:max_take_value".to_string(), Value::Nat(Nat::from(MAX_TAKE_VALUE))),
+            ("icrc7:tx_window".to_string(), Value::Nat(Nat::from(TX_WINDOW_NANOS))),
+            ("icrc7:permitted_drift".to_string(), Value::Nat(Nat::from(PERMITTED_DRIFT_NANOS))),
+            ("icrc37:max_approvals_per_token_or_collection".to_string(), Value::Nat(Nat::from(MAX_APPROVALS_PER_TOKEN_OR_COLLECTION))),
+            ("icrc37:max_revoke_approvals".to_string(), Value::Nat(Nat::from(max_update_batch_size(&details)))),
+            ("logo".to_string(), Value::Text("https://example.com/logo.png".to_string())),
+            ("icrc37plus:metadata_frozen".to_string(), Value::Nat(Nat::from(details.metadata_frozen_at.is_some() as u8))),
+        ];
+        
+        if let Some(frozen_at) = details.metadata_frozen_at {
+            metadata.push(("icrc37plus:metadata_frozen_at".to_string(), Value::Nat(Nat::from(frozen_at))));
+        }
+        
+        // Add max_supply if set
+        if let Some(max_supply) = details.max_supply {
+            metadata.push(("icrc7:supply_cap".to_string(), Value::Nat(Nat::from(max_supply))));
+            metadata.push(("max_supply".to_string(), Value::Nat(Nat::from(max_supply))));
+        }
+        
+        metadata
+    });
+    
+    // Add admin information
+    let admins = ADMINS.with(|admins| {
+        admins.borrow()
+            .iter()
+            .filter(|(_, admin_type)| **admin_type == AdminType::System)
+            .map(|(principal, _)| principal.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    });
+    
+    metadata.push(("owner".to_string(), Value::Text(admins)));
+    
+    metadata
+}
+
+#[query]
+fn icrc7_name() -> String {
+    COLLECTION_DETAILS.with(|details| details.borrow().name.clone())
+}
+
+#[query]
+fn icrc7_symbol() -> String {
+    COLLECTION_DETAILS.with(|details| details.borrow().symbol.clone())
+}
+
+#[query]
+fn icrc7_description() -> Option<String> {
+    Some(COLLECTION_DETAILS.with(|details| details.borrow().description.clone()))
+}
+
+#[query]
+fn icrc7_total_supply() -> u64 {
+    NFTS.with(|nfts| nfts.borrow().len())
+}
+
+#[query]
+fn icrc7_tx_window() -> Option<u64> {
+    Some(TX_WINDOW_NANOS)
+}
+
+#[query]
+fn icrc7_permitted_drift() -> Option<u64> {
+    Some(PERMITTED_DRIFT_NANOS)
+}
+
+#[query]
+fn icrc7_supported_standards() -> Vec<Standard> {
+    vec![
+        Standard {
+            name: "ICRC-3".to_string(),
+            url: "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-3".to_string(),
+        },
+        Standard {
+            name: "ICRC-7".to_string(),
+            url: "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-7".to_string(),
+        },
+        Standard {
+            name: "ICRC-37".to_string(),
+            url: "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-37".to_string(),
+        }
+    ]
+}
+
+// Token query methods
+#[query]
+fn icrc7_token_metadata(token_ids: Vec<u64>) -> Vec<Option<Vec<(String, Value)>>> {
+    query_batch(token_ids, |token_id| {
+        NFTS.with(|nfts| nfts.borrow().get(&token_id)).map(|nft| token_metadata_entries(&nft.metadata))
+    })
+}
+
+// ICRC-7 metadata of a token. The unprefixed name/description/image keys are kept
+// for clients written against the original response.
+fn token_metadata_entries(metadata: &NFTMetadata) -> Vec<(String, Value)> {
+    let mut entries = vec![
+        ("icrc7:name".to_string(), Value::Text(metadata.name.clone())),
+        ("icrc7:description".to_string(), Value::Text(metadata.description.clone())),
+        ("icrc7:metadata:uri:image".to_string(), Value::Text(metadata.image_url.clone())),
+    ];
+    
+    if let Some(content_url) = &metadata.content_url {
+        entries.push(("icrc7:metadata:uri:content".to_string(), Value::Text(content_url.clone())));
+    }
+    if let Some(content_type) = &metadata.content_type {
+        entries.push(("icrc7:metadata:content_type".to_string(), Value::Text(content_type.clone())));
+    }
+    if let Some(attributes) = metadata.properties.as_ref().and_then(trait_attributes) {
+        entries.push(("icrc7:metadata:attributes".to_string(), attributes));
+    }
+    if let Some(svg_id) = metadata.svg_id {
+        entries.push(("icrc37plus:svg_id".to_string(), nat_value(svg_id)));
+    }
+    entries.push(("icrc37plus:is_layered".to_string(), nat_value(metadata.is_layered as u64)));
+    if let Some(layers) = &metadata.layers {
+        let layers = layers.iter().map(|key| Value::Text(key.clone())).collect();
+        entries.push(("icrc37plus:layers".to_string(), Value::Array(layers)));
+    }
+    
+    entries.push(("name".to_string(), Value::Text(metadata.name.clone())));
+    entries.push(("description".to_string(), Value::Text(metadata.description.clone())));
+    entries.push(("image".to_string(), Value::Text(metadata.image_url.clone())));
+    entries
+}
+
+// Token properties as a marketplace-style attribute list: a Map becomes
+// [{trait_type, value}, ...], an Array is taken to be such a list already
+fn trait_attributes(properties: &Value) -> Option<Value> {
+    match properties {
+        Value::Map(traits) => Some(Value::Array(
+            traits.iter()
+                .map(|(trait_type, value)| Value::Map(vec![
+                    ("trait_type".to_string(), Value::Text(trait_type.clone())),
+                    ("value".to_string(), value.clone()),
+                ]))
+                .collect(),
+        )),
+        Value::Array(_) => Some(properties.clone()),
+        _ => None,
+    }
+}
+
+#[query]
+fn icrc7_owner_of(token_ids: Vec<u64>) -> Vec<Option<Account>> {
+    query_batch(token_ids, |token_id| {
+        NFTS.with(|nfts| {
+            nfts.borrow().get(&token_id).map(|nft| nft.owner_account())
+        })
+    })
+}
+
+#[query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<u64> {
+    query_batch(accounts, |account| {
+        // An invalid subaccount cannot own anything
+        let account = match account.normalized() {
+            Ok(account) => account,
+            Err(_) => return 0,
+        };
+        
+        owned_token_ids(&account).len() as u64
+    })
+}
+
+// Token IDs in ascending order, starting after prev
+#[query]
+fn icrc7_tokens(prev: Option<u64>, take: Option<u64>) -> Vec<u64> {
+    let take_amount = take.unwrap_or(DEFAULT_TAKE_VALUE).min(MAX_TAKE_VALUE) as usize;
+    let start = match prev {
+        Some(prev) => Bound::Excluded(prev),
+        None => Bound::Unbounded,
+    };
+    
+    NFTS.with(|nfts| {
+        nfts.borrow()
+            .range((start, Bound::Unbounded))
+            .take(take_amount)
+            .map(|(id, _)| id)
+            .collect()
+    })
+}
+
+// Token IDs held by an account in ascending order, starting after prev
+#[query]
+fn icrc7_tokens_of(account: Account, prev: Option<u64>, take: Option<u64>) -> Vec<u64> {
+    let take_amount = take.unwrap_or(DEFAULT_TAKE_VALUE).min(MAX_TAKE_VALUE) as usize;
+    
+    let account = match account.normalized() {
+        Ok(account) => account,
+        Err(_) => return Vec::new(),
+    };
+    
+    let start = match prev {
+        Some(prev) => Bound::Excluded((account.clone(), prev)),
+        None => Bound::Included((account.clone(), 0)),
+    };
+    let end = Bound::Included((account, u64::MAX));
+    
+    OWNER_TOKENS.with(|owner_tokens| {
+        owner_tokens.borrow()
+            .range((start, end))
+            .take(take_amount)
+            .map(|((_, token_id), _)| token_id)
+            .collect()
+    })
+}
+
+// A validated ownership change, applied by commit_transfer
+struct PendingTransfer {
+    nft: NFT,
+    from: Account,
+    to: Account,
+    btype: &'static str,
+    tx: Vec<(String, Value)>,
+    dedup: Option<(Blob<32>, u64)>, // Transaction hash and created_at_time
+}
+
+// Transfer method
+#[update]
+fn icrc7_transfer(args: Vec<TransferArgs>) -> Vec<Result<u64, TransferError>> {
+    let caller = caller();
+    update_batch(args, |arg| prepare_transfer(caller, arg).map(commit_transfer))
+}
+
+// All-or-nothing icrc7_transfer: either every item is applied or none is, in
+// which case the error lists the reason for each failing item
+#[update]
+fn icrc7_atomic_transfer(args: Vec<TransferArgs>) -> Result<Vec<u64>, Vec<Option<TransferError>>> {
+    let caller = caller();
+    atomic_batch(args, |arg| prepare_transfer(caller, arg))
+}
+
+fn prepare_transfer(caller: Principal, arg: TransferArgs) -> Result<PendingTransfer, TransferError> {
+    let token_id = arg.token_id;
+    
+    let from = Account { owner: caller, subaccount: arg.from_subaccount.clone() }
+        .normalized()
+        .map_err(invalid_account_error)?;
+    let to = arg.to.normalized().map_err(invalid_account_error)?;
+    check_memo(&arg.memo).map_err(invalid_memo_error)?;
+    
+    check_created_at_time(arg.created_at_time)?;
+    
+    // Block contents double as the deduplication key
+    let mut tx = vec![
+        ("tid".to_string(), nat_value(token_id)),
+        ("from".to_string(), account_value(&from)),
+        ("to".to_string(), account_value(&to)),
+    ];
+    push_memo_and_time(&mut tx, arg.memo, arg.created_at_time);
+    let dedup = arg.created_at_time.map(|created_at_time| (transaction_hash(BTYPE_TRANSFER, &tx), created_at_time));
+    if let Some(duplicate_of) = dedup.as_ref().and_then(|(tx_hash, _)| find_duplicate(tx_hash)) {
+        return Err(TransferError::Duplicate { duplicate_of });
+    }
+    
+    if to == from || to.owner == Principal::anonymous() {
+        return Err(TransferError::InvalidRecipient);
+    }
+    
+    // Verify token exists and ownership
+    let nft = match NFTS.with(|nfts| nfts.borrow().get(&token_id)) {
+        Some(nft) => nft,
+        None => return Err(TransferError::NotFound),
+    };
+    
+    if nft.owner_account() != from {
+        return Err(TransferError::Unauthorized);
+    }
+    
+    Ok(PendingTransfer { nft, from, to, btype: BTYPE_TRANSFER, tx, dedup })
+}
+
+// Apply a validated transfer and log it, returning the block index
+fn commit_transfer(pending: PendingTransfer) -> u64 {
+    let PendingTransfer { mut nft, from, to, btype, tx, dedup } = pending;
+    let token_id = nft.token_id;
+    
+    // Update transfer history
+    push_transfer_record(token_id, TransferRecord {
+        from: from.owner,
+        to: to.owner,
+        timestamp: time(),
+    });
+    
+    // Update owner
+    nft.owner = to.owner;
+    nft.owner_subaccount = to.subaccount.clone();
+    
+    // Update storage
+    NFTS.with(|nfts| {
+        nfts.borrow_mut().insert(token_id, nft);
+    });
+    
+    move_owned_token(token_id, &from, to);
+    
+    // Approvals were granted by the previous owner
+    clear_token_approvals(token_id);
+    
+    // Record the transfer in the block log
+    let tx_index = record_block(btype, tx);
+    if let Some((tx_hash, created_at_time)) = dedup {
+        remember_transaction(tx_hash, created_at_time, tx_index);
+    }
+    
+    tx_index
+}
+
+// Validate a whole batch before committing any of it. Nothing is awaited between
+// the two phases, so no other message can change state in between.
+fn atomic_batch<A>(
+    args: Vec<A>,
+    mut prepare: impl FnMut(A) -> Result<PendingTransfer, TransferError>,
+) -> Result<Vec<u64>, Vec<Option<TransferError>>> {
+    let max = COLLECTION_DETAILS.with(|details| max_update_batch_size(&details.borrow()));
+    if let Err(message) = check_batch_size(args.len(), max) {
+        return Err(vec![Some(TransferError::GenericBatchError {
+            error_code: Nat::from(1u8),
+            message,
+        })]);
+    }
+    
+    // Items are validated against current state, so a token may only move once per batch
+    let mut token_ids = BTreeSet::new();
+    let prepared: Vec<Result<PendingTransfer, TransferError>> = args.into_iter().map(|arg| {
+        if instruction_counter() > UPDATE_INSTRUCTION_BUDGET {
+            return Err(TransferError::TooManyRequests);
+        }
+        let pending = prepare(arg)?;
+        if !token_ids.insert(pending.nft.token_id) {
+            return Err(TransferError::GenericError {
+                error_code: Nat::from(5u8),
+                message: format!("Token {} appears more than once in the batch", pending.nft.token_id),
+            });
+        }
+        Ok(pending)
+    }).collect();
+    
+    if prepared.iter().any(|result| result.is_err()) {
+        return Err(prepared.into_iter().map(Result::err).collect());
+    }
+    
+    Ok(prepared.into_iter().flatten().map(commit_transfer).collect())
+}
+
+// Reject created_at_time values outside the transaction window
+fn check_created_at_time(created_at_time: Option<u64>) -> Result<(), TransferError> {
+    let Some(created_at_time) = created_at_time else {
+        return Ok(());
+    };
+    
+    let now = time();
+    if created_at_time.saturating_add(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS) < now {
+        return Err(TransferError::TooOld);
+    }
+    if created_at_time > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+        return Err(TransferError::CreatedInFuture { ledger_time: now });
+    }
+    Ok(())
+}
+
+fn transaction_hash(btype: &str, tx: &[(String, Value)]) -> Blob<32> {
+    let hash = hash_value(&Value::Map(vec![
+        ("btype".to_string(), Value::Text(btype.to_string())),
+        ("tx".to_string(), Value::Map(tx.to_vec())),
+    ]));
+    Blob::try_from(&hash[..]).expect("SHA-256 hashes are 32 bytes")
+}
+
+// Block index of an identical transaction still inside the window
+fn find_duplicate(tx_hash: &Blob<32>) -> Option<u64> {
+    TX_DEDUP.with(|dedup| dedup.borrow().get(tx_hash))
+}
+
+fn remember_transaction(tx_hash: Blob<32>, created_at_time: u64, tx_index: u64) {
+    prune_transaction_dedup();
+    
+    TX_DEDUP.with(|dedup| dedup.borrow_mut().insert(tx_hash, tx_index));
+    TX_DEDUP_EXPIRY.with(|expiry| expiry.borrow_mut().insert((created_at_time, tx_hash), ()));
+}
+
+// Forget transactions that are too old to be replayed anyway
+fn prune_transaction_dedup() {
+    let cutoff = time().saturating_sub(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS);
+    let expired: Vec<(u64, Blob<32>)> = TX_DEDUP_EXPIRY.with(|expiry| {
+        expiry.borrow()
+            .iter()
+            .take_while(|((created_at_time, _), _)| *created_at_time < cutoff)
+            .take(MAX_DEDUP_PRUNE_PER_CALL)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    
+    for key in expired {
+        TX_DEDUP_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&key));
+        TX_DEDUP.with(|dedup| dedup.borrow_mut().remove(&key.1));
+    }
+}
+
+// Move a token between accounts in the owner index
+fn move_owned_token(token_id: u64, from: &Account, to: Account) {
+    OWNER_TOKENS.with(|owner_tokens| {
+        let mut tokens = owner_tokens.borrow_mut();
+        tokens.remove(&(from.clone(), token_id));
+        tokens.insert((to, token_id), ());
+    });
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct BurnArgs {
+    from_subaccount: Option<Vec<u8>>,
+    token_id: u64,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// Burn tokens held by the caller
+#[update]
+fn icrc7_burn(args: Vec<BurnArgs>) -> Vec<Result<u64, TransferError>> {
+    update_batch(args, |arg| {
+        let from = Account { owner: caller(), subaccount: arg.from_subaccount }
+            .normalized()
+            .map_err(invalid_account_error)?;
+        check_memo(&arg.memo).map_err(invalid_memo_error)?;
+        
+        let nft = match NFTS.with(|nfts| nfts.borrow().get(&arg.token_id)) {
+            Some(nft) => nft,
+            None => return Err(TransferError::NotFound),
+        };
+        
+        if nft.owner_account() != from {
+            return Err(TransferError::Unauthorized);
+        }
+        
+        Ok(burn_token(&nft, None, arg.memo, arg.created_at_time))
+    })
+}
+
+// Burn any token for moderation - system admin only, and only when the collection allows it
+#[update]
+fn admin_burn(token_id: u64, memo: Option<Vec<u8>>) -> Result<u64, String> {
+    let caller = caller();
+    
+    if !is_system_admin(caller) {
+        return Err("Unauthorized: Only system admins can burn other users' tokens".to_string());
+    }
+    
+    let enabled = COLLECTION_DETAILS.with(|details| details.borrow().admin_burn_enabled.unwrap_or(false));
+    if !enabled {
+        return Err("Admin burns are disabled for this collection".to_string());
+    }
+    check_memo(&memo)?;
+    
+    let nft = NFTS.with(|nfts| nfts.borrow().get(&token_id))
+        .ok_or_else(|| format!("Token {} not found", token_id))?;
+    
+    Ok(burn_token(&nft, Some(caller), memo, None))
+}
+
+// Remove a token and everything indexed by it, returning the 7burn block index.
+// The asset stays marked as minted so the supply cap keeps counting it.
+fn burn_token(nft: &NFT, admin: Option<Principal>, memo: Option<Vec<u8>>, created_at_time: Option<u64>) -> u64 {
+    let token_id = nft.token_id;
+    let from = nft.owner_account();
+    
+    NFTS.with(|nfts| {
+        nfts.borrow_mut().remove(&token_id);
+    });
+    
+    OWNER_TOKENS.with(|owner_tokens| {
+        owner_tokens.borrow_mut().remove(&(from.clone(), token_id));
+    });
+    
+    TOKEN_ASSETS.with(|assets| {
+        assets.borrow_mut().remove(&token_id);
+    });
+    
+    clear_token_approvals(token_id);
+    
+    BURNED_COUNT.with(|burned| *burned.borrow_mut() += 1);
+    
+    let mut tx = vec![
+        ("tid".to_string(), nat_value(token_id)),
+        ("from".to_string(), account_value(&from)),
+    ];
+    if let Some(admin) = admin {
+        tx.push(("burned_by".to_string(), account_value(&Account { owner: admin, subaccount: None })));
+    }
+    push_memo_and_time(&mut tx, memo, created_at_time);
+    record_block(BTYPE_BURN, tx)
+}
+
+// Drop every spender approval on a token
+fn clear_token_approvals(token_id: u64) {
+    let keys = token_approval_keys(token_id);
+    TOKEN_APPROVALS.with(|approvals| {
+        let mut approvals = approvals.borrow_mut();
+        for key in keys {
+            approvals.remove(&key);
+        }
+    });
+}
+
+// ==== ICRC-37 EXTENSION METHODS ====
+
+// ICRC-37 methods for token approvals
+#[update]
+fn icrc37_approve_tokens(args: Vec<ApprovalArgs>) -> Vec<Result<u64, TransferError>> {
+    update_batch(args, |arg| {
+        let caller_principal = caller();
+        let token_id = arg.token_id;
+        
+        let owner = Account { owner: caller_principal, subaccount: arg.from_subaccount.clone() }
+            .normalized()
+            .map_err(invalid_account_error)?;
+        let spender = arg.spender.normalized().map_err(invalid_account_error)?;
+        check_memo(&arg.memo).map_err(invalid_memo_error)?;
+        
+        // Check if token exists and the caller's account owns it
+        let token_owner = match NFTS.with(|nfts| nfts.borrow().get(&token_id).map(|nft| nft.owner_account())) {
+            Some(token_owner) => token_owner,
+            None => return Err(TransferError::NotFound),
+        };
+        
+        if token_owner != owner {
+            return Err(TransferError::Unauthorized);
+        }
+        
+        let replaces_existing = TOKEN_APPROVALS.with(|approvals| {
+            approvals.borrow().contains_key(&(token_id, spender.clone()))
+        });
+        if !replaces_existing && token_approval_keys(token_id).len() as u64 >= MAX_APPROVALS_PER_TOKEN_OR_COLLECTION {
+            return Err(too_many_approvals_error());
+        }
+        
+        // Record timestamp
+        let timestamp = time();
+        
+        // Create approval info
+        let approval_info = ApprovalInfo {
+            spender: spender.owner,
+            token_id,
+            expires_at: arg.expires_at,
+            created_at: timestamp,
+            memo: arg.memo.clone(),
+        };
+        
+        // Add to approvals
+        TOKEN_APPROVALS.with(|approvals| {
+            approvals.borrow_mut().insert((token_id, spender.clone()), approval_info);
+        });
+        
+        // Record the approval in the block log
+        let mut tx = vec![
+            ("tid".to_string(), nat_value(token_id)),
+            ("from".to_string(), account_value(&owner)),
+            ("spender".to_string(), account_value(&spender)),
+        ];
+        if let Some(expires_at) = arg.expires_at {
+            tx.push(("exp".to_string(), nat_value(expires_at)));
+        }
+        push_memo_and_time(&mut tx, arg.memo, arg.created_at_time);
+        record_block(BTYPE_APPROVE, tx);
+        
+        Ok(timestamp)
+    })
+}
+
+#[update]
+fn icrc37_approve_collection(args: ApprovalCollectionArgs) -> Result<u64, TransferError> {
+    let caller_principal = caller();
+    
+    let owner = Account { owner: caller_principal, subaccount: args.from_subaccount.clone() }
+        .normalized()
+        .map_err(invalid_account_error)?;
+    let spender = args.spender.normalized().map_err(invalid_account_error)?;
+    check_memo(&args.memo).map_err(invalid_memo_error)?;
+    
+    // Check for self-approval (unnecessary but could be problematic)
+    if owner == spender {
+        return Err(TransferError::GenericError {
+            error_code: Nat::from(1u8),
+            message: "Self-approval is unnecessary".to_string(),
+        });
+    }
+    
+    let replaces_existing = COLLECTION_APPROVALS.with(|approvals| {
+        approvals.borrow().contains_key(&(owner.clone(), spender.clone()))
+    });
+    if !replaces_existing && collection_approval_keys(&owner).len() as u64 >= MAX_APPROVALS_PER_TOKEN_OR_COLLECTION {
+        return Err(too_many_approvals_error());
+    }
+    
+    // Record timestamp
+    let timestamp = time();
+    
+    // Create a dummy approval info (token_id is not relevant for collection approval)
+    let approval_info = ApprovalInfo {
+        spender: spender.owner,
+        token_id: 0, // Not used for collection approval
         expires_at: args.expires_at,
         created_at: timestamp,
+        memo: args.memo.clone(),
+    };
+    
+    // Add to collection approvals
+    COLLECTION_APPROVALS.with(|approvals| {
+        approvals.borrow_mut().insert((owner.clone(), spender.clone()), approval_info);
+    });
+    
+    // Record the collection approval in the block log
+    let mut tx = vec![
+        ("from".to_string(), account_value(&owner)),
+        ("spender".to_string(), account_value(&spender)),
+    ];
+    if let Some(expires_at) = args.expires_at {
+        tx.push(("exp".to_string(), nat_value(expires_at)));
+    }
+    push_memo_and_time(&mut tx, args.memo, args.created_at_time);
+    record_block(BTYPE_APPROVE_COLLECTION, tx);
+    
+    Ok(timestamp)
+}
+
+#[query]
+fn icrc37_is_approved(spender: Account, from: Account, token_id: u64) -> bool {
+    let (spender, from) = match (spender.normalized(), from.normalized()) {
+        (Ok(spender), Ok(from)) => (spender, from),
+        _ => return false,
+    };
+    
+    // Check token-specific approval
+    let token_approved = TOKEN_APPROVALS.with(|approvals| {
+        approvals.borrow().get(&(token_id, spender.clone()))
+            .is_some_and(|approval_info| approval_info.is_active())
+    });
+    
+    if token_approved {
+        return true;
+    }
+    
+    // Check collection-wide approval
+    COLLECTION_APPROVALS.with(|approvals| {
+        approvals.borrow().get(&(from, spender))
+            .is_some_and(|approval_info| approval_info.is_active())
+    })
+}
+
+// Each Ok carries the block index of the transfer, as icrc7_transfer does.
+// Earlier versions returned the transfer timestamp here.
+#[update]
+fn icrc37_transfer_from(args: Vec<TransferFromArgs>) -> Vec<Result<u64, TransferError>> {
+    let caller = caller();
+    update_batch(args, |arg| prepare_transfer_from(caller, arg).map(commit_transfer))
+}
+
+// All-or-nothing icrc37_transfer_from, see icrc7_atomic_transfer
+#[update]
+fn icrc37_atomic_transfer_from(args: Vec<TransferFromArgs>) -> Result<Vec<u64>, Vec<Option<TransferError>>> {
+    let caller = caller();
+    atomic_batch(args, |arg| prepare_transfer_from(caller, arg))
+}
+
+fn prepare_transfer_from(caller: Principal, arg: TransferFromArgs) -> Result<PendingTransfer, TransferError> {
+    let token_id = arg.token_id;
+    
+    let spender = Account { owner: caller, subaccount: arg.spender_subaccount.clone() }
+        .normalized()
+        .map_err(invalid_account_error)?;
+    let from = arg.from.normalized().map_err(invalid_account_error)?;
+    let to = arg.to.normalized().map_err(invalid_account_error)?;
+    check_memo(&arg.memo).map_err(invalid_memo_error)?;
+    
+    // Verify token exists
+    let nft = match NFTS.with(|nfts| nfts.borrow().get(&token_id)) {
+        Some(nft) => nft,
+        None => return Err(TransferError::NotFound),
+    };
+    
+    // Check that the from account owns the token
+    if nft.owner_account() != from {
+        return Err(TransferError::Unauthorized);
+    }
+    
+    // Check if caller is approved for this token or collection
+    if !icrc37_is_approved(spender.clone(), from.clone(), token_id) {
+        return Err(TransferError::Unauthorized);
+    }
+    
+    let mut tx = vec![
+        ("tid".to_string(), nat_value(token_id)),
+        ("spender".to_string(), account_value(&spender)),
+        ("from".to_string(), account_value(&from)),
+        ("to".to_string(), account_value(&to)),
+    ];
+    push_memo_and_time(&mut tx, arg.memo, arg.created_at_time);
+    
+    Ok(PendingTransfer { nft, from, to, btype: BTYPE_TRANSFER_FROM, tx, dedup: None })
+}
+
+#[update]
+fn icrc37_revoke_token_approvals(args: RevokeTokenApprovalsArgs) -> Vec<Result<u64, RevokeTokenApprovalError>> {
+    if let Err(message) = check_batch_size(args.token_ids.len(), max_revoke_approvals()) {
+        return vec![Err(RevokeTokenApprovalError::GenericBatchError {
+            error_code: Nat::from(1u8),
+            message,
+        })];
+    }
+    
+    let owner = match (Account { owner: caller(), subaccount: args.from_subaccount.clone() }).normalized() {
+        Ok(owner) => owner,
+        Err(message) => return vec![Err(RevokeTokenApprovalError::GenericBatchError {
+            error_code: Nat::from(2u8),
+            message,
+        })],
+    };
+    let spender = match args.spender.clone().map(|spender| spender.normalized()).transpose() {
+        Ok(spender) => spender,
+        Err(message) => return vec![Err(RevokeTokenApprovalError::GenericBatchError {
+            error_code: Nat::from(2u8),
+            message,
+        })],
+    };
+    if let Err(message) = check_memo(&args.memo) {
+        return vec![Err(RevokeTokenApprovalError::GenericBatchError {
+            error_code: Nat::from(4u8),
+            message,
+        })];
+    }
+    
+    args.token_ids.iter().map(|&token_id| {
+        let token_owner = NFTS.with(|nfts| nfts.borrow().get(&token_id).map(|nft| nft.owner_account()))
+            .ok_or(RevokeTokenApprovalError::NonExistingTokenId)?;
+        if token_owner != owner {
+            return Err(RevokeTokenApprovalError::Unauthorized);
+        }
+        
+        let keys = match &spender {
+            Some(spender) => vec![(token_id, spender.clone())],
+            None => token_approval_keys(token_id),
+        };
+        let removed = TOKEN_APPROVALS.with(|approvals| {
+            let mut approvals = approvals.borrow_mut();
+            keys.iter().filter(|key| approvals.remove(key).is_some()).count()
+        });
+        if removed == 0 {
+            return Err(RevokeTokenApprovalError::ApprovalDoesNotExist);
+        }
+        
+        // A revoke block without a spender revokes every spender
+        let mut tx = vec![
+            ("tid".to_string(), nat_value(token_id)),
+            ("from".to_string(), account_value(&owner)),
+        ];
+        if let Some(spender) = &spender {
+            tx.push(("spender".to_string(), account_value(spender)));
+        }
+        push_memo_and_time(&mut tx, args.memo.clone(), args.created_at_time);
+        Ok(record_block(BTYPE_REVOKE, tx))
+    }).collect()
+}
+
+#[update]
+fn icrc37_revoke_collection_approvals(args: RevokeCollectionApprovalsArgs) -> Result<u64, RevokeCollectionApprovalError> {
+    let generic_error = |error_code: u8| move |message: String| RevokeCollectionApprovalError::GenericError {
+        error_code: Nat::from(error_code),
+        message,
+    };
+    
+    let owner = Account { owner: caller(), subaccount: args.from_subaccount }
+        .normalized()
+        .map_err(generic_error(2))?;
+    let spender = args.spender.map(|spender| spender.normalized()).transpose().map_err(generic_error(2))?;
+    check_memo(&args.memo).map_err(generic_error(4))?;
+    
+    let keys = match &spender {
+        Some(spender) => vec![(owner.clone(), spender.clone())],
+        None => collection_approval_keys(&owner),
+    };
+    let removed = COLLECTION_APPROVALS.with(|approvals| {
+        let mut approvals = approvals.borrow_mut();
+        keys.iter().filter(|key| approvals.remove(key).is_some()).count()
+    });
+    if removed == 0 {
+        return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist);
+    }
+    
+    let mut tx = vec![("from".to_string(), account_value(&owner))];
+    if let Some(spender) = &spender {
+        tx.push(("spender".to_string(), account_value(spender)));
+    }
+    push_memo_and_time(&mut tx, args.memo, args.created_at_time);
+    Ok(record_block(BTYPE_REVOKE_COLLECTION, tx))
+}
+
+// Active approvals on a token, ordered by spender and starting after prev
+#[query]
+fn icrc37_get_token_approvals(token_id: u64, prev: Option<TokenApproval>, take: Option<u64>) -> Vec<TokenApproval> {
+    let take_amount = take.unwrap_or(DEFAULT_TAKE_VALUE).min(MAX_TAKE_VALUE) as usize;
+    let start = prev.map(|prev| prev.spender).unwrap_or(MIN_ACCOUNT);
+    
+    TOKEN_APPROVALS.with(|approvals| {
+        approvals.borrow()
+            .range((token_id, start.clone())..)
+            .take_while(|((id, _), _)| *id == token_id)
+            .filter(|((_, spender), info)| *spender != start && info.is_active())
+            .take(take_amount)
+            .map(|((token_id, spender), info)| TokenApproval {
+                token_id,
+                spender,
+                expires_at: info.expires_at,
+                memo: info.memo,
+                created_at_time: info.created_at,
+            })
+            .collect()
+    })
+}
+
+// Active collection approvals granted by an account, ordered by spender and starting after prev
+#[query]
+fn icrc37_get_collection_approvals(owner: Account, prev: Option<CollectionApproval>, take: Option<u64>) -> Vec<CollectionApproval> {
+    let take_amount = take.unwrap_or(DEFAULT_TAKE_VALUE).min(MAX_TAKE_VALUE) as usize;
+    let owner = match owner.normalized() {
+        Ok(owner) => owner,
+        Err(_) => return Vec::new(),
+    };
+    let start = prev.map(|prev| prev.spender).unwrap_or(MIN_ACCOUNT);
+    
+    COLLECTION_APPROVALS.with(|approvals| {
+        approvals.borrow()
+            .range((owner.clone(), start.clone())..)
+            .take_while(|((from, _), _)| *from == owner)
+            .filter(|((_, spender), info)| *spender != start && info.is_active())
+            .take(take_amount)
+            .map(|((_, spender), info)| CollectionApproval {
+                spender,
+                expires_at: info.expires_at,
+                memo: info.memo,
+                created_at_time: info.created_at,
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn icrc37_max_approvals_per_token_or_collection() -> Option<u64> {
+    Some(MAX_APPROVALS_PER_TOKEN_OR_COLLECTION)
+}
+
+#[query]
+fn icrc37_max_revoke_approvals() -> Option<u64> {
+    Some(max_revoke_approvals())
+}
+
+// Revocations are update calls and share the update batch limit
+fn max_revoke_approvals() -> u64 {
+    COLLECTION_DETAILS.with(|details| max_update_batch_size(&details.borrow()) as u64)
+}
+
+// Keys of every approval on a token, expired ones included
+fn token_approval_keys(token_id: u64) -> Vec<(u64, Account)> {
+    TOKEN_APPROVALS.with(|approvals| {
+        approvals.borrow()
+            .range((token_id, MIN_ACCOUNT)..)
+            .take_while(|((id, _), _)| *id == token_id)
+            .map(|(key, _)| key)
+            .collect()
+    })
+}
+
+// Keys of every collection approval granted by an account, expired ones included
+fn collection_approval_keys(owner: &Account) -> Vec<(Account, Account)> {
+    COLLECTION_APPROVALS.with(|approvals| {
+        approvals.borrow()
+            .range((owner.clone(), MIN_ACCOUNT)..)
+            .take_while(|((from, _), _)| from == owner)
+            .map(|(key, _)| key)
+            .collect()
+    })
+}
+
+fn too_many_approvals_error() -> TransferError {
+    TransferError::GenericError {
+        error_code: Nat::from(3u8),
+        message: format!("At most {} approvals are allowed per token or collection", MAX_APPROVALS_PER_TOKEN_OR_COLLECTION),
+    }
+}
+
+// Memos are stored with approvals and logged in blocks, so their size is capped
+fn check_memo(memo: &Option<Vec<u8>>) -> Result<(), String> {
+    match memo {
+        Some(memo) if memo.len() > MAX_MEMO_LENGTH => Err(format!("Memo must be at most {} bytes", MAX_MEMO_LENGTH)),
+        _ => Ok(()),
+    }
+}
+
+fn invalid_memo_error(message: String) -> TransferError {
+    TransferError::GenericError {
+        error_code: Nat::from(4u8),
+        message,
+    }
+}
+
+// ==== TESTING FUNCTIONS ====
+
+// Get the caller's principal ID - useful for testing
+#[query]
+fn whoami() -> Principal {
+    caller()
+}
+
+// Add the caller as a system admin - only for testing purposes
+#[update]
+fn make_me_admin() -> Result<(), String> {
+    let caller_principal = caller();
+    
+    // Check if already an admin to avoid error messages
+    if is_admin(caller_principal) {
+        return Ok(());
+    }
+    
+    // Add caller as a system admin
+    ADMINS.with(|admins| {
+        admins.borrow_mut().insert(caller_principal, AdminType::System);
+    });
+    
+    Ok(())
+}
+
+// ==== ADMIN AND WHITELIST FUNCTIONS ====
+
+#[update]
+fn add_admin(user: Principal, admin_type: AdminType) -> Result<(), String> {
+    let caller = caller();
+    
+    // Only system admins can add new admins
+    if !is_system_admin(caller) {
+        return Err("Unauthorized: Only system admins can add new admins".to_string());
+    }
+    
+    ADMINS.with(|admins| {
+        admins.borrow_mut().insert(user, admin_type);
+    });
+    
+    // Also add to whitelist automatically
+    WHITELIST.with(|whitelist| {
+        whitelist.borrow_mut().insert(user, true);
+    });
+    
+    Ok(())
+}
+
+#[update]
+fn remove_admin(user: Principal) -> Result<(), String> {
+    let caller = caller();
+    
+    // Check if caller is a system admin
+    if !is_system_admin(caller) {
+        return Err("Unauthorized: Only system admins can remove admins".to_string());
+    }
+    
+    // Cannot remove yourself if you're the only system admin
+    if user == caller && count_system_admins() <= 1 {
+        return Err("Cannot remove the last system admin".to_string());
+    }
+    
+    // Remove the admin
+    ADMINS.with(|admins| {
+        admins.borrow_mut().remove(&user);
+    });
+    
+    Ok(())
+}
+
+#[query]
+fn get_admins() -> Vec<Admin> {
+    ADMINS.with(|admins| {
+        admins.borrow()
+            .iter()
+            .map(|(owner, admin_type)| {
+                Admin {
+                    owner: *owner,
+                    admin_type: admin_type.clone(),
+                }
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn is_admin_type(user: Principal, required_type: AdminType) -> bool {
+    ADMINS.with(|admins| {
+        admins.borrow().get(&user) == Some(&required_type)
+    })
+}
+
+#[update]
+fn add_to_whitelist(user: Principal) -> Result<(), String> {
+    let caller = caller();
+
+    if !is_admin(caller) {
+        return Err("Unauthorized: Only admins can add users to whitelist".to_string());
+    }
+    
+    WHITELIST.with(|whitelist| {
+        whitelist.borrow_mut().insert(user, true);
+    });
+    
+    Ok(())
+}
+
+#[update]
+fn remove_from_whitelist(user: Principal) -> Result<(), String> {
+    let caller = caller();
+
+    if !is_admin(caller) {
+        return Err("Unauthorized: Only admins can remove users from whitelist".to_string());
+    }
+    
+    WHITELIST.with(|whitelist| {
+        whitelist.borrow_mut().remove(&user);
+    });
+    
+    Ok(())
+}
+
+#[query]
+fn is_whitelisted(user: Principal) -> bool {
+    WHITELIST.with(|whitelist| {
+        whitelist.borrow().get(&user).is_some()
+    })
+}
+
+// Helper functions for admin checks
+fn is_admin(user: Principal) -> bool {
+    ADMINS.with(|admins| {
+        admins.borrow().contains_key(&user)
+    })
+}
+
+fn is_system_admin(user: Principal) -> bool {
+    ADMINS.with(|admins| {
+        admins.borrow().get(&user) == Some(&AdminType::System)
+    })
+}
+
+fn is_functional_admin(user: Principal) -> bool {
+    ADMINS.with(|admins| {
+        admins.borrow().get(&user).is_some()
+    })
+}
+
+// Helper to count system admins
+fn count_system_admins() -> usize {
+    ADMINS.with(|admins| {
+        admins.borrow()
+            .iter()
+            .filter(|(_, admin_type)| **admin_type == AdminType::System)
+            .count()
+    })
+}
+
+// ==== MINTING FUNCTIONS ====
+
+#[update]
+async fn mint(args: MintArgs) -> Result<u64, String> {
+    let caller = caller();
+    let current_time = time();
+    
+    // Check that minting is active for this user
+    let (price, currency, asset_ids) = COLLECTION_DETAILS.with(|details| {
+        let details = details.borrow();
+        
+        // Check if pricing is enabled
+        if !details.pricing_enabled {
+            return Err("Minting is not enabled".to_string());
+        }
+        
+        // Check if user is in whitelist
+        let user_in_whitelist = WHITELIST.with(|whitelist| {
+            whitelist.borrow().get(&caller).copied().unwrap_or(false)
+        });
+        
+        // Find active schedules that match the user's status
+        let active_schedules: Vec<&MintSchedule> = details.mint_schedules.iter()
+            .filter(|s| s.active)
+            .filter(|s| {
+                // Check if time constraints are met
+                let time_valid = match (s.start_time, s.end_time) {
+                    (Some(start), Some(end)) => current_time >= start && current_time <= end,
+                    (Some(start), None) => current_time >= start,
+                    (None, Some(end)) => current_time <= end,
+                    (None, None) => true,
+                };
+                
+                // Check if user status matches the schedule
+                let status_matches = if s.whitelist_only {
+                    user_in_whitelist
+                } else {
+                    true // Non-whitelist schedules apply to everyone
+                };
+                
+                time_valid && status_matches
+            })
+            .collect();
+        
+        if active_schedules.is_empty() {
+            return Err("No active minting schedules available for this user".to_string());
+        }
+        
+        let active_schedules = select_schedule(active_schedules, &args.schedule)?;
+        
+        // Check max supply if set, counting mints that are still waiting on payment
+        if let Some(max_supply) = details.max_supply {
+            let minted_count = TOKEN_ID_COUNTER.with(|counter| *counter.borrow()) + reserved_mint_count();
+            if minted_count >= max_supply {
+                return Err("Maximum supply reached".to_string());
+            }
+        }
+        
+        // Get the price for this minting (1 NFT)
+        let quantity = 1;
+        
+        let asset_ids = resolve_mint_assets(vec![args.asset_id.clone()], quantity)?;
+        
+        // Get the appropriate price from the active schedules
+        let (price, currency) = get_active_mint_price(quantity, &active_schedules)?
+            .ok_or_else(|| "No price available for this quantity".to_string())?;
+        
+        Ok((price, currency, asset_ids))
+    })?;
+    
+    // Hold the asset while the ledger call is in flight
+    reserve_mint_assets(&asset_ids);
+    let receipt = match collect_payment(caller, &currency, price, args.payment).await {
+        Ok(receipt) => receipt,
+        Err(e) => {
+            release_mint_assets(&asset_ids);
+            return Err(e);
+        }
+    };
+    
+    // Mint the NFT now that payment has been taken
+    let result = mint_reserved_assets(caller, &asset_ids);
+    release_mint_assets(&asset_ids);
+    
+    let new_token_id = match result {
+        Ok(token_ids) => token_ids[0],
+        Err(e) => return Err(refund_payment(receipt, e).await),
+    };
+    
+    Ok(new_token_id)
+}
+
+// New function to mint multiple NFTs
+#[update]
+async fn mint_bundle(args: MintBundleArgs) -> Result<Vec<u64>, String> {
+    let caller = caller();
+    let current_time = time();
+    let quantity = args.quantity;
+    
+    if quantity == 0 {
+        return Err("Quantity must be greater than 0".to_string());
+    }
+    
+    // Check that minting is active for this user
+    let (price, currency, asset_ids) = COLLECTION_DETAILS.with(|details| {
+        let details = details.borrow();
+        
+        // Check if pricing is enabled
+        if !details.pricing_enabled {
+            return Err("Minting is not enabled".to_string());
+        }
+        
+        // Check if user is in whitelist
+        let user_in_whitelist = WHITELIST.with(|whitelist| {
+            whitelist.borrow().get(&caller).copied().unwrap_or(false)
+        });
+        
+        // Find active schedules that match the user's status
+        let active_schedules: Vec<&MintSchedule> = details.mint_schedules.iter()
+            .filter(|s| s.active)
+            .filter(|s| {
+                // Check if time constraints are met
+                let time_valid = match (s.start_time, s.end_time) {
+                    (Some(start), Some(end)) => current_time >= start && current_time <= end,
+                    (Some(start), None) => current_time >= start,
+                    (None, Some(end)) => current_time <= end,
+                    (None, None) => true,
+                };
+                
+                // Check if user status matches the schedule
+                let status_matches = if s.whitelist_only {
+                    user_in_whitelist
+                } else {
+                    true // Non-whitelist schedules apply to everyone
+                };
+                
+                time_valid && status_matches
+            })
+            .collect();
+        
+        if active_schedules.is_empty() {
+            return Err("No active minting schedules available for this user".to_string());
+        }
+        
+        let active_schedules = select_schedule(active_schedules, &args.schedule)?;
+        
+        // Check max supply if set, counting mints that are still waiting on payment
+        if let Some(max_supply) = details.max_supply {
+            let minted_count = TOKEN_ID_COUNTER.with(|counter| *counter.borrow()) + reserved_mint_count();
+            if minted_count + quantity > max_supply {
+                return Err(format!("Requested quantity exceeds available supply: {} left",
+                                   max_supply.saturating_sub(minted_count)));
+            }
+        }
+        
+        // Get the price for this bundle size
+        let (price, currency) = get_active_mint_price(quantity, &active_schedules)?
+            .ok_or_else(|| format!("No price available for quantity {}", quantity))?;
+        
+        let asset_ids = resolve_mint_assets(args.asset_ids.clone(), quantity)?;
+        
+        Ok((price, currency, asset_ids))
+    })?;
+    
+    // Hold the assets while the ledger call is in flight
+    reserve_mint_assets(&asset_ids);
+    let receipt = match collect_payment(caller, &currency, price, args.payment).await {
+        Ok(receipt) => receipt,
+        Err(e) => {
+            release_mint_assets(&asset_ids);
+            return Err(e);
+        }
+    };
+    
+    // Mint the NFTs now that payment has been taken
+    let result = mint_reserved_assets(caller, &asset_ids);
+    release_mint_assets(&asset_ids);
+    
+    let token_ids = match result {
+        Ok(token_ids) => token_ids,
+        Err(e) => return Err(refund_payment(receipt, e).await),
+    };
+    
+    Ok(token_ids)
+}
+
+// Get available bundles for the user
+#[query]
+fn get_available_bundles(user: Principal) -> Vec<(MintSchedule, Vec<PricedBundle>)> {
+    let current_time = time();
+    
+    COLLECTION_DETAILS.with(|details| {
+        let details = details.borrow();
+        
+        if !details.pricing_enabled {
+            return vec![];
+        }
+        
+        // Check if user is in whitelist
+        let user_in_whitelist = WHITELIST.with(|whitelist| {
+            whitelist.borrow().get(&user).copied().unwrap_or(false)
+        });
+        
+        // Find active schedules that match the user's status
+        details.mint_schedules.iter()
+            .filter(|s| s.active)
+            .filter(|s| {
+                // Check if time constraints are met
+                let time_valid = match (s.start_time, s.end_time) {
+                    (Some(start), Some(end)) => current_time >= start && current_time <= end,
+                    (Some(start), None) => current_time >= start,
+                    (None, Some(end)) => current_time <= end,
+                    (None, None) => true,
+                };
+                
+                // Check if user status matches the schedule
+                let status_matches = if s.whitelist_only {
+                    user_in_whitelist
+                } else {
+                    true // Non-whitelist schedules apply to everyone
+                };
+                
+                time_valid && status_matches
+            })
+            .map(|schedule| {
+                let currency = schedule_currency(schedule);
+                let bundles = schedule.bundle_prices.iter()
+                    .map(|bundle| PricedBundle {
+                        quantity: bundle.quantity,
+                        price: bundle.price.clone(),
+                        currency: currency.clone(),
+                    })
+                    .collect();
+                (schedule.clone(), bundles)
+            })
+            .collect()
+    })
+}
+
+// ==== TOKEN METADATA ADMINISTRATION ====
+
+// Upper bound on the candid size of the fields changed by one edit, which keeps
+// the logged block well inside the stable block size
+const MAX_METADATA_PATCH_SIZE: usize = 4 * 1024;
+
+// Fields left as None are kept unchanged
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct UpdateTokenMetadataArgs {
+    token_id: u64,
+    name: Option<String>,
+    description: Option<String>,
+    image_url: Option<String>,
+    properties: Option<Value>,  // Trait attributes: a Map of trait -> value, or an Array of attribute maps
+    layers: Option<Vec<String>>, // Asset keys of the layers, an empty list removes layering
+}
+
+// Edit the metadata of a minted token - admin only
+#[update]
+fn update_token_metadata(args: UpdateTokenMetadataArgs) -> Result<u64, String> {
+    let caller = caller();
+    
+    if !is_admin(caller) {
+        return Err("Unauthorized: Only admins can update token metadata".to_string());
+    }
+    
+    apply_token_metadata_update(caller, args)
+}
+
+// Edit the metadata of several tokens - admin only. Each edit succeeds or fails on its own.
+#[update]
+fn update_token_metadata_batch(args: Vec<UpdateTokenMetadataArgs>) -> Vec<Result<u64, String>> {
+    let caller = caller();
+    
+    if !is_admin(caller) {
+        return vec![Err("Unauthorized: Only admins can update token metadata".to_string())];
+    }
+    
+    let max = COLLECTION_DETAILS.with(|details| max_update_batch_size(&details.borrow()));
+    if let Err(message) = check_batch_size(args.len(), max) {
+        return vec![Err(message)];
+    }
+    
+    args.into_iter()
+        .map(|arg| apply_token_metadata_update(caller, arg))
+        .collect()
+}
+
+// Permanently freeze the metadata of one token, or of the whole collection when
+// no token is given - system admin only. Returns the block index.
+// Freezing also locks the bytes of every asset the frozen tokens reference, and
+// a collection-wide freeze locks the base URL.
+#[update]
+fn freeze_metadata(token_id: Option<u64>) -> Result<u64, String> {
+    let caller = caller();
+    
+    if !is_system_admin(caller) {
+        return Err("Unauthorized: Only system admins can freeze metadata".to_string());
+    }
+    
+    if is_collection_metadata_frozen() {
+        return Err("Collection metadata is already frozen".to_string());
+    }
+    
+    let mut tx = vec![("from".to_string(), account_value(&Account { owner: caller, subaccount: None }))];
+    match token_id {
+        Some(token_id) => {
+            let nft = NFTS.with(|nfts| nfts.borrow().get(&token_id))
+                .ok_or_else(|| format!("Token {} not found", token_id))?;
+            if is_token_metadata_frozen(token_id) {
+                return Err(format!("Metadata of token {} is already frozen", token_id));
+            }
+            FROZEN_TOKENS.with(|frozen| frozen.borrow_mut().insert(token_id, ()));
+            freeze_token_assets(&nft);
+            tx.push(("tid".to_string(), nat_value(token_id)));
+        }
+        // Minted assets are covered by the flag, token layers are listed once here
+        None => {
+            NFTS.with(|nfts| {
+                for (_, nft) in nfts.borrow().iter() {
+                    freeze_token_layers(&nft);
+                }
+            });
+            COLLECTION_DETAILS.with(|details| details.borrow_mut().metadata_frozen_at = Some(time()));
+        }
+    }
+    
+    Ok(record_block(BTYPE_METADATA_FREEZE, tx))
+}
+
+#[query]
+fn is_metadata_frozen(token_id: Option<u64>) -> bool {
+    is_collection_metadata_frozen() || token_id.is_some_and(is_token_metadata_frozen)
+}
+
+fn is_collection_metadata_frozen() -> bool {
+    COLLECTION_DETAILS.with(|details| details.borrow().metadata_frozen_at.is_some())
+}
+
+fn is_token_metadata_frozen(token_id: u64) -> bool {
+    FROZEN_TOKENS.with(|frozen| frozen.borrow().contains_key(&token_id))
+}
+
+// Assets of frozen tokens and the layers of every token in a frozen collection are
+// listed in FROZEN_ASSETS. Once the whole collection is frozen every minted asset
+// is frozen too.
+fn is_asset_frozen(key: &str) -> bool {
+    let asset_key = AssetKey::new(key);
+    FROZEN_ASSETS.with(|frozen| frozen.borrow().contains_key(&asset_key))
+        || (is_collection_metadata_frozen() && MINTED_ASSETS.with(|minted| minted.borrow().contains_key(&asset_key)))
+}
+
+// Lock the token's own asset and its layers
+fn freeze_token_assets(nft: &NFT) {
+    if let Some(asset_key) = TOKEN_ASSETS.with(|assets| assets.borrow().get(&nft.token_id)) {
+        FROZEN_ASSETS.with(|frozen| frozen.borrow_mut().insert(asset_key, ()));
+    }
+    freeze_token_layers(nft);
+}
+
+fn freeze_token_layers(nft: &NFT) {
+    FROZEN_ASSETS.with(|frozen| {
+        let mut frozen = frozen.borrow_mut();
+        for layer in nft.metadata.layers.iter().flatten() {
+            frozen.insert(AssetKey::new(layer), ());
+        }
+    });
+}
+
+fn apply_token_metadata_update(caller: Principal, args: UpdateTokenMetadataArgs) -> Result<u64, String> {
+    let token_id = args.token_id;
+    let mut nft = NFTS.with(|nfts| nfts.borrow().get(&token_id))
+        .ok_or_else(|| format!("Token {} not found", token_id))?;
+    
+    if is_metadata_frozen(Some(token_id)) {
+        return Err(format!("Metadata of token {} is frozen", token_id));
+    }
+    
+    // Changed fields, logged with their new values
+    let mut changes = Vec::new();
+    
+    if let Some(name) = args.name {
+        if name.is_empty() {
+            return Err("Token name cannot be empty".to_string());
+        }
+        changes.push(("icrc7:name".to_string(), Value::Text(name.clone())));
+        nft.metadata.name = name;
+    }
+    
+    if let Some(description) = args.description {
+        changes.push(("icrc7:description".to_string(), Value::Text(description.clone())));
+        nft.metadata.description = description;
+    }
+    
+    if let Some(image_url) = args.image_url {
+        changes.push(("icrc7:metadata:uri:image".to_string(), Value::Text(image_url.clone())));
+        nft.metadata.image_url = image_url;
+    }
+    
+    if let Some(properties) = args.properties {
+        let attributes = trait_attributes(&properties)
+            .ok_or_else(|| "Token properties must be a Map or an Array of attributes".to_string())?;
+        changes.push(("icrc7:metadata:attributes".to_string(), attributes));
+        nft.metadata.properties = Some(properties);
+    }
+    
+    if let Some(layers) = args.layers {
+        if let Some(missing) = layers.iter().find(|key| !asset_exists(key)) {
+            return Err(format!("Layer asset '{}' not found", missing));
+        }
+        changes.push((
+            "icrc37plus:layers".to_string(),
+            Value::Array(layers.iter().map(|key| Value::Text(key.clone())).collect()),
+        ));
+        nft.metadata.is_layered = !layers.is_empty();
+        nft.metadata.layers = if layers.is_empty() { None } else { Some(layers) };
+    }
+    
+    if changes.is_empty() {
+        return Err("No metadata fields to update".to_string());
+    }
+    
+    let changes = Value::Map(changes);
+    let patch_size = candid::encode_one(&changes).map_err(|e| e.to_string())?.len();
+    if patch_size > MAX_METADATA_PATCH_SIZE {
+        return Err(format!("Metadata update of {} bytes exceeds the limit of {} bytes", patch_size, MAX_METADATA_PATCH_SIZE));
+    }
+    let nft_size = candid::encode_one(&nft).map_err(|e| e.to_string())?.len();
+    if nft_size > <NFT as BoundedStorable>::MAX_SIZE as usize {
+        return Err("Token metadata is too large to store".to_string());
+    }
+    
+    NFTS.with(|nfts| {
+        nfts.borrow_mut().insert(token_id, nft);
+    });
+    
+    Ok(record_block(BTYPE_METADATA_UPDATE, vec![
+        ("tid".to_string(), nat_value(token_id)),
+        ("from".to_string(), account_value(&Account { owner: caller, subaccount: None })),
+        ("meta".to_string(), changes),
+    ]))
+}
+
+// ==== CUSTOM QUERY FUNCTIONS ====
+
+#[query]
+fn get_nft(token_id: u64) -> Option<NFT> {
+    load_nft(token_id)
+}
+
+#[query]
+fn get_user_nfts(user: Principal) -> Vec<NFT> {
+    // Collect tokens held by any subaccount of the user
+    let first_account = Account { owner: user, subaccount: None };
+    let token_ids: Vec<u64> = OWNER_TOKENS.with(|owner_tokens| {
+        owner_tokens.borrow()
+            .range((first_account, 0)..)
+            .take_while(|((account, _), _)| account.owner == user)
+            .map(|((_, token_id), _)| token_id)
+            .collect()
+    });
+    
+    token_ids.into_iter().filter_map(load_nft).collect()
+}
+
+#[query]
+fn get_transaction_history(token_id: u64) -> Vec<TransferRecord> {
+    transfer_history(token_id)
+}
+
+#[query]
+fn get_collection_info() -> Vec<(String, Value)> {
+    icrc7_collection_metadata()
+}
+
+// Dedicated method to update the base URL - admin only
+#[update]
+fn update_base_url(new_base_url: String) -> Result<(), String> {
+    let caller = caller();
+    
+    // Check if caller is an admin (either type)
+    if !is_admin(caller) {
+        return Err("Unauthorized: Only admins can update the base URL".to_string());
+    }
+    
+    if is_collection_metadata_frozen() {
+        return Err("Collection metadata is frozen: the base URL can no longer change".to_string());
+    }
+    
+    // Update the base URL
+    COLLECTION_DETAILS.with(|details| {
+        let mut details_ref = details.borrow_mut();
+        details_ref.base_url = new_base_url;
+    });
+    
+    Ok(())
+}
+
+// Update prices for NFT bundles - unified method for admin
+// Deprecated: use update_mint_schedule instead
+#[update]
+fn update_prices(_price_args: String) -> Result<(), String> {
+    Err("This method is deprecated. Use update_mint_schedule instead.".to_string())
+}
+
+// Get minting timeframes - public query
+#[query]
+fn get_minting_timeframes() -> Vec<MintSchedule> {
+    COLLECTION_DETAILS.with(|details| details.borrow().mint_schedules.clone())
+}
+
+// Check if minting is currently active
+#[query]
+fn is_minting_active() -> (bool, bool, u64) {
+    let current_time = time();
+    
+    COLLECTION_DETAILS.with(|details| {
+        let details = details.borrow();
+        
+        // Check if public minting is active
+        let public_minting_active = details.mint_schedules.iter().any(|schedule| {
+            match (schedule.start_time, schedule.end_time) {
+                (Some(start), Some(end)) => current_time >= start && current_time <= end,
+                (Some(start), None) => current_time >= start,
+                (None, Some(end)) => current_time <= end,
+                (None, None) => true,
+            }
+        });
+        
+        // Check if whitelist minting is active
+        let whitelist_active = details.mint_schedules.iter().any(|schedule| {
+            match (schedule.start_time, schedule.end_time) {
+                (Some(start), Some(end)) => current_time >= start && current_time <= end,
+                (Some(start), None) => current_time >= start,
+                (None, Some(end)) => current_time <= end,
+                (None, None) => true,
+            }
+        });
+        
+        (public_minting_active, whitelist_active, current_time)
+    })
+}
+
+// Collection update method - admin only
+#[update]
+fn update_collection_details(args: UpdateCollectionDetailsArgs) -> Result<(), String> {
+    let caller = caller();
+    
+    // Check if caller is an admin (either type)
+    if !is_admin(caller) {
+        return Err("Unauthorized: Only admins can update collection details".to_string());
+    }
+    
+    if let Some(subaccount) = &args.treasury_subaccount {
+        if subaccount.len() != SUBACCOUNT_LENGTH {
+            return Err(format!("Treasury subaccount must be {} bytes", SUBACCOUNT_LENGTH));
+        }
+    }
+    
+    if args.max_query_batch_size == Some(0) || args.max_update_batch_size == Some(0) {
+        return Err("Batch sizes must be at least 1".to_string());
+    }
+    
+    if args.base_url.is_some() && is_collection_metadata_frozen() {
+        return Err("Collection metadata is frozen: the base URL can no longer change".to_string());
+    }
+    
+    if args.name.is_some() || args.token_name_template.is_some() {
+        COLLECTION_DETAILS.with(|details| {
+            let details = details.borrow();
+            let template = args.token_name_template.as_deref().or(details.token_name_template.as_deref());
+            check_token_name_template(template, args.name.as_deref().unwrap_or(&details.name))
+        })?;
+    }
+    
+    // Check if we're trying to update max_supply and if minting has started
+    // (burned tokens still count, the cap applies to tokens ever minted)
+    if args.max_supply.is_some() {
+        let minted_count = TOKEN_ID_COUNTER.with(|counter| *counter.borrow());
+        if minted_count > 0 {
+            return Err("Cannot modify max supply after minting has started".to_string());
+        }
+    }
+    
+    // Update the collection details
+    COLLECTION_DETAILS.with(|details| {
+        let mut details_ref = details.borrow_mut();
+        
+        if let Some(name) = args.name {
+            details_ref.name = name;
+        }
+        
+        if let Some(symbol) = args.symbol {
+            details_ref.symbol = symbol;
+        }
+        
+        if let Some(description) = args.description {
+            details_ref.description = description;
+        }
+        
+        if let Some(max_supply) = args.max_supply {
+            details_ref.max_supply = Some(max_supply);
+        }
+        
+        if let Some(base_url) = args.base_url {
+            details_ref.base_url = base_url;
+        }
+        
+        if let Some(token_name_template) = args.token_name_template {
+            details_ref.token_name_template = Some(token_name_template);
+        }
+        
+        if let Some(pricing_enabled) = args.pricing_enabled {
+            details_ref.pricing_enabled = pricing_enabled;
+        }
+        
+        if let Some(mint_schedules) = args.mint_schedules {
+            details_ref.mint_schedules = mint_schedules;
+        }
+        
+        if let Some(payment_ledger) = args.payment_ledger {
+            details_ref.payment_ledger = Some(payment_ledger);
+        }
+        
+        if let Some(treasury_subaccount) = args.treasury_subaccount {
+            details_ref.treasury_subaccount = Some(treasury_subaccount);
+        }
+        
+        if let Some(admin_burn_enabled) = args.admin_burn_enabled {
+            details_ref.admin_burn_enabled = Some(admin_burn_enabled);
+        }
+        
+        if let Some(max_query_batch_size) = args.max_query_batch_size {
+            details_ref.max_query_batch_size = Some(max_query_batch_size);
+        }
+        
+        if let Some(max_update_batch_size) = args.max_update_batch_size {
+            details_ref.max_update_batch_size = Some(max_update_batch_size);
+        }
+    });
+    
+    Ok(())
+}
+
+// Arguments for updating a specific mint schedule
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UpdateMintScheduleArgs {
+    pub name: String,                 // Name of the schedule to update (must be unique)
+    pub bundle_prices: Vec<BundlePrice>,        // Bundle prices directly associated with this schedule
+    pub start_time: Option<u64>,      // Start time in nanoseconds since epoch
+    pub end_time: Option<u64>,        // End time in nanoseconds since epoch
+    pub active: Option<bool>,         // Whether this schedule is active
+    pub whitelist_only: Option<bool>, // Whether this schedule is only for whitelisted users
+    pub payment_ledger: Option<Principal>, // Payment token ledger for this schedule
+    pub payment_symbol: Option<String>,
+    pub payment_decimals: Option<u8>,
+}
+
+// Update a mint schedule or add a new one
+#[update]
+fn update_mint_schedule(args: UpdateMintScheduleArgs) -> Result<(), String> {
+    let caller = caller();
+    
+    if !is_admin(caller) {
+        return Err("Unauthorized: Only admins can update mint schedules".to_string());
+    }
+    
+    if args.name.is_empty() {
+        return Err("Schedule name cannot be empty".to_string());
+    }
+    
+    // Validate time range if both are provided
+    if let (Some(start), Some(end)) = (args.start_time, args.end_time) {
+        if end <= start {
+            return Err("End time must be after start time".to_string());
+        }
+    }
+    
+    COLLECTION_DETAILS.with(|details| {
+        let mut details_ref = details.borrow_mut();
+        
+        // Try to find an existing schedule with this name
+        let existing_schedule = details_ref.mint_schedules.iter_mut().find(|s| s.name == args.name);
+        
+        if let Some(schedule) = existing_schedule {
+            // Update existing schedule
+            schedule.bundle_prices = args.bundle_prices;
+            
+            if let Some(start) = args.start_time {
+                schedule.start_time = Some(start);
+            }
+            
+            if let Some(end) = args.end_time {
+                schedule.end_time = Some(end);
+            }
+            
+            if let Some(active) = args.active {
+                schedule.active = active;
+            }
+            
+            if let Some(whitelist_only) = args.whitelist_only {
+                schedule.whitelist_only = whitelist_only;
+            }
+            
+            if let Some(payment_ledger) = args.payment_ledger {
+                schedule.payment_ledger = Some(payment_ledger);
+            }
+            
+            if let Some(payment_symbol) = args.payment_symbol {
+                schedule.payment_symbol = Some(payment_symbol);
+            }
+            
+            if let Some(payment_decimals) = args.payment_decimals {
+                schedule.payment_decimals = Some(payment_decimals);
+            }
+        } else {
+            // Add new schedule
+            details_ref.mint_schedules.push(MintSchedule {
+                name: args.name,
+                bundle_prices: args.bundle_prices,
+                start_time: args.start_time,
+                end_time: args.end_time,
+                active: args.active.unwrap_or(false),
+                whitelist_only: args.whitelist_only.unwrap_or(false),
+                payment_ledger: args.payment_ledger,
+                payment_symbol: args.payment_symbol,
+                payment_decimals: args.payment_decimals,
+            });
+        }
+    });
+    
+    Ok(())
+}
+
+// Remove a mint schedule
+#[update]
+fn remove_mint_schedule(name: String) -> Result<(), String> {
+    let caller = caller();
+    
+    if !is_admin(caller) {
+        return Err("Unauthorized: Only admins can remove mint schedules".to_string());
+    }
+    
+    if name.is_empty() {
+        return Err("Schedule name cannot be empty".to_string());
+    }
+    
+    COLLECTION_DETAILS.with(|details| {
+        let mut details_ref = details.borrow_mut();
+        let initial_len = details_ref.mint_schedules.len();
+        
+        details_ref.mint_schedules.retain(|s| s.name != name);
+        
+        if details_ref.mint_schedules.len() == initial_len {
+            return Err(format!("No schedule with name '{}' found", name));
+        }
+        
+        Ok(())
+    });
+    
+    Ok(())
+}
+
+// Pick the assets for a mint: explicit asset IDs must exist and be unminted, otherwise
+// the next unminted assets are assigned in key order
+fn resolve_mint_assets(asset_ids: Vec<String>, quantity: u64) -> Result<Vec<String>, String> {
+    if asset_ids.is_empty() {
+        // Asset keys come back sorted from the stable map
+        let available: Vec<String> = asset_keys().into_iter()
+            .filter(|key| !is_asset_minted(key) && !is_asset_reserved(key))
+            .take(quantity as usize)
+            .collect();
+        
+        if (available.len() as u64) < quantity {
+            return Err(format!("Only {} unminted assets available", available.len()));
+        }
+        return Ok(available);
+    }
+    
+    if asset_ids.len() as u64 != quantity {
+        return Err(format!("Expected {} asset IDs, got {}", quantity, asset_ids.len()));
+    }
+    
+    for (index, asset_id) in asset_ids.iter().enumerate() {
+        if asset_ids[..index].contains(asset_id) {
+            return Err(format!("Asset '{}' listed more than once", asset_id));
+        }
+        if !asset_exists(asset_id) {
+            return Err(format!("Asset with key '{}' not found", asset_id));
+        }
+        if is_asset_minted(asset_id) {
+            return Err(format!("Asset '{}' has already been minted", asset_id));
+        }
+        if is_asset_reserved(asset_id) {
+            return Err(format!("Asset '{}' is being minted by another request", asset_id));
+        }
+    }
+    
+    Ok(asset_ids)
+}
+
+fn is_asset_reserved(key: &str) -> bool {
+    MINT_RESERVATIONS.with(|reserved| reserved.borrow().contains(key))
+}
+
+fn reserved_mint_count() -> u64 {
+    MINT_RESERVATIONS.with(|reserved| reserved.borrow().len() as u64)
+}
+
+fn reserve_mint_assets(asset_ids: &[String]) {
+    MINT_RESERVATIONS.with(|reserved| {
+        reserved.borrow_mut().extend(asset_ids.iter().cloned());
+    });
+}
+
+fn release_mint_assets(asset_ids: &[String]) {
+    MINT_RESERVATIONS.with(|reserved| {
+        let mut reserved = reserved.borrow_mut();
+        for asset_id in asset_ids {
+            reserved.remove(asset_id);
+        }
+    });
+}
+
+// Mint every reserved asset, or none of them if any is no longer mintable. Every
+// token is built and size-checked before the first one is stored, so a failure
+// leaves nothing behind and the payment can be refunded in full.
+fn mint_reserved_assets(owner: Principal, asset_ids: &[String]) -> Result<Vec<u64>, String> {
+    let first_id = TOKEN_ID_COUNTER.with(|counter| *counter.borrow()) + 1;
+    let nfts = asset_ids.iter()
+        .zip(first_id..)
+        .map(|(asset_id, token_id)| build_nft(owner, asset_id, token_id))
+        .collect::<Result<Vec<NFT>, String>>()?;
+    
+    TOKEN_ID_COUNTER.with(|counter| *counter.borrow_mut() += nfts.len() as u64);
+    Ok(nfts.into_iter()
+        .zip(asset_ids)
+        .map(|(nft, asset_id)| store_minted_nft(nft, asset_id))
+        .collect())
+}
+
+fn is_asset_minted(key: &str) -> bool {
+    MINTED_ASSETS.with(|minted| minted.borrow().contains_key(&AssetKey::new(key)))
+}
+
+// Longest token name the template may render, for the largest possible id
+const MAX_TOKEN_NAME_LENGTH: usize = 256;
+const DEFAULT_TOKEN_NAME_TEMPLATE: &str = "{collection} #{id}";
+
+fn render_token_name(template: Option<&str>, collection: &str, token_id: u64) -> String {
+    template.unwrap_or(DEFAULT_TOKEN_NAME_TEMPLATE)
+        .replace("{collection}", collection)
+        .replace("{id}", &token_id.to_string())
+}
+
+// Reject a template / collection name pair whose names would not fit a stored token
+fn check_token_name_template(template: Option<&str>, collection: &str) -> Result<(), String> {
+    if template.is_some_and(|template| template.len() > MAX_TOKEN_NAME_LENGTH) {
+        return Err(format!("Token name template must be at most {} bytes", MAX_TOKEN_NAME_LENGTH));
+    }
+    if render_token_name(template, collection, u64::MAX).len() > MAX_TOKEN_NAME_LENGTH {
+        return Err(format!("Rendered token names must be at most {} bytes", MAX_TOKEN_NAME_LENGTH));
+    }
+    Ok(())
+}
+
+// Build token metadata from the linked asset and the collection settings
+fn build_nft_metadata(token_id: u64, asset: &Asset) -> NFTMetadata {
+    let (name, description, base_url) = COLLECTION_DETAILS.with(|details| {
+        let details = details.borrow();
+        let name = render_token_name(details.token_name_template.as_deref(), &details.name, token_id);
+        let description = asset.description.clone()
+            .unwrap_or_else(|| details.description.clone());
+        (name, description, details.base_url.trim_end_matches('/').to_string())
+    });
+    
+    let image_url = format!("{}/{}", base_url, asset.key);
+    let is_svg = asset.content_type == "image/svg+xml";
+    let layers = if is_svg { svg_layer_keys(&asset.data) } else { Vec::new() };
+    
+    NFTMetadata {
+        name,
+        description,
+        image_url: image_url.clone(),
+        content_url: Some(image_url),
+        content_type: Some(asset.content_type.clone()),
+        properties: None,
+        is_layered: !layers.is_empty(),
+        svg_id: if is_svg { Some(token_id) } else { None },
+        layers: if layers.is_empty() { None } else { Some(layers) },
+    }
+}
+
+// Asset keys referenced by an SVG through `/asset/<key>` hrefs (see manage_assets.js)
+fn svg_layer_keys(svg: &[u8]) -> Vec<String> {
+    let text = match std::str::from_utf8(svg) {
+        Ok(text) => text,
+        Err(_) => return Vec::new(),
+    };
+    
+    let mut layers = Vec::new();
+    for part in text.split("href=\"").skip(1) {
+        let href = part.split('"').next().unwrap_or_default();
+        let href = href.split('?').next().unwrap_or_default();
+        if let Some(key) = href.strip_prefix("/asset/") {
+            if !key.is_empty() && !layers.iter().any(|k: &String| k == key) {
+                layers.push(key.to_string());
+            }
+        }
+    }
+    layers
+}
+
+// Build the token for an unminted asset without storing anything
+fn build_nft(owner: Principal, asset_id: &str, token_id: u64) -> Result<NFT, String> {
+    let asset = load_asset(asset_id)
+        .ok_or_else(|| format!("Asset with key '{}' not found", asset_id))?;
+    
+    if is_asset_minted(asset_id) {
+        return Err(format!("Asset '{}' has already been minted", asset_id));
+    }
+    
+    let metadata = build_nft_metadata(token_id, &asset);
+    let nft = NFT {
+        token_id,
+        owner,
+        owner_subaccount: None,
+        metadata,
+        created_at: time(),
+        transfer_history: Vec::new(),
+    };
+    
+    let nft_size = candid::encode_one(&nft).map_err(|e| e.to_string())?.len();
+    if nft_size > <NFT as BoundedStorable>::MAX_SIZE as usize {
+        return Err(format!("Token metadata for asset '{}' is too large to store", asset_id));
+    }
+    Ok(nft)
+}
+
+// Store a built NFT, keeping NFTS, OWNER_TOKENS, TOKEN_ASSETS and MINTED_ASSETS in sync
+fn store_minted_nft(nft: NFT, asset_id: &str) -> u64 {
+    let token_id = nft.token_id;
+    let owner = nft.owner;
+    let metadata = &nft.metadata;
+    let meta = Value::Map(vec![
+        ("icrc7:name".to_string(), Value::Text(metadata.name.clone())),
+        ("icrc7:image".to_string(), Value::Text(metadata.image_url.clone())),
+        ("asset".to_string(), Value::Text(asset_id.to_string())),
+    ]);
+    
+    // The collection freeze only listed the layers of the tokens that existed then
+    if is_collection_metadata_frozen() {
+        freeze_token_layers(&nft);
+    }
+    
+    NFTS.with(|nfts| {
+        nfts.borrow_mut().insert(token_id, nft);
+    });
+    
+    // Add token to owner's collection
+    OWNER_TOKENS.with(|owner_tokens| {
+        owner_tokens.borrow_mut().insert((Account { owner, subaccount: None }, token_id), ());
+    });
+    
+    TOKEN_ASSETS.with(|assets| {
+        assets.borrow_mut().insert(token_id, AssetKey::new(asset_id));
+    });
+    
+    MINTED_ASSETS.with(|minted| {
+        minted.borrow_mut().insert(AssetKey::new(asset_id), ());
+    });
+    
+    record_block(BTYPE_MINT, vec![
+        ("tid".to_string(), nat_value(token_id)),
+        ("from".to_string(), account_value(&Account { owner: canister_id(), subaccount: None })),
+        ("to".to_string(), account_value(&Account { owner, subaccount: None })),
+        ("meta".to_string(), meta),
+    ]);
+    
+    token_id
+}
+
+// Restrict the active schedules to the one the buyer asked for
+fn select_schedule<'a>(active_schedules: Vec<&'a MintSchedule>, name: &Option<String>) -> Result<Vec<&'a MintSchedule>, String> {
+    match name {
+        None => Ok(active_schedules),
+        Some(name) => {
+            let selected: Vec<&MintSchedule> = active_schedules.into_iter()
+                .filter(|s| &s.name == name)
+                .collect();
+            if selected.is_empty() {
+                return Err(format!("Schedule '{}' is not active for this user", name));
+            }
+            Ok(selected)
+        }
+    }
+}
+
+// Payment token of a schedule, falling back to the collection's ledger
+fn schedule_currency(schedule: &MintSchedule) -> PaymentCurrency {
+    PaymentCurrency {
+        ledger: schedule.payment_ledger.unwrap_or_else(payment_ledger),
+        symbol: schedule.payment_symbol.clone(),
+        decimals: schedule.payment_decimals.unwrap_or(DEFAULT_PAYMENT_DECIMALS),
+    }
+}
+
+// Get the active mint price for a given quantity from the active schedules.
+// Prices are only compared between schedules paid in the same token.
+fn get_active_mint_price(quantity: u64, active_schedules: &[&MintSchedule]) -> Result<Option<(Nat, PaymentCurrency)>, String> {
+    if active_schedules.is_empty() {
+        return Err("No active minting schedules available".to_string());
+    }
+    
+    // Choose the best price (lowest) from all active schedules
+    let mut best_price: Option<(Nat, PaymentCurrency)> = None;
+    
+    for schedule in active_schedules {
+        // Find the closest bundle size that is <= requested quantity
+        let closest_bundle = schedule.bundle_prices.iter()
+            .filter(|b| b.quantity > 0 && b.quantity <= quantity)
+            .max_by_key(|b| b.quantity);
+        
+        if let Some(bundle) = closest_bundle {
+            let bundle_count = quantity.div_ceil(bundle.quantity);
+            let total_price = bundle.price.clone() * Nat::from(bundle_count);
+            let currency = schedule_currency(schedule);
+            
+            // Update best price if this is better
+            if let Some((ref current_best, ref best_currency)) = best_price {
+                if best_currency.ledger != currency.ledger {
+                    return Err("Active schedules are priced in different tokens; choose one with the schedule argument".to_string());
+                }
+                if total_price < *current_best {
+                    best_price = Some((total_price, currency));
+                }
+            } else {
+                best_price = Some((total_price, currency));
+            }
+        }
+    }
+    
+    Ok(best_price)
+}
+
+// Get active mint price based on user status and available schedules
+fn get_user_mint_price(user: Principal, quantity: u64) -> Result<Nat, String> {
+    let current_time = time();
+    
+    COLLECTION_DETAILS.with(|details| {
+        let details = details.borrow();
+        
+        if !details.pricing_enabled {
+            return Err("Minting is not enabled".to_string());
+        }
+        
+        // Check if user is in whitelist
+        let user_in_whitelist = WHITELIST.with(|whitelist| {
+            whitelist.borrow().get(&user).copied().unwrap_or(false)
+        });
+        
+        // Find active schedules that match the user's status
+        let active_schedules: Vec<&MintSchedule> = details.mint_schedules.iter()
+            .filter(|s| s.active)
+            .filter(|s| {
+                // Check if time constraints are met
+                let time_valid = match (s.start_time, s.end_time) {
+                    (Some(start), Some(end)) => current_time >= start && current_time <= end,
+                    (Some(start), None) => current_time >= start,
+                    (None, Some(end)) => current_time <= end,
+                    (None, None) => true,
+                };
+                
+                // Check if user status matches the schedule
+                let status_matches = if s.whitelist_only {
+                    user_in_whitelist
+                } else {
+                    true // Non-whitelist schedules apply to everyone
+                };
+                
+                time_valid && status_matches
+            })
+            .collect();
+        
+        if active_schedules.is_empty() {
+            return Err("No active minting schedules available for this user".to_string());
+        }
+        
+        // Get the price for this quantity from active schedules
+        get_active_mint_price(quantity, &active_schedules)?
+            .map(|(price, _)| price)
+            .ok_or_else(|| format!("No price available for quantity {}", quantity))
+    })
+}
+
+// ==== PAYMENT FUNCTIONS ====
+
+// Mainnet ICP ledger, used when no payment ledger is configured
+const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+const DEFAULT_PAYMENT_DECIMALS: u8 = 8;
+
+// Pre-stable-structures layout of the redeemed payment set, only read during migration
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+struct PaymentState {
+    used_blocks: BTreeSet<(Principal, u64)>, // (ledger, block) pairs already redeemed for a mint
+}
+
+// Principals are at most 29 bytes, so the ledger id fits a fixed-size blob key
+fn payment_block_key(ledger: Principal, index: u64) -> (Blob<29>, u64) {
+    let ledger = Blob::<29>::try_from(ledger.as_slice()).expect("principal longer than 29 bytes");
+    (ledger, index)
+}
+
+// A payment that has been taken and may need to be refunded
+#[derive(Clone, Debug)]
+struct PaymentReceipt {
+    ledger: Principal,
+    payer: Account,
+    amount: Nat,
+    block_index: Nat,
+}
+
+// ICRC-1 / ICRC-2 ledger interface (only the fields we use)
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct LedgerTransferArgs {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct LedgerTransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum LedgerTransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum LedgerTransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct LedgerGetTransactionsRequest {
+    start: Nat,
+    length: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct LedgerGetTransactionsResponse {
+    first_index: Nat,
+    log_length: Nat,
+    transactions: Vec<LedgerTransaction>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct LedgerTransaction {
+    kind: String,
+    transfer: Option<LedgerTransfer>,
+    timestamp: u64,
+}
+
+// ICRC-3 get_blocks, archived block ranges are not followed
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct LedgerGetBlocksArgs {
+    start: Nat,
+    length: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct LedgerGetBlocksResult {
+    log_length: Nat,
+    blocks: Vec<LedgerBlockWithId>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct LedgerBlockWithId {
+    id: Nat,
+    block: Value,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct LedgerTransfer {
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+fn describe_transfer_error(error: LedgerTransferError) -> String {
+    match error {
+        LedgerTransferError::BadFee { expected_fee } => format!("BadFee: expected fee {}", expected_fee),
+        LedgerTransferError::BadBurn { min_burn_amount } => format!("BadBurn: minimum burn amount {}", min_burn_amount),
+        LedgerTransferError::InsufficientFunds { balance } => format!("InsufficientFunds: balance {}", balance),
+        LedgerTransferError::TooOld => "TooOld".to_string(),
+        LedgerTransferError::CreatedInFuture { ledger_time } => format!("CreatedInFuture: ledger time {}", ledger_time),
+        LedgerTransferError::Duplicate { duplicate_of } => format!("Duplicate: duplicate of block {}", duplicate_of),
+        LedgerTransferError::TemporarilyUnavailable => "TemporarilyUnavailable".to_string(),
+        LedgerTransferError::GenericError { error_code, message } => format!("GenericError {}: {}", error_code, message),
+    }
+}
+
+fn describe_transfer_from_error(error: LedgerTransferFromError) -> String {
+    match error {
+        LedgerTransferFromError::BadFee { expected_fee } => format!("BadFee: expected fee {}", expected_fee),
+        LedgerTransferFromError::BadBurn { min_burn_amount } => format!("BadBurn: minimum burn amount {}", min_burn_amount),
+        LedgerTransferFromError::InsufficientFunds { balance } => format!("InsufficientFunds: balance {}", balance),
+        LedgerTransferFromError::InsufficientAllowance { allowance } => format!("InsufficientAllowance: allowance {}", allowance),
+        LedgerTransferFromError::TooOld => "TooOld".to_string(),
+        LedgerTransferFromError::CreatedInFuture { ledger_time } => format!("CreatedInFuture: ledger time {}", ledger_time),
+        LedgerTransferFromError::Duplicate { duplicate_of } => format!("Duplicate: duplicate of block {}", duplicate_of),
+        LedgerTransferFromError::TemporarilyUnavailable => "TemporarilyUnavailable".to_string(),
+        LedgerTransferFromError::GenericError { error_code, message } => format!("GenericError {}: {}", error_code, message),
+    }
+}
+
+fn payment_ledger() -> Principal {
+    COLLECTION_DETAILS.with(|details| details.borrow().payment_ledger)
+        .unwrap_or_else(|| Principal::from_text(ICP_LEDGER_CANISTER_ID).unwrap())
+}
+
+// Account of this canister that receives mint payments
+fn treasury_account() -> Account {
+    let subaccount = COLLECTION_DETAILS.with(|details| details.borrow().treasury_subaccount.clone());
+    Account {
+        owner: canister_id(),
+        subaccount: normalize_subaccount(&subaccount).unwrap_or(None),
+    }
+}
+
+// Take exactly `price` from the buyer; a zero price needs no payment
+async fn collect_payment(payer: Principal, currency: &PaymentCurrency, price: Nat, method: Option<PaymentMethod>) -> Result<Option<PaymentReceipt>, String> {
+    if price == 0u8 {
+        return Ok(None);
+    }
+    
+    let ledger = currency.ledger;
+    let method = method.unwrap_or(PaymentMethod::Icrc2Approval { from_subaccount: None });
+    
+    match method {
+        PaymentMethod::Icrc2Approval { from_subaccount } => {
+            let from = Account { owner: payer, subaccount: from_subaccount }.normalized()?;
+            let args = LedgerTransferFromArgs {
+                spender_subaccount: None,
+                from: from.clone(),
+                to: treasury_account(),
+                amount: price.clone(),
+                fee: None,
+                memo: None,
+                created_at_time: Some(time()),
+            };
+            
+            let (result,): (Result<Nat, LedgerTransferFromError>,) = ic_cdk::call(ledger, "icrc2_transfer_from", (args,))
+                .await
+                .map_err(|(code, msg)| format!("Payment ledger call failed: {:?} {}", code, msg))?;
+            
+            let block_index = result.map_err(|e| format!("Payment failed: {}", describe_transfer_from_error(e)))?;
+            
+            Ok(Some(PaymentReceipt { ledger, payer: from, amount: price, block_index }))
+        }
+        PaymentMethod::Icrc1Transfer { block_index } => {
+            let index: u64 = block_index.0.clone().try_into()
+                .map_err(|_| "Invalid block index".to_string())?;
+            
+            // Claim the block up front so concurrent mints cannot redeem it twice
+            let claimed = USED_PAYMENT_BLOCKS.with(|used| {
+                used.borrow_mut().insert(payment_block_key(ledger, index), ()).is_none()
+            });
+            if !claimed {
+                return Err(format!("Block {} has already been used for a mint", index));
+            }
+            
+            match verify_transfer_block(ledger, index, payer, &price).await {
+                Ok(from) => Ok(Some(PaymentReceipt { ledger, payer: from, amount: price, block_index })),
+                Err(e) => {
+                    USED_PAYMENT_BLOCKS.with(|used| used.borrow_mut().remove(&payment_block_key(ledger, index)));
+                    Err(e)
+                }
+            }
+        }
+    }
+}
+
+// Check that a ledger block is a transfer of exactly `price` from the payer to the treasury
+async fn verify_transfer_block(ledger: Principal, index: u64, payer: Principal, price: &Nat) -> Result<Account, String> {
+    let transfer = fetch_ledger_transfer(ledger, index).await?;
+    
+    if transfer.from.owner != payer {
+        return Err(format!("Block {} was not sent by the caller", index));
+    }
+    
+    let to = transfer.to.normalized()?;
+    if to != treasury_account() {
+        return Err(format!("Block {} was not sent to the collection treasury", index));
+    }
+    
+    if transfer.amount != *price {
+        return Err(format!("Block {} transferred {} but the price is {}", index, transfer.amount, price));
+    }
+    
+    transfer.from.normalized()
+}
+
+// Read a transfer block through ICRC-3, falling back to the ICRC-1 index-style
+// get_transactions for ledgers that predate ICRC-3
+async fn fetch_ledger_transfer(ledger: Principal, index: u64) -> Result<LedgerTransfer, String> {
+    let unavailable = || format!("Block {} is not available on the ledger (it may be archived); pay with an ICRC-2 approval instead", index);
+    let not_transfer = || format!("Block {} is not a transfer", index);
+    
+    let args = vec![LedgerGetBlocksArgs { start: Nat::from(index), length: Nat::from(1u8) }];
+    let icrc3: Result<(LedgerGetBlocksResult,), _> = ic_cdk::call(ledger, "icrc3_get_blocks", (args,)).await;
+    if let Ok((result,)) = icrc3 {
+        let block = result.blocks.into_iter()
+            .find(|block| block.id == index)
+            .ok_or_else(unavailable)?;
+        return icrc3_transfer(&block.block).ok_or_else(not_transfer);
+    }
+    
+    let request = LedgerGetTransactionsRequest {
+        start: Nat::from(index),
+        length: Nat::from(1u8),
     };
+    let (response,): (LedgerGetTransactionsResponse,) = ic_cdk::call(ledger, "get_transactions", (request,))
+        .await
+        .map_err(|(code, msg)| format!(
+            "Payment ledger supports neither icrc3_get_blocks nor get_transactions ({:?} {}); pay with an ICRC-2 approval instead",
+            code, msg
+        ))?;
+    
+    if response.first_index != index || response.transactions.is_empty() {
+        return Err(unavailable());
+    }
+    
+    response.transactions.into_iter().next()
+        .and_then(|tx| tx.transfer)
+        .ok_or_else(not_transfer)
+}
+
+// Transfer fields of an ICRC-3 ICRC-1/ICRC-2 block ("1xfer", "2xfer" or a legacy op "xfer")
+fn icrc3_transfer(block: &Value) -> Option<LedgerTransfer> {
+    let tx = value_field(block, "tx")?;
+    let is_transfer = match (value_field(block, "btype"), value_field(tx, "op")) {
+        (Some(Value::Text(btype)), _) => btype == "1xfer" || btype == "2xfer",
+        (None, Some(Value::Text(op))) => op == "xfer",
+        _ => false,
+    };
+    if !is_transfer {
+        return None;
+    }
+    
+    let amount = match value_field(tx, "amt") {
+        Some(Value::Nat(amount)) => amount.clone(),
+        _ => return None,
+    };
+    Some(LedgerTransfer {
+        from: value_account(value_field(tx, "from"))?,
+        to: value_account(value_field(tx, "to"))?,
+        amount,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    })
+}
+
+// Return a payment after a failed mint; the ledger fee is deducted from the refund.
+// Returns the error message to report to the caller.
+async fn refund_payment(receipt: Option<PaymentReceipt>, error: String) -> String {
+    let receipt = match receipt {
+        Some(receipt) => receipt,
+        None => return error,
+    };
+    
+    match refund(&receipt).await {
+        Ok(block_index) => format!("{} (payment refunded in block {})", error, block_index),
+        Err(refund_error) => {
+            ic_cdk::println!("Refund of payment block {} to {} failed: {}",
+                             receipt.block_index, receipt.payer.owner, refund_error);
+            format!("{} (refund of payment block {} failed: {})", error, receipt.block_index, refund_error)
+        }
+    }
+}
+
+async fn refund(receipt: &PaymentReceipt) -> Result<Nat, String> {
+    let (fee,): (Nat,) = ic_cdk::call(receipt.ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, msg)| format!("Payment ledger call failed: {:?} {}", code, msg))?;
+    
+    if receipt.amount <= fee {
+        return Err("Payment does not cover the ledger fee".to_string());
+    }
+    
+    ledger_transfer(receipt.ledger, receipt.payer.clone(), receipt.amount.clone() - fee).await
+}
+
+async fn ledger_transfer(ledger: Principal, to: Account, amount: Nat) -> Result<Nat, String> {
+    let args = LedgerTransferArgs {
+        from_subaccount: treasury_account().subaccount,
+        to,
+        amount,
+        fee: None,
+        memo: None,
+        created_at_time: Some(time()),
+    };
+    
+    let (result,): (Result<Nat, LedgerTransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (args,))
+        .await
+        .map_err(|(code, msg)| format!("Payment ledger call failed: {:?} {}", code, msg))?;
+    
+    result.map_err(describe_transfer_error)
+}
+
+// Withdraw collected mint payments held on a ledger - system admin only
+#[update]
+async fn withdraw_payments(ledger: Principal, to: Account, amount: Nat) -> Result<Nat, String> {
+    if !is_system_admin(caller()) {
+        return Err("Unauthorized: Only system admins can withdraw payments".to_string());
+    }
+    
+    let to = to.normalized()?;
+    ledger_transfer(ledger, to, amount).await
+}
+
+// ==== ASSET MANAGEMENT FUNCTIONS ====
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct Asset {
+    key: String,
+    content_type: String,
+    data: Vec<u8>,
+    description: Option<String>,
+    uploaded_by: Principal,
+    created_at: u64,
+    modified_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct AssetMetadata {
+    key: String,
+    content_type: String,
+    size: usize,
+    created_at: u64,
+    modified_at: u64,
+    description: Option<String>,
+    uploaded_by: Principal,
+    sha256: Option<Vec<u8>>, // Absent for assets stored before content hashing
+    version: Option<u64>,    // Bumped on every write to the key, absent means 1
+    encodings: Option<Vec<AssetEncoding>>, // Precompressed variants of the content
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct UploadArgs {
+    key: Option<String>,          // Optional, will be generated if not provided
+    content_type: String,         // MIME type (e.g., "image/png")
+    data: Vec<u8>,               // Binary content
+    description: Option<String>,  // Optional description stored in metadata
+    expected_version: Option<u64>, // Only replace the asset at this version (0 = must not exist)
+    encoding: Option<TransferEncoding>, // How `data` is encoded in transit (None = Raw)
+    content_encodings: Option<Vec<EncodedContent>>, // Precompressed variants of the decoded data, stored unchecked
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Serialize)]
+enum TransferEncoding {
+    Raw,
+    Base64,
+    Hex,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct DownloadResult {
+    data: Vec<u8>,
+    content_type: String,
+    metadata: AssetMetadata,
+}
+
+// System functions for stable storage
+//
+// Large collections live in stable structures and survive upgrades on their own;
+// only the small heap state is written out here.
+#[pre_upgrade]
+fn pre_upgrade() {
+    StableCell::new(memory(HEAP_STATE_MEMORY_ID), snapshot_heap_state())
+        .expect("failed to save heap state");
+    write_state_header();
+    
+    ic_cdk::println!("Pre-upgrade: Saved heap state to stable memory");
+}
+
+// Version 5: full stable_save snapshot written before the move to stable structures
+type FullState = (
+    u64, // TOKEN_ID_COUNTER
+    HashMap<u64, NFT>, // NFTS
+    HashMap<Account, Vec<u64>>, // OWNER_TOKENS
+    HashMap<Principal, bool>, // WHITELIST
+    HashMap<Principal, AdminType>, // ADMINS
+    CollectionDetails, // COLLECTION_DETAILS with new fields
+    HashMap<String, Asset>, // ASSETS
+    HashMap<String, bool>, // MINTED_ASSETS
+    HashMap<u64, HashMap<Account, ApprovalInfo>>, // TOKEN_APPROVALS
+    HashMap<Account, HashMap<Account, ApprovalInfo>>, // COLLECTION_APPROVALS
+    Vec<Transaction>, // Legacy transaction log, migrated into BLOCKS
+    u64, // Legacy transaction counter
+    Vec<ArchiveInfo>, // ARCHIVES
+    Option<PaymentState>, // Redeemed payment blocks (absent in older layouts)
+    Option<BlockLog>, // ICRC-3 block log (absent in older layouts)
+    Option<ArchiveState>, // ARCHIVE_STATE (absent in older layouts)
+);
+
+// Version 4: full snapshot where ownership and approvals were keyed by principal only
+type PrincipalKeyedState = (
+    u64,
+    HashMap<u64, NFT>,
+    HashMap<Principal, Vec<u64>>,
+    HashMap<Principal, bool>,
+    HashMap<Principal, AdminType>,
+    CollectionDetails,
+    HashMap<String, Asset>,
+    HashMap<String, bool>,
+    HashMap<u64, HashMap<Principal, ApprovalInfo>>,
+    HashMap<Principal, HashMap<Principal, ApprovalInfo>>,
+    Vec<Transaction>,
+    u64,
+    Vec<ArchiveInfo>,
+);
+
+// Principal-keyed entries belong to the principal's default subaccount
+fn default_account(owner: Principal) -> Account {
+    Account { owner, subaccount: None }
+}
+
+fn upgrade_principal_keyed_state(state: PrincipalKeyedState) -> FullState {
+    let (token_id_counter, nfts, owner_tokens, whitelist, admins, collection_details,
+         assets, minted_assets, token_approvals, collection_approvals,
+         transactions, tx_counter, archives) = state;
+    
+    let owner_tokens = owner_tokens.into_iter()
+        .map(|(owner, tokens)| (default_account(owner), tokens))
+        .collect();
+    
+    let token_approvals = token_approvals.into_iter()
+        .map(|(token_id, spenders)| {
+            let spenders = spenders.into_iter()
+                .map(|(spender, info)| (default_account(spender), info))
+                .collect();
+            (token_id, spenders)
+        })
+        .collect();
+    
+    let collection_approvals = collection_approvals.into_iter()
+        .map(|(owner, spenders)| {
+            let spenders = spenders.into_iter()
+                .map(|(spender, info)| (default_account(spender), info))
+                .collect();
+            (default_account(owner), spenders)
+        })
+        .collect();
+    
+    (token_id_counter, nfts, owner_tokens, whitelist, admins, collection_details,
+     assets, minted_assets, token_approvals, collection_approvals,
+     transactions, tx_counter, archives, None, None, None)
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    if !stable_memory_is_managed() {
+        // stable_save snapshots carry no header. They are decoded in full before the
+        // first stable map is touched, since the memory manager overwrites the start
+        // of stable memory.
+        if ic_cdk::api::stable::stable64_size() > 0 {
+            let legacy_state = decode_legacy_state();
+            ic_cdk::println!("Post-upgrade: Migrating state from layout version {}", legacy_state.version());
+            migrate_legacy_state(legacy_state);
+            migrate_v6_to_v7();
+        } else {
+            ic_cdk::println!("Post-upgrade: No data restored during upgrade. Initializing with empty state.");
+        }
+        certify_all_assets();
+        write_state_header();
+        return;
+    }
     
-    // Add to collection approvals
-    COLLECTION_APPROVALS.with(|approvals| {
-        approvals.borrow_mut()
-            .entry(caller_principal)
-            .or_insert_with(HashMap::new)
-            .insert(spender_principal, approval_info);
-    });
+    let version = stored_state_version();
+    if !(STATE_VERSION_STABLE_STRUCTURES..=STATE_VERSION).contains(&version) {
+        ic_cdk::trap(&format!(
+            "Stable memory holds state version {}, this build only understands versions up to {}; refusing to upgrade",
+            version, STATE_VERSION
+        ));
+    }
     
-    // Record the collection approval in the transaction log - using 0 as token_id for collection approval
-    let _transaction_id = record_transaction("approve", 0, caller_principal, spender_principal, 
-                                           args.memo, "collection_approval".to_string());
+    let cell = StableCell::init(memory(HEAP_STATE_MEMORY_ID), snapshot_heap_state())
+        .expect("failed to load heap state");
+    restore_heap_state(cell.get().clone());
+    if version == STATE_VERSION_STABLE_STRUCTURES {
+        migrate_v6_to_v7();
+    }
+    certify_all_assets();
+    update_certified_data();
+    write_state_header();
     
-    Ok(timestamp)
+    ic_cdk::println!("Post-upgrade: Restored state version {} from stable memory", version);
 }
 
+// Layout version of the state currently held in stable memory
 #[query]
-fn icrc37_is_approved(spender: Account, from: Account, token_id: u64) -> bool {
-    let spender_principal = spender.owner;
-    let from_principal = from.owner;
-    
-    // Check token-specific approval
-    let token_approved = TOKEN_APPROVALS.with(|approvals| {
-        approvals.borrow().get(&token_id)
-            .and_then(|spender_map| spender_map.get(&spender_principal))
-            .map(|approval_info| {
-                // Check if approval has expired
-                match approval_info.expires_at {
-                    Some(expires_at) => expires_at > time(),
-                    None => true, // No expiration means it's valid
-                }
-            })
-            .unwrap_or(false)
-    });
-    
-    if token_approved {
-        return true;
+fn get_state_version() -> u32 {
+    stored_state_version()
+}
+
+// Snapshots written with stable_save before the state header existed, one per layout version
+enum LegacyState {
+    AssetsOnly(HashMap<String, Asset>),
+    AssetsAdmins(HashMap<String, Asset>, HashMap<Principal, AdminType>),
+    AssetsAdminsMinted(HashMap<String, Asset>, HashMap<Principal, AdminType>, HashMap<String, bool>),
+    PrincipalKeyed(PrincipalKeyedState),
+    AccountKeyed(FullState),
+}
+
+impl LegacyState {
+    fn version(&self) -> u32 {
+        match self {
+            LegacyState::AssetsOnly(..) => STATE_VERSION_ASSETS_ONLY,
+            LegacyState::AssetsAdmins(..) => STATE_VERSION_ASSETS_ADMINS,
+            LegacyState::AssetsAdminsMinted(..) => STATE_VERSION_ASSETS_ADMINS_MINTED,
+            LegacyState::PrincipalKeyed(..) => STATE_VERSION_PRINCIPAL_KEYED,
+            LegacyState::AccountKeyed(..) => STATE_VERSION_ACCOUNT_KEYED,
+        }
+    }
+}
+
+// Identify a headerless snapshot by decoding it, newest layout first. An
+// unrecognised snapshot traps so the upgrade is rolled back instead of
+// starting over with empty state.
+fn decode_legacy_state() -> LegacyState {
+    if let Ok(state) = ic_cdk::storage::stable_restore::<FullState>() {
+        return LegacyState::AccountKeyed(state);
+    }
+    if let Ok(state) = ic_cdk::storage::stable_restore::<PrincipalKeyedState>() {
+        return LegacyState::PrincipalKeyed(state);
+    }
+    if let Ok((assets, admins, minted_assets)) = ic_cdk::storage::stable_restore::<(
+        HashMap<String, Asset>,
+        HashMap<Principal, AdminType>,
+        HashMap<String, bool>,
+    )>() {
+        return LegacyState::AssetsAdminsMinted(assets, admins, minted_assets);
+    }
+    if let Ok((assets, admins)) = ic_cdk::storage::stable_restore::<(
+        HashMap<String, Asset>,
+        HashMap<Principal, AdminType>,
+    )>() {
+        return LegacyState::AssetsAdmins(assets, admins);
+    }
+    if let Ok((assets,)) = ic_cdk::storage::stable_restore::<(HashMap<String, Asset>,)>() {
+        return LegacyState::AssetsOnly(assets);
     }
     
-    // Check collection-wide approval
-    COLLECTION_APPROVALS.with(|approvals| {
-        approvals.borrow().get(&from_principal)
-            .and_then(|spender_map| spender_map.get(&spender_principal))
-            .map(|approval_info| {
-                // Check if approval has expired
-                match approval_info.expires_at {
-                    Some(expires_at) => expires_at > time(),
-                    None => true, // No expiration means it's valid
-                }
-            })
-            .unwrap_or(false)
-    })
+    ic_cdk::trap("Stable memory holds an unrecognised state layout; refusing to upgrade");
 }
 
-#[update]
-fn icrc37_transfer_from(args: Vec<TransferFromArgs>) -> Vec<Result<u64, TransferError>> {
-    args.into_iter().map(|arg| {
-        let caller_principal = caller();
-        let token_id = arg.token_id;
-        let from_principal = arg.from.owner;
-        let to_principal = arg.to.owner;
-        
-        // Verify token exists
-        let mut nft = match NFTS.with(|nfts| nfts.borrow().get(&token_id).cloned()) {
-            Some(nft) => nft,
-            None => return Err(TransferError::NotFound),
+// Walk a legacy snapshot through each migration up to the current layout
+fn migrate_legacy_state(mut state: LegacyState) {
+    loop {
+        state = match state {
+            LegacyState::AssetsOnly(assets) => migrate_v1_to_v2(assets),
+            LegacyState::AssetsAdmins(assets, admins) => migrate_v2_to_v3(assets, admins),
+            LegacyState::AssetsAdminsMinted(assets, admins, minted_assets) => {
+                migrate_v3_to_v4(assets, admins, minted_assets)
+            }
+            LegacyState::PrincipalKeyed(state) => migrate_v4_to_v5(state),
+            LegacyState::AccountKeyed(state) => return migrate_v5_to_v6(state),
         };
-        
-        // Check that the from account owns the token
-        if nft.owner != from_principal {
-            return Err(TransferError::Unauthorized);
-        }
-        
-        // Check if caller is approved for this token or collection
-        let is_approved = icrc37_is_approved(
-            Account { owner: caller_principal, subaccount: None },
-            Account { owner: from_principal, subaccount: None },
-            token_id
-        );
-        
-        if !is_approved {
-            return Err(TransferError::Unauthorized);
+    }
+}
+
+// v1 -> v2: admins were not persisted yet
+fn migrate_v1_to_v2(assets: HashMap<String, Asset>) -> LegacyState {
+    LegacyState::AssetsAdmins(assets, HashMap::new())
+}
+
+// v2 -> v3: minted assets were not tracked yet
+fn migrate_v2_to_v3(assets: HashMap<String, Asset>, admins: HashMap<Principal, AdminType>) -> LegacyState {
+    LegacyState::AssetsAdminsMinted(assets, admins, HashMap::new())
+}
+
+// v3 -> v4: everything else starts from the canister defaults
+fn migrate_v3_to_v4(
+    assets: HashMap<String, Asset>,
+    admins: HashMap<Principal, AdminType>,
+    minted_assets: HashMap<String, bool>,
+) -> LegacyState {
+    let collection_details = COLLECTION_DETAILS.with(|c| c.borrow().clone());
+    LegacyState::PrincipalKeyed((
+        0,
+        HashMap::new(),
+        HashMap::new(),
+        HashMap::new(),
+        admins,
+        collection_details,
+        assets,
+        minted_assets,
+        HashMap::new(),
+        HashMap::new(),
+        Vec::new(),
+        0,
+        Vec::new(),
+    ))
+}
+
+// v4 -> v5: ownership and approvals become account keyed
+fn migrate_v4_to_v5(state: PrincipalKeyedState) -> LegacyState {
+    LegacyState::AccountKeyed(upgrade_principal_keyed_state(state))
+}
+
+// v6 -> v7: decode image assets that were stored as base64 or hex text and used
+// to be decoded by guesswork on every request. The v6 layout hashed these like any
+// other asset, so they are told apart by their first chunk and only loaded in full
+// when it is not in the declared format.
+fn migrate_v6_to_v7() {
+    for key in asset_keys() {
+        let Some(metadata) = asset_metadata(&key) else { continue };
+        let head = first_asset_chunk(&key);
+        match matches_signature(&metadata.content_type, &head) {
+            Some(true) => continue,
+            Some(false) => {}
+            None => {
+                if looks_transfer_encoded(&head) {
+                    ic_cdk::println!("Migration: asset '{}' ({}) looks base64 or hex encoded, left as stored", key, metadata.content_type);
+                }
+                continue;
+            }
         }
+        let Some(asset) = load_asset(&key) else { continue };
+        let Some(data) = legacy_canonical_bytes(&metadata.content_type, &asset.data) else { continue };
         
-        // Record timestamp
-        let timestamp = time();
-        
-        // Update token owner and transfer history
-        nft.owner = to_principal;
-        nft.transfer_history.push(TransferRecord {
-            from: from_principal,
-            to: to_principal,
-            timestamp,
-        });
-        
-        // Update storage
-        NFTS.with(|nfts| {
-            nfts.borrow_mut().insert(token_id, nft);
-        });
-        
-        // Update old owner's records
-        OWNER_TOKENS.with(|owner_tokens| {
-            let mut tokens = owner_tokens.borrow_mut();
-            if let Some(token_vec) = tokens.get_mut(&from_principal) {
-                token_vec.retain(|&id| id != token_id);
+        let asset_key = AssetKey::new(&key);
+        remove_asset_chunks(&asset_key);
+        ASSET_CHUNKS.with(|chunks| {
+            let mut chunks = chunks.borrow_mut();
+            for (index, chunk) in data.chunks(ASSET_CHUNK_SIZE).enumerate() {
+                chunks.insert((asset_key.clone(), index as u32), AssetChunk(chunk.to_vec()));
             }
         });
-        
-        // Update new owner's records
-        OWNER_TOKENS.with(|owner_tokens| {
-            let mut tokens = owner_tokens.borrow_mut();
-            tokens.entry(to_principal)
-                .or_insert_with(Vec::new)
-                .push(token_id);
-        });
-        
-        // Remove the token approval since it's been used
-        TOKEN_APPROVALS.with(|approvals| {
-            if let Some(spender_map) = approvals.borrow_mut().get_mut(&token_id) {
-                spender_map.remove(&caller_principal);
-            }
+        ASSETS.with(|assets| {
+            assets.borrow_mut().insert(asset_key, AssetMetadata {
+                size: data.len(),
+                sha256: Some(Sha256::digest(&data).to_vec()),
+                ..metadata
+            });
         });
-        
-        // Record the transfer in the transaction log
-        let _transaction_id = record_transaction("transfer", token_id, from_principal, to_principal, 
-                                               arg.memo, "transfer_from".to_string());
-        
-        Ok(timestamp)
-    }).collect()
+        certify_asset(&key);
+    }
 }
 
-// Helper function to record transactions in the log
-fn record_transaction(
-    kind: &str, 
-    token_id: u64, 
-    from: Principal, 
-    to: Principal, 
-    memo: Option<Vec<u8>>, 
-    operation: String
-) -> u64 {
-    let transaction_id = TRANSACTION_ID_COUNTER.with(|counter| {
-        let id = *counter.borrow();
-        *counter.borrow_mut() += 1;
-        id
-    });
+// v5 -> v6: move the snapshot into the stable structures
+fn migrate_v5_to_v6(state: FullState) {
+    let (token_id_counter, nfts, owner_tokens, whitelist, admins, collection_details, 
+         assets, minted_assets, token_approvals, collection_approvals, 
+         transactions, _tx_counter, archives, payment_state, block_log,
+         archive_state) = state;
     
-    let timestamp = time();
+    // Older layouts kept a flat transaction log; rebuild it as hashed blocks
+    let block_log = block_log.unwrap_or_else(|| migrate_legacy_transactions(transactions));
     
-    let transaction = Transaction {
-        kind: kind.to_string(),
-        timestamp,
-        token_id,
-        from,
-        to,
-        memo,
-        operation,
-        transaction_id,
-    };
+    // Save stats before moving variables
+    let nfts_count = nfts.len();
+    let owners_count = owner_tokens.len();
+    let blocks_count = block_log.blocks.len();
     
-    TRANSACTIONS.with(|txs| {
-        txs.borrow_mut().push(transaction);
+    restore_heap_state(HeapState {
+        token_id_counter,
+        burned_count: None,
+        next_upload_batch_id: None,
+        whitelist,
+        admins,
+        collection_details,
+        archives,
+        archive_state: archive_state.unwrap_or_default(),
+        log_tip: LogTip {
+            first_index: block_log.first_index,
+            last_hash: block_log.last_hash,
+        },
     });
     
-    transaction_id
-}
-
-// ==== TESTING FUNCTIONS ====
-
-// Get the caller's principal ID - useful for testing
-#[query]
-fn whoami() -> Principal {
-    caller()
-}
-
-// Add the caller as a system admin - only for testing purposes
-#[update]
-fn make_me_admin() -> Result<(), String> {
-    let caller_principal = caller();
+    // Transfer history moves out of the NFT records into its own map
+    for (token_id, mut nft) in nfts {
+        for record in std::mem::take(&mut nft.transfer_history) {
+            push_transfer_record(token_id, record);
+        }
+        NFTS.with(|n| n.borrow_mut().insert(token_id, nft));
+    }
     
-    // Check if already an admin to avoid error messages
-    if is_admin(caller_principal) {
-        return Ok(());
+    OWNER_TOKENS.with(|o| {
+        let mut o = o.borrow_mut();
+        for (account, token_ids) in owner_tokens {
+            for token_id in token_ids {
+                o.insert((account.clone(), token_id), ());
+            }
+        }
+    });
+    
+    // Repair images the old upload path kept as base64 or hex text before they
+    // are hashed and certified
+    for (_, mut asset) in assets {
+        if let Some(data) = legacy_canonical_bytes(&asset.content_type, &asset.data) {
+            asset.data = data;
+        }
+        store_asset(asset);
     }
     
-    // Add caller as a system admin
-    ADMINS.with(|admins| {
-        admins.borrow_mut().insert(caller_principal, AdminType::System);
+    MINTED_ASSETS.with(|m| {
+        let mut m = m.borrow_mut();
+        for (key, minted) in minted_assets {
+            if minted {
+                m.insert(AssetKey::new(&key), ());
+            }
+        }
     });
     
-    Ok(())
-}
-
-// ==== ADMIN AND WHITELIST FUNCTIONS ====
-
-#[update]
-fn add_admin(user: Principal, admin_type: AdminType) -> Result<(), String> {
-    let caller = caller();
+    TOKEN_APPROVALS.with(|t| {
+        let mut t = t.borrow_mut();
+        for (token_id, spenders) in token_approvals {
+            for (spender, info) in spenders {
+                t.insert((token_id, spender), info);
+            }
+        }
+    });
     
-    // Only system admins can add new admins
-    if !is_system_admin(caller) {
-        return Err("Unauthorized: Only system admins can add new admins".to_string());
-    }
+    COLLECTION_APPROVALS.with(|c| {
+        let mut c = c.borrow_mut();
+        for (owner, spenders) in collection_approvals {
+            for (spender, info) in spenders {
+                c.insert((owner.clone(), spender), info);
+            }
+        }
+    });
     
-    ADMINS.with(|admins| {
-        admins.borrow_mut().insert(user, admin_type);
+    BLOCKS.with(|b| {
+        let mut b = b.borrow_mut();
+        for (offset, block) in block_log.blocks.into_iter().enumerate() {
+            b.insert(block_log.first_index + offset as u64, block);
+        }
     });
+    update_certified_data();
     
-    // Also add to whitelist automatically
-    WHITELIST.with(|whitelist| {
-        whitelist.borrow_mut().insert(user, true);
+    USED_PAYMENT_BLOCKS.with(|u| {
+        let mut u = u.borrow_mut();
+        for (ledger, index) in payment_state.unwrap_or_default().used_blocks {
+            u.insert(payment_block_key(ledger, index), ());
+        }
     });
     
-    Ok(())
+    ic_cdk::println!("Post-upgrade: Migrated legacy state into stable structures");
+    ic_cdk::println!("Stats: {} NFTs, {} owners, {} blocks", 
+                     nfts_count, owners_count, blocks_count);
 }
 
+// Upload a file (PNG or other) - admin only
 #[update]
-fn remove_admin(user: Principal) -> Result<(), String> {
+fn upload(args: UploadArgs) -> Result<String, String> {
     let caller = caller();
     
-    // Check if caller is a system admin
-    if !is_system_admin(caller) {
-        return Err("Unauthorized: Only system admins can remove admins".to_string());
+    // Check if caller is an admin (either type)
+    if !is_admin(caller) {
+        return Err("Unauthorized: Only admins can upload assets".to_string());
     }
     
-    // Cannot remove yourself if you're the only system admin
-    if user == caller && count_system_admins() <= 1 {
-        return Err("Cannot remove the last system admin".to_string());
+    // Generate key if not provided (default to png for backward compatibility)
+    let key = match args.key {
+        Some(key) => key,
+        None => {
+            let extension = if args.content_type == "image/png" { "png" } 
+                         else { args.content_type.split("/").last().unwrap_or("bin") };
+            format!("asset-{}.{}", time(), extension)
+        }
+    };
+    
+    check_asset_fields(&key, &args.content_type, &args.description)?;
+    check_asset_version(&key, args.expected_version)?;
+    
+    // Frozen tokens must keep pointing at the same bytes
+    if is_asset_frozen(&key) {
+        return Err(format!("Asset '{}' is referenced by frozen token metadata and cannot be replaced", key));
     }
     
-    // Remove the admin
-    ADMINS.with(|admins| {
-        admins.borrow_mut().remove(&user);
-    });
+
+    // Store canonical bytes only; SVGs must be text
+    let data = decode_transfer_encoding(args.data, args.encoding.unwrap_or(TransferEncoding::Raw))?;
+    let content_as_string = if args.content_type == "image/svg+xml" {
+        String::from_utf8(data.clone())
+            .map_err(|_| format!("SVG content for '{}' is not valid UTF-8", key))?
+    } else {
+        format!("Uploaded binary file with key: {}", key)
+    };
     
-    Ok(())
+    let asset = Asset {
+        key: key.clone(),
+        content_type: args.content_type,
+        data,
+        description: args.description,
+        uploaded_by: caller,
+        created_at: time(),
+        modified_at: time(),
+    };
+    
+    // Store the asset, then any precompressed variants the client sent along
+    let version = store_asset(asset);
+    for variant in args.content_encodings.unwrap_or_default() {
+        store_asset_encoding(&key, variant.encoding, asset_pieces(&variant.data))?;
+    }
+    
+    // Record the upload in the block log
+    record_block(BTYPE_ASSET_UPLOAD, vec![
+        ("from".to_string(), account_value(&Account { owner: caller, subaccount: None })),
+        ("key".to_string(), Value::Text(key.clone())),
+        ("version".to_string(), nat_value(version)),
+    ]);
+    
+    // Return the content as a string
+    Ok(content_as_string)
 }
 
+// Download a file 
 #[query]
-fn get_admins() -> Vec<Admin> {
-    ADMINS.with(|admins| {
-        admins.borrow()
-            .iter()
-            .map(|(owner, admin_type)| {
-                Admin {
-                    owner: *owner,
-                    admin_type: admin_type.clone(),
-                }
-            })
-            .collect()
+fn download(key: String) -> Result<DownloadResult, String> {
+    // No need to check caller for downloads
+    let asset = load_asset(&key)
+        .ok_or_else(|| format!("Asset with key '{}' not found", key))?;
+    let metadata = asset_metadata(&key)
+        .ok_or_else(|| "Failed to get asset metadata".to_string())?;
+    
+    // Assets are stored decoded, so the bytes go out as they are
+    Ok(DownloadResult {
+        data: asset.data,
+        content_type: asset.content_type,
+        metadata,
     })
 }
 
+// List all assets - admin only
 #[query]
-fn is_admin_type(user: Principal, required_type: AdminType) -> bool {
-    ADMINS.with(|admins| {
-        admins.borrow().get(&user) == Some(&required_type)
+fn list_assets() -> Result<Vec<AssetMetadata>, String> {
+    // We don't need caller for this query function
+    
+    // Get all asset metadata
+    ASSETS.with(|assets| {
+        Ok(assets.borrow().iter()
+            .map(|(_, metadata)| metadata)
+            .collect())
     })
 }
 
+#[query]
+fn get_asset_info(key: String) -> Option<AssetMetadata> {
+    asset_metadata(&key)
+}
+
+// Keys, content types and descriptions are stored in bounded stable map entries
+fn check_asset_fields(key: &str, content_type: &str, description: &Option<String>) -> Result<(), String> {
+    if key.is_empty() || key.len() > MAX_ASSET_KEY_LENGTH {
+        return Err(format!("Asset key must be between 1 and {} bytes", MAX_ASSET_KEY_LENGTH));
+    }
+    if is_reserved_route_key(key) {
+        return Err(format!("Asset key '{}' is reserved for the HTTP routes", key));
+    }
+    if content_type.is_empty() || content_type.len() > MAX_CONTENT_TYPE_LENGTH {
+        return Err(format!("Content type must be between 1 and {} bytes", MAX_CONTENT_TYPE_LENGTH));
+    }
+    if description.as_ref().is_some_and(|d| d.len() > MAX_ASSET_DESCRIPTION_LENGTH) {
+        return Err(format!("Asset description must be at most {} bytes", MAX_ASSET_DESCRIPTION_LENGTH));
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct RenameAssetArgs {
+    from: String,
+    to: String,
+    overwrite: Option<bool>,        // Replace an existing asset at `to` (default false)
+    expected_version: Option<u64>,  // Version `to` must be at, 0 if it must not exist
+}
+
+// Delete an asset - admin only. Assets referenced by minted tokens are kept unless
+// a system admin forces the deletion; assets locked by frozen metadata are always kept.
 #[update]
-fn add_to_whitelist(user: Principal) -> Result<(), String> {
+fn delete_asset(key: String, force: Option<bool>) -> Result<(), String> {
     let caller = caller();
-
+    
     if !is_admin(caller) {
-        return Err("Unauthorized: Only admins can add users to whitelist".to_string());
+        return Err("Unauthorized: Only admins can delete assets".to_string());
+    }
+    
+    if !asset_exists(&key) {
+        return Err(format!("Asset with key '{}' not found", key));
+    }
+    if is_asset_frozen(&key) {
+        return Err(format!("Asset '{}' is referenced by frozen token metadata and cannot be deleted", key));
+    }
+    if is_asset_reserved(&key) {
+        return Err(format!("Asset '{}' is being minted by another request", key));
+    }
+    
+    let forced = force.unwrap_or(false);
+    if is_asset_referenced(&key) {
+        if !forced {
+            return Err(format!("Asset '{}' is referenced by a minted token; pass force to delete it anyway", key));
+        }
+        if !is_system_admin(caller) {
+            return Err("Unauthorized: Only system admins can delete assets referenced by minted tokens".to_string());
+        }
+    }
+    
+    let asset_key = AssetKey::new(&key);
+    remove_asset_chunks(&asset_key);
+    remove_encoded_chunks(&key, None);
+    ASSETS.with(|assets| assets.borrow_mut().remove(&asset_key));
+    certify_asset(&key);
+    
+    let mut tx = vec![
+        ("from".to_string(), account_value(&Account { owner: caller, subaccount: None })),
+        ("key".to_string(), Value::Text(key)),
+    ];
+    if forced {
+        tx.push(("forced".to_string(), Value::Nat(Nat::from(1u8))));
     }
-    
-    WHITELIST.with(|whitelist| {
-        whitelist.borrow_mut().insert(user, true);
-    });
+    record_block(BTYPE_ASSET_DELETE, tx);
     
     Ok(())
 }
 
+// Move an asset to a new key - admin only. Assets referenced by minted tokens keep
+// their key, since token image URLs point at it.
 #[update]
-fn remove_from_whitelist(user: Principal) -> Result<(), String> {
+fn rename_asset(args: RenameAssetArgs) -> Result<AssetMetadata, String> {
     let caller = caller();
-
+    
     if !is_admin(caller) {
-        return Err("Unauthorized: Only admins can remove users from whitelist".to_string());
+        return Err("Unauthorized: Only admins can rename assets".to_string());
     }
     
-    WHITELIST.with(|whitelist| {
-        whitelist.borrow_mut().remove(&user);
+    let mut metadata = asset_metadata(&args.from)
+        .ok_or_else(|| format!("Asset with key '{}' not found", args.from))?;
+    check_asset_fields(&args.to, &metadata.content_type, &metadata.description)?;
+    if args.from == args.to {
+        return Err("Source and destination keys are the same".to_string());
+    }
+    if is_asset_referenced(&args.from) {
+        return Err(format!("Asset '{}' is referenced by a minted token and cannot be renamed", args.from));
+    }
+    for key in [&args.from, &args.to] {
+        if is_asset_frozen(key) {
+            return Err(format!("Asset '{}' is referenced by frozen token metadata and cannot change", key));
+        }
+        if is_asset_reserved(key) {
+            return Err(format!("Asset '{}' is being minted by another request", key));
+        }
+    }
+    if asset_exists(&args.to) && !args.overwrite.unwrap_or(false) {
+        return Err(format!("Asset '{}' already exists; pass overwrite to replace it", args.to));
+    }
+    check_asset_version(&args.to, args.expected_version)?;
+    
+    let from_key = AssetKey::new(&args.from);
+    let to_key = AssetKey::new(&args.to);
+    remove_asset_chunks(&to_key);
+    let chunks: Vec<(u32, AssetChunk)> = ASSET_CHUNKS.with(|chunks| {
+        chunks.borrow()
+            .range((from_key.clone(), 0)..=(from_key.clone(), u32::MAX))
+            .map(|((_, index), chunk)| (index, chunk))
+            .collect()
+    });
+    remove_asset_chunks(&from_key);
+    ASSET_CHUNKS.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        for (index, chunk) in chunks {
+            stored.insert((to_key.clone(), index), chunk);
+        }
     });
     
-    Ok(())
+    remove_encoded_chunks(&args.to, None);
+    for encoding in ContentEncoding::ALL {
+        let from_chunk_key = (from_key.clone(), encoding.id());
+        let pieces: Vec<(u32, AssetChunk)> = ENCODED_CHUNKS.with(|chunks| {
+            chunks.borrow()
+                .range((from_chunk_key.clone(), 0)..=(from_chunk_key.clone(), u32::MAX))
+                .map(|((_, index), chunk)| (index, chunk))
+                .collect()
+        });
+        ENCODED_CHUNKS.with(|chunks| {
+            let mut chunks = chunks.borrow_mut();
+            for (index, piece) in pieces {
+                chunks.remove(&(from_chunk_key.clone(), index));
+                chunks.insert(((to_key.clone(), encoding.id()), index), piece);
+            }
+        });
+    }
+    
+    metadata.key = args.to.clone();
+    metadata.modified_at = time();
+    metadata.version = Some(next_asset_version(&args.to));
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        assets.remove(&from_key);
+        assets.insert(to_key, metadata.clone());
+    });
+    certify_asset(&args.from);
+    certify_asset(&args.to);
+    
+    record_block(BTYPE_ASSET_RENAME, vec![
+        ("from".to_string(), account_value(&Account { owner: caller, subaccount: None })),
+        ("key".to_string(), Value::Text(args.from)),
+        ("new_key".to_string(), Value::Text(args.to)),
+        ("version".to_string(), nat_value(metadata.version.unwrap_or(1))),
+    ]);
+    
+    Ok(metadata)
 }
 
-#[query]
-fn is_whitelisted(user: Principal) -> bool {
-    WHITELIST.with(|whitelist| {
-        whitelist.borrow().get(&user).is_some()
-    })
+// Whether a minted token uses the asset, including tokens burned since. Minted
+// assets stay marked after a burn, so MINTED_ASSETS covers every token asset.
+fn is_asset_referenced(key: &str) -> bool {
+    is_asset_minted(key)
 }
 
-// Helper functions for admin checks
-fn is_admin(user: Principal) -> bool {
-    ADMINS.with(|admins| {
-        admins.borrow().contains_key(&user)
-    })
+// Current version of an asset, 0 if there is none
+fn asset_version(key: &str) -> u64 {
+    asset_metadata(key).map_or(0, |metadata| metadata.version.unwrap_or(1))
 }
 
-fn is_system_admin(user: Principal) -> bool {
-    ADMINS.with(|admins| {
-        admins.borrow().get(&user) == Some(&AdminType::System)
-    })
+fn next_asset_version(key: &str) -> u64 {
+    asset_version(key) + 1
 }
 
-fn is_functional_admin(user: Principal) -> bool {
-    ADMINS.with(|admins| {
-        admins.borrow().get(&user).is_some()
-    })
+// Compare-and-swap guard for writes that replace an asset
+fn check_asset_version(key: &str, expected_version: Option<u64>) -> Result<(), String> {
+    match expected_version {
+        Some(expected) if expected != asset_version(key) => Err(format!(
+            "Asset '{}' is at version {}, expected version {}", key, asset_version(key), expected
+        )),
+        _ => Ok(()),
+    }
 }
 
-/// Helper function to check if data is hex-encoded
-fn is_hex_encoded(data: &Vec<u8>) -> bool {
-    // Check if data matches common hex patterns
-    if data.len() > 2 {
-        // Common SVG hex pattern starts with '<' and then has a hex digit sequence
-        if data[0] == b'<' && data[1] as char == '3' && data[2] as char == 'f' {
-            return true;
-        }
+// Decode upload data exactly once, according to its declared transfer encoding
+fn decode_transfer_encoding(data: Vec<u8>, encoding: TransferEncoding) -> Result<Vec<u8>, String> {
+    match encoding {
+        TransferEncoding::Raw => Ok(data),
+        TransferEncoding::Base64 => base64::decode(&data)
+            .map_err(|e| format!("Invalid base64 data: {}", e)),
+        TransferEncoding::Hex => decode_hex(&data),
     }
-    false
 }
 
-/// Helper function to decode hex-encoded data
-fn decode_hex(hex_data: &Vec<u8>) -> Result<Vec<u8>, String> {
-    // First check if it's already valid UTF-8 and starts with an XML declaration
-    // This would indicate it's already properly formatted SVG
-    if let Ok(s) = String::from_utf8(hex_data.clone()) {
-        if s.starts_with("<?xml") || s.starts_with("<svg") {
-            // This is already properly formatted SVG - return as is
-            return Ok(hex_data.clone());
-        }
-    }
-    
-    // Check if it's base64 encoded
-    if is_base64(hex_data) {
-        return decode_base64(hex_data);
+fn decode_hex(data: &[u8]) -> Result<Vec<u8>, String> {
+    if !data.len().is_multiple_of(2) {
+        return Err("Invalid hex data: odd number of digits".to_string());
     }
-    
-    // Convert bytes to a string for hex processing
-    let hex_str = match String::from_utf8(hex_data.clone()) {
-        Ok(s) => s,
-        Err(_) => return Err("Invalid UTF-8 in hex data".to_string()),
-    };
-    
-    // Remove any whitespace
-    let hex_str = hex_str.trim();
-    
-    // Check if the string has the "\" prefix used in canister calls
-    let hex_str = if hex_str.starts_with("\\x") {
-        &hex_str[2..]
-    } else if hex_str.starts_with("\\u{") && hex_str.ends_with("}") {
-        &hex_str[3..hex_str.len() - 1]
-    } else {
-        hex_str
-    };
-    
-    // Now decode the hex string
-    (0..hex_str.len())
-        .step_by(2)
-        .map(|i| {
-            if i + 2 <= hex_str.len() {
-                let byte_str = &hex_str[i..i + 2];
-                u8::from_str_radix(byte_str, 16).map_err(|e| e.to_string())
-            } else {
-                Err("Odd number of digits in hex string".to_string())
-            }
+    data.chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair).ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("Invalid hex data: '{}' is not a hex byte", String::from_utf8_lossy(pair)))
         })
         .collect()
 }
 
-// Helper function to check if data appears to be base64 encoded
-fn is_base64(data: &[u8]) -> bool {
-    // Convert to a UTF-8 string
-    if let Ok(s) = String::from_utf8(data.to_vec()) {
-        // Check if it looks like base64: starts with reasonable characters and possibly ends with = padding
-        let valid_chars = s.chars().all(|c| {
-            (c >= 'A' && c <= 'Z') || 
-            (c >= 'a' && c <= 'z') || 
-            (c >= '0' && c <= '9') || 
-            c == '+' || c == '/' || c == '='
-        });
-        
-        // Consider it base64 if it has valid chars and reasonable length
-        return valid_chars && s.len() > 8;
+// Whether the bytes are in the declared format, None for formats we cannot recognise
+fn matches_signature(content_type: &str, bytes: &[u8]) -> Option<bool> {
+    match content_type {
+        "image/png" => Some(bytes.starts_with(b"\x89PNG\r\n\x1a\n")),
+        "image/svg+xml" => Some(std::str::from_utf8(bytes).is_ok_and(|text| text.trim_start().starts_with('<'))),
+        "image/jpeg" => Some(bytes.starts_with(&[0xff, 0xd8, 0xff])),
+        "image/gif" => Some(bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a")),
+        "image/webp" => Some(bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP"),
+        _ => None,
     }
-    false
 }
 
-// Helper function to decode base64-encoded data
-fn decode_base64(base64_data: &[u8]) -> Result<Vec<u8>, String> {
-    // First convert to a UTF-8 string
-    let base64_str = match String::from_utf8(base64_data.to_vec()) {
-        Ok(s) => s,
-        Err(_) => return Err("Invalid UTF-8 in base64 string".to_string()),
-    };
-    
-    // Decode base64 using simple implementation
-    // This is a basic implementation and could be replaced with a library
-    let decoded = base64_decode(&base64_str);
-    Ok(decoded)
+fn looks_transfer_encoded(data: &[u8]) -> bool {
+    std::str::from_utf8(data).is_ok_and(|text| {
+        let text = text.trim().as_bytes();
+        base64::decode(text).is_ok() || decode_hex(text).is_ok()
+    })
 }
 
-// Basic base64 decoding implementation
-fn base64_decode(input: &str) -> Vec<u8> {
-    let alphabet = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
-    
-    let mut result = Vec::new();
-    let mut buf = 0u32;
-    let mut bits_left = 0;
-    
-    for c in input.chars().filter(|&c| c != '\n' && c != '\r' && c != ' ' && c != '\t') {
-        if c == '=' {
-            // Padding character, skip
-            continue;
-        }
-        
-        if let Some(val) = alphabet.find(c) {
-            buf = (buf << 6) | val as u32;
-            bits_left += 6;
-            
-            if bits_left >= 8 {
-                bits_left -= 8;
-                result.push((buf >> bits_left) as u8);
-                buf &= (1 << bits_left) - 1;
-            }
-        } else {
-            // Invalid character, ignore
-        }
+// Bytes an asset stored by the old guessing upload path stands for: base64 or hex
+// text that strictly decodes to the declared image format. None if already canonical
+// or the format has no signature to check against.
+fn legacy_canonical_bytes(content_type: &str, data: &[u8]) -> Option<Vec<u8>> {
+    let is_canonical = |bytes: &[u8]| matches_signature(content_type, bytes).unwrap_or(true);
+    if is_canonical(data) {
+        return None;
     }
     
-    result
+    let text = std::str::from_utf8(data).ok()?.trim().as_bytes();
+    [base64::decode(text).ok(), decode_hex(text).ok()]
+        .into_iter()
+        .flatten()
+        .find(|decoded| is_canonical(decoded))
 }
 
-// Helper to count system admins
-fn count_system_admins() -> usize {
-    ADMINS.with(|admins| {
-        admins.borrow()
-            .iter()
-            .filter(|(_, admin_type)| **admin_type == AdminType::System)
-            .count()
-    })
-}
+// ==== CONTENT ENCODINGS ====
+//
+// Assets may carry precompressed variants next to their identity bytes. The
+// canister does not compress or inflate anything itself: variants are uploaded
+// by admins and nothing checks that they decode to the identity content. Each
+// variant is certified as stored, so the certificate vouches for the bytes that
+// were uploaded, not for their equivalence with the identity content.
 
-// ==== MINTING FUNCTIONS ====
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq)]
+enum ContentEncoding {
+    Gzip,
+    Brotli,
+}
 
-#[update]
-async fn mint(args: MintArgs) -> Result<u64, String> {
-    let caller = caller();
-    let current_time = ic_cdk::api::time();
+impl ContentEncoding {
+    // Preferred first when the client accepts several
+    const ALL: [ContentEncoding; 2] = [ContentEncoding::Brotli, ContentEncoding::Gzip];
     
-    // Check that minting is active for this user
-    COLLECTION_DETAILS.with(|details| {
-        let details = details.borrow();
-        
-        // Check if pricing is enabled
-        if !details.pricing_enabled {
-            return Err("Minting is not enabled".to_string());
-        }
-        
-        // Check if user is in whitelist
-        let user_in_whitelist = WHITELIST.with(|whitelist| {
-            whitelist.borrow().get(&caller).copied().unwrap_or(false)
-        });
-        
-        // Find active schedules that match the user's status
-        let active_schedules: Vec<&MintSchedule> = details.mint_schedules.iter()
-            .filter(|s| s.active)
-            .filter(|s| {
-                // Check if time constraints are met
-                let time_valid = match (s.start_time, s.end_time) {
-                    (Some(start), Some(end)) => current_time >= start && current_time <= end,
-                    (Some(start), None) => current_time >= start,
-                    (None, Some(end)) => current_time <= end,
-                    (None, None) => true,
-                };
-                
-                // Check if user status matches the schedule
-                let status_matches = if s.whitelist_only {
-                    user_in_whitelist
-                } else {
-                    true // Non-whitelist schedules apply to everyone
-                };
-                
-                time_valid && status_matches
-            })
-            .collect();
-        
-        if active_schedules.is_empty() {
-            return Err("No active minting schedules available for this user".to_string());
+    // Content-Encoding token
+    fn name(self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Brotli => "br",
         }
-        
-        // Check max supply if set
-        if let Some(max_supply) = details.max_supply {
-            let minted_count = NFT_COUNTER.with(|counter| counter.borrow().get());
-            if minted_count >= max_supply {
-                return Err("Maximum supply reached".to_string());
-            }
-        }
-        
-        // Get the price for this minting (1 NFT)
-        let quantity = 1;
-        
-        // Get the appropriate price from the active schedules
-        let price = get_active_mint_price(quantity, &active_schedules)?
-            .ok_or_else(|| "No price available for this quantity".to_string())?;
-        
-        // TODO: Handle ICP payment verification here
-        // 1. Check if price > 0
-        // 2. If yes, verify that correct amount was paid
-        
-        Ok(())
-    })?;
-    
-    // Mint the NFT now that all checks have passed
-    let new_token_id = mint_nft(caller, args.asset_id.clone())?;
-    
-    // Record the transaction
-    record_transaction(
-        "mint",
-        new_token_id,
-        ic_cdk::api::id(),
-        caller,
-        None, // memo
-        format!("Minted token {} with asset {}", new_token_id, args.asset_id)
-    );
-    
-    Ok(new_token_id)
-}
-
-// New function to mint multiple NFTs
-#[update]
-async fn mint_bundle(args: MintBundleArgs) -> Result<Vec<u64>, String> {
-    let caller = caller();
-    let current_time = ic_cdk::api::time();
-    let quantity = args.quantity;
+    }
     
-    if quantity == 0 {
-        return Err("Quantity must be greater than 0".to_string());
+    fn from_name(name: &str) -> Option<ContentEncoding> {
+        ContentEncoding::ALL.into_iter().find(|encoding| encoding.name() == name)
     }
     
-    // Check that minting is active for this user
-    COLLECTION_DETAILS.with(|details| {
-        let details = details.borrow();
-        
-        // Check if pricing is enabled
-        if !details.pricing_enabled {
-            return Err("Minting is not enabled".to_string());
-        }
-        
-        // Check if user is in whitelist
-        let user_in_whitelist = WHITELIST.with(|whitelist| {
-            whitelist.borrow().get(&caller).copied().unwrap_or(false)
-        });
-        
-        // Find active schedules that match the user's status
-        let active_schedules: Vec<&MintSchedule> = details.mint_schedules.iter()
-            .filter(|s| s.active)
-            .filter(|s| {
-                // Check if time constraints are met
-                let time_valid = match (s.start_time, s.end_time) {
-                    (Some(start), Some(end)) => current_time >= start && current_time <= end,
-                    (Some(start), None) => current_time >= start,
-                    (None, Some(end)) => current_time <= end,
-                    (None, None) => true,
-                };
-                
-                // Check if user status matches the schedule
-                let status_matches = if s.whitelist_only {
-                    user_in_whitelist
-                } else {
-                    true // Non-whitelist schedules apply to everyone
-                };
-                
-                time_valid && status_matches
-            })
-            .collect();
-        
-        if active_schedules.is_empty() {
-            return Err("No active minting schedules available for this user".to_string());
-        }
-        
-        // Check max supply if set
-        if let Some(max_supply) = details.max_supply {
-            let minted_count = NFT_COUNTER.with(|counter| counter.borrow().get());
-            if minted_count + quantity > max_supply {
-                return Err(format!("Requested quantity exceeds available supply: {} left", max_supply - minted_count));
-            }
+    // Discriminant used in ENCODED_CHUNKS keys
+    fn id(self) -> u8 {
+        match self {
+            ContentEncoding::Gzip => 1,
+            ContentEncoding::Brotli => 2,
         }
-        
-        // Get the price for this bundle size
-        let price = get_active_mint_price(quantity, &active_schedules)?
-            .ok_or_else(|| format!("No price available for quantity {}", quantity))?;
-        
-        // TODO: Handle ICP payment verification here
-        // 1. Check if price > 0
-        // 2. If yes, verify that correct amount was paid
-        
-        Ok(())
-    })?;
-    
-    // Mint the NFTs now that all checks have passed
-    let mut token_ids = Vec::with_capacity(quantity as usize);
-    
-    for _ in 0..quantity {
-        // Generate a unique asset ID for each token in the bundle
-        let asset_id = format!("asset-{}", generate_uuid());
-        
-        // Mint the NFT
-        let token_id = mint_nft(caller, asset_id)?;
-        token_ids.push(token_id);
     }
-    
-    // Get the first token to represent the bundle in the transaction
-    let first_token_id = token_ids.first().copied().unwrap_or(0);
-    
-    // Record the transaction for the entire bundle
-    record_transaction(
-        "mint_bundle",
-        first_token_id,
-        ic_cdk::api::id(),
-        caller,
-        None, // memo
-        format!("Minted bundle of {} tokens", quantity)
-    );
-    
-    Ok(token_ids)
 }
 
-// Get available bundles for the user
-#[query]
-fn get_available_bundles(user: Principal) -> Vec<(MintSchedule, Vec<BundlePrice>)> {
-    let current_time = ic_cdk::api::time();
-    
-    COLLECTION_DETAILS.with(|details| {
-        let details = details.borrow();
-        
-        if !details.pricing_enabled {
-            return vec![];
-        }
-        
-        // Check if user is in whitelist
-        let user_in_whitelist = WHITELIST.with(|whitelist| {
-            whitelist.borrow().get(&user).copied().unwrap_or(false)
-        });
-        
-        // Find active schedules that match the user's status
-        details.mint_schedules.iter()
-            .filter(|s| s.active)
-            .filter(|s| {
-                // Check if time constraints are met
-                let time_valid = match (s.start_time, s.end_time) {
-                    (Some(start), Some(end)) => current_time >= start && current_time <= end,
-                    (Some(start), None) => current_time >= start,
-                    (None, Some(end)) => current_time <= end,
-                    (None, None) => true,
-                };
-                
-                // Check if user status matches the schedule
-                let status_matches = if s.whitelist_only {
-                    user_in_whitelist
-                } else {
-                    true // Non-whitelist schedules apply to everyone
-                };
-                
-                time_valid && status_matches
-            })
-            .map(|schedule| (schedule.clone(), schedule.bundle_prices.clone()))
-            .collect()
-    })
+// A stored encoded variant of an asset
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct AssetEncoding {
+    encoding: ContentEncoding,
+    size: usize,
+    sha256: Vec<u8>, // Hash of the encoded bytes, used for its ETag
 }
 
-// ==== CUSTOM QUERY FUNCTIONS ====
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct EncodedContent {
+    encoding: ContentEncoding,
+    data: Vec<u8>,
+}
 
-#[query]
-fn get_nft(token_id: u64) -> Option<NFT> {
-    NFTS.with(|nfts| {
-        nfts.borrow().get(&token_id).cloned()
-    })
+impl AssetMetadata {
+    fn encoded(&self, encoding: ContentEncoding) -> Option<&AssetEncoding> {
+        self.encodings.iter().flatten().find(|variant| variant.encoding == encoding)
+    }
 }
 
-#[query]
-fn get_user_nfts(user: Principal) -> Vec<NFT> {
-    OWNER_TOKENS.with(|owner_tokens| {
-        let tokens = owner_tokens.borrow();
-        if let Some(token_ids) = tokens.get(&user) {
-            return NFTS.with(|nfts| {
-                let nfts_map = nfts.borrow();
-                token_ids.iter()
-                    .filter_map(|id| nfts_map.get(id).cloned())
-                    .collect()
-            });
+// Add or replace an encoded variant of an existing asset
+fn store_asset_encoding(key: &str, encoding: ContentEncoding, pieces: impl IntoIterator<Item = (u32, AssetChunk)>) -> Result<AssetEncoding, String> {
+    let mut metadata = asset_metadata(key)
+        .ok_or_else(|| format!("Asset with key '{}' not found", key))?;
+    
+    remove_encoded_chunks(key, Some(encoding));
+    let chunk_key = (AssetKey::new(key), encoding.id());
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    ENCODED_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for (index, piece) in pieces {
+            hasher.update(&piece.0);
+            size += piece.0.len();
+            chunks.insert((chunk_key.clone(), index), piece);
         }
-        Vec::new()
-    })
+    });
+    
+    let variant = AssetEncoding { encoding, size, sha256: hasher.finalize().to_vec() };
+    let mut encodings = metadata.encodings.take().unwrap_or_default();
+    encodings.retain(|existing| existing.encoding != encoding);
+    encodings.push(variant.clone());
+    metadata.encodings = Some(encodings);
+    ASSETS.with(|assets| assets.borrow_mut().insert(AssetKey::new(key), metadata));
+    certify_asset(key);
+    Ok(variant)
 }
 
-#[query]
-fn get_transaction_history(token_id: u64) -> Vec<TransferRecord> {
-    NFTS.with(|nfts| {
-        nfts.borrow()
-            .get(&token_id)
-            .map(|nft| nft.transfer_history.clone())
-            .unwrap_or_default()
-    })
+// Drop one encoded variant's chunks, or those of all variants
+fn remove_encoded_chunks(key: &str, encoding: Option<ContentEncoding>) {
+    for encoding in ContentEncoding::ALL.into_iter().filter(|e| encoding.is_none_or(|only| only == *e)) {
+        let chunk_key = (AssetKey::new(key), encoding.id());
+        ENCODED_CHUNKS.with(|chunks| {
+            let mut chunks = chunks.borrow_mut();
+            let keys: Vec<(EncodedAssetKey, u32)> = chunks
+                .range((chunk_key.clone(), 0)..=(chunk_key.clone(), u32::MAX))
+                .map(|(key, _)| key)
+                .collect();
+            for key in keys {
+                chunks.remove(&key);
+            }
+        });
+    }
 }
 
-#[query]
-fn get_collection_info() -> Vec<(String, Value)> {
-    icrc7_collection_metadata()
+// Split data into ASSET_CHUNK_SIZE pieces
+fn asset_pieces(data: &[u8]) -> impl Iterator<Item = (u32, AssetChunk)> + '_ {
+    data.chunks(ASSET_CHUNK_SIZE)
+        .enumerate()
+        .map(|(index, piece)| (index as u32, AssetChunk(piece.to_vec())))
 }
 
-// Dedicated method to update the base URL - admin only
+// Best stored variant the client accepts, None to serve identity
+fn negotiate_encoding<'a>(accept_encoding: &str, metadata: &'a AssetMetadata) -> Option<&'a AssetEncoding> {
+    let accepted: Vec<(&str, bool)> = accept_encoding.split(',')
+        .map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let rejected = parts.any(|param| {
+                param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+            });
+            (name, !rejected)
+        })
+        .collect();
+    let accepts = |encoding: ContentEncoding| {
+        accepted.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(encoding.name()))
+            .or_else(|| accepted.iter().find(|(name, _)| *name == "*"))
+            .is_some_and(|(_, ok)| *ok)
+    };
+    
+    ContentEncoding::ALL.into_iter()
+        .filter(|encoding| accepts(*encoding))
+        .find_map(|encoding| metadata.encoded(encoding))
+}
+
+// ==== CHUNKED UPLOADS ====
+//
+// Files too large for a single ingress message are uploaded in a session:
+// create_upload_batch, then upload_chunk in order, then commit_upload_batch with
+// the SHA-256 of the whole file. Chunks are staged in UPLOAD_CHUNKS and only
+// become visible under the asset key on commit.
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct UploadBatch {
+    key: String,
+    content_type: String,
+    description: Option<String>,
+    uploaded_by: Principal,
+    expected_version: Option<u64>,
+    content_encoding: Option<ContentEncoding>,
+    identity_sha256: Option<Vec<u8>>, // Identity content an encoded variant is uploaded against
+    length: u64,      // Bytes received so far
+    chunk_count: u32, // Chunks received so far, also the index of the next chunk
+    expires_at: u64,  // Extended by every chunk
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct CreateUploadBatchArgs {
+    key: String,
+    content_type: String,
+    description: Option<String>,
+    expected_version: Option<u64>, // Checked again on commit
+    content_encoding: Option<ContentEncoding>, // Upload an encoded variant of the existing asset instead
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct UploadChunkArgs {
+    batch_id: u64,
+    index: u32,    // Position of the chunk in the file, starting at 0
+    data: Vec<u8>, // All chunks but the last must be a multiple of ASSET_CHUNK_SIZE
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct CommitUploadBatchArgs {
+    batch_id: u64,
+    sha256: Vec<u8>, // SHA-256 of the complete file
+}
+
+// Open an upload session for an asset - admin only. Returns the batch ID.
 #[update]
-fn update_base_url(new_base_url: String) -> Result<(), String> {
+fn create_upload_batch(args: CreateUploadBatchArgs) -> Result<u64, String> {
     let caller = caller();
     
-    // Check if caller is an admin (either type)
     if !is_admin(caller) {
-        return Err("Unauthorized: Only admins can update the base URL".to_string());
+        return Err("Unauthorized: Only admins can upload assets".to_string());
     }
     
-    // Update the base URL
-    COLLECTION_DETAILS.with(|details| {
-        let mut details_ref = details.borrow_mut();
-        details_ref.base_url = new_base_url;
+    check_asset_fields(&args.key, &args.content_type, &args.description)?;
+    check_asset_version(&args.key, args.expected_version)?;
+    if args.content_encoding.is_some() && !asset_exists(&args.key) {
+        return Err(format!("Asset with key '{}' not found; upload its identity content first", args.key));
+    }
+    if is_asset_frozen(&args.key) {
+        return Err(format!("Asset '{}' is referenced by frozen token metadata and cannot be replaced", args.key));
+    }
+    
+    prune_upload_batches(time());
+    
+    let identity_sha256 = args.content_encoding
+        .and_then(|_| asset_metadata(&args.key))
+        .and_then(|metadata| metadata.sha256);
+    let batch_id = NEXT_UPLOAD_BATCH_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        *next += 1;
+        *next
     });
     
-    Ok(())
+    UPLOAD_BATCHES.with(|batches| {
+        batches.borrow_mut().insert(batch_id, UploadBatch {
+            key: args.key,
+            content_type: args.content_type,
+            description: args.description,
+            uploaded_by: caller,
+            expected_version: args.expected_version,
+            content_encoding: args.content_encoding,
+            identity_sha256,
+            length: 0,
+            chunk_count: 0,
+            expires_at: time() + UPLOAD_BATCH_EXPIRY_NANOS,
+        });
+    });
+    
+    Ok(batch_id)
 }
 
-// Update prices for NFT bundles - unified method for admin
-// Deprecated: use update_mint_schedule instead
+// Append the next chunk to an upload session. Returns the bytes received so far.
 #[update]
-fn update_prices(_price_args: String) -> Result<(), String> {
-    Err("This method is deprecated. Use update_mint_schedule instead.".to_string())
-}
-
-// Get minting timeframes - public query
-#[query]
-fn get_minting_timeframes() -> Vec<MintSchedule> {
-    COLLECTION_DETAILS.with(|details| details.borrow().mint_schedules.clone())
-}
-
-// Check if minting is currently active
-#[query]
-fn is_minting_active() -> (bool, bool, u64) {
-    let current_time = ic_cdk::api::time();
+fn upload_chunk(args: UploadChunkArgs) -> Result<u64, String> {
+    let caller = caller();
+    let mut batch = open_upload_batch(caller, args.batch_id)?;
     
-    COLLECTION_DETAILS.with(|details| {
-        let details = details.borrow();
-        
-        // Check if public minting is active
-        let public_minting_active = details.mint_schedules.iter().any(|schedule| {
-            match (schedule.start_time, schedule.end_time) {
-                (Some(start), Some(end)) => current_time >= start && current_time <= end,
-                (Some(start), None) => current_time >= start,
-                (None, Some(end)) => current_time <= end,
-                (None, None) => true,
-            }
-        });
-        
-        // Check if whitelist minting is active
-        let whitelist_active = details.mint_schedules.iter().any(|schedule| {
-            match (schedule.start_time, schedule.end_time) {
-                (Some(start), Some(end)) => current_time >= start && current_time <= end,
-                (Some(start), None) => current_time >= start,
-                (None, Some(end)) => current_time <= end,
-                (None, None) => true,
-            }
-        });
-        
-        (public_minting_active, whitelist_active, current_time)
-    })
+    if args.index != batch.chunk_count {
+        return Err(format!("Expected chunk {} of batch {}, got chunk {}", batch.chunk_count, args.batch_id, args.index));
+    }
+    if args.data.is_empty() || args.data.len() > MAX_UPLOAD_CHUNK_SIZE {
+        return Err(format!("Chunks must be between 1 and {} bytes", MAX_UPLOAD_CHUNK_SIZE));
+    }
+    // Staged pieces must line up with the ASSET_CHUNK_SIZE layout used for streaming
+    if batch.length % ASSET_CHUNK_SIZE as u64 != 0 {
+        return Err(format!("Only the last chunk may be shorter than a multiple of {} bytes", ASSET_CHUNK_SIZE));
+    }
+    if batch.length + args.data.len() as u64 > MAX_ASSET_SIZE as u64 {
+        return Err(format!("Assets are limited to {} bytes", MAX_ASSET_SIZE));
+    }
+    
+    let first_piece = (batch.length / ASSET_CHUNK_SIZE as u64) as u32;
+    UPLOAD_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for (offset, piece) in args.data.chunks(ASSET_CHUNK_SIZE).enumerate() {
+            chunks.insert((args.batch_id, first_piece + offset as u32), AssetChunk(piece.to_vec()));
+        }
+    });
+    
+    batch.length += args.data.len() as u64;
+    batch.chunk_count += 1;
+    batch.expires_at = time() + UPLOAD_BATCH_EXPIRY_NANOS;
+    let length = batch.length;
+    UPLOAD_BATCHES.with(|batches| {
+        batches.borrow_mut().insert(args.batch_id, batch);
+    });
+    
+    Ok(length)
 }
 
-// Collection update method - admin only
+// Verify the uploaded file against its SHA-256 and publish it under the batch's key
 #[update]
-fn update_collection_details(args: UpdateCollectionDetailsArgs) -> Result<(), String> {
+fn commit_upload_batch(args: CommitUploadBatchArgs) -> Result<AssetMetadata, String> {
     let caller = caller();
+    let batch = open_upload_batch(caller, args.batch_id)?;
     
-    // Check if caller is an admin (either type)
-    if !is_admin(caller) {
-        return Err("Unauthorized: Only admins can update collection details".to_string());
+    if batch.length == 0 {
+        return Err(format!("Batch {} has no chunks", args.batch_id));
+    }
+    if is_asset_frozen(&batch.key) {
+        return Err(format!("Asset '{}' is referenced by frozen token metadata and cannot be replaced", batch.key));
     }
+    check_asset_version(&batch.key, batch.expected_version)?;
     
-    // Check if we're trying to update max_supply and if minting has started
-    if args.max_supply.is_some() {
-        let nft_count = NFTS.with(|nfts| nfts.borrow().len());
-        if nft_count > 0 {
-            return Err("Cannot modify max supply after minting has started".to_string());
+    let mut hasher = Sha256::new();
+    UPLOAD_CHUNKS.with(|chunks| {
+        for (_, piece) in chunks.borrow().range((args.batch_id, 0)..=(args.batch_id, u32::MAX)) {
+            hasher.update(&piece.0);
         }
+    });
+    let sha256 = hasher.finalize().to_vec();
+    if sha256 != args.sha256 {
+        return Err(format!("SHA-256 mismatch for batch {}: uploaded content hashes to {}", args.batch_id, hex_string(&sha256)));
     }
     
-    // Update the collection details
-    COLLECTION_DETAILS.with(|details| {
-        let mut details_ref = details.borrow_mut();
-        
-        if let Some(name) = args.name {
-            details_ref.name = name;
-        }
-        
-        if let Some(symbol) = args.symbol {
-            details_ref.symbol = symbol;
-        }
-        
-        if let Some(description) = args.description {
-            details_ref.description = description;
-        }
-        
-        if let Some(max_supply) = args.max_supply {
-            details_ref.max_supply = Some(max_supply);
-        }
-        
-        if let Some(base_url) = args.base_url {
-            details_ref.base_url = base_url;
-        }
-        
-        if let Some(pricing_enabled) = args.pricing_enabled {
-            details_ref.pricing_enabled = pricing_enabled;
+    // An encoded variant leaves the identity content and its version alone. It is
+    // only stored next to the identity content it was uploaded against.
+    if let Some(encoding) = batch.content_encoding {
+        let identity = asset_metadata(&batch.key)
+            .ok_or_else(|| format!("Asset with key '{}' not found", batch.key))?;
+        if identity.sha256 != batch.identity_sha256 {
+            return Err(format!("Asset '{}' was replaced after batch {} was created; upload the variant again", batch.key, args.batch_id));
         }
+        store_asset_encoding(&batch.key, encoding, take_staged_pieces(args.batch_id))?;
+        UPLOAD_BATCHES.with(|batches| batches.borrow_mut().remove(&args.batch_id));
         
-        if let Some(mint_schedules) = args.mint_schedules {
-            details_ref.mint_schedules = mint_schedules;
+        record_block(BTYPE_ASSET_UPLOAD, vec![
+            ("from".to_string(), account_value(&Account { owner: caller, subaccount: None })),
+            ("key".to_string(), Value::Text(batch.key.clone())),
+            ("sha256".to_string(), Value::Blob(sha256)),
+            ("content_encoding".to_string(), Value::Text(encoding.name().to_string())),
+        ]);
+        return asset_metadata(&batch.key).ok_or_else(|| format!("Asset with key '{}' not found", batch.key));
+    }
+    
+    // Move the staged pieces over any previous content under the key
+    let version = next_asset_version(&batch.key);
+    let asset_key = AssetKey::new(&batch.key);
+    remove_asset_chunks(&asset_key);
+    remove_encoded_chunks(&batch.key, None);
+    ASSET_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for (index, piece) in take_staged_pieces(args.batch_id) {
+            chunks.insert((asset_key.clone(), index), piece);
         }
     });
     
-    Ok(())
+    let metadata = AssetMetadata {
+        key: batch.key.clone(),
+        content_type: batch.content_type,
+        size: batch.length as usize,
+        created_at: time(),
+        modified_at: time(),
+        description: batch.description,
+        uploaded_by: caller,
+        sha256: Some(sha256.clone()),
+        version: Some(version),
+        encodings: None,
+    };
+    ASSETS.with(|assets| {
+        assets.borrow_mut().insert(asset_key, metadata.clone());
+    });
+    UPLOAD_BATCHES.with(|batches| batches.borrow_mut().remove(&args.batch_id));
+    certify_asset(&batch.key);
+    
+    record_block(BTYPE_ASSET_UPLOAD, vec![
+        ("from".to_string(), account_value(&Account { owner: caller, subaccount: None })),
+        ("key".to_string(), Value::Text(batch.key)),
+        ("sha256".to_string(), Value::Blob(sha256)),
+        ("version".to_string(), nat_value(version)),
+    ]);
+    
+    Ok(metadata)
 }
 
-// Arguments for updating a specific mint schedule
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub struct UpdateMintScheduleArgs {
-    pub name: String,                 // Name of the schedule to update (must be unique)
-    pub bundle_prices: Vec<BundlePrice>,        // Bundle prices directly associated with this schedule
-    pub start_time: Option<u64>,      // Start time in nanoseconds since epoch
-    pub end_time: Option<u64>,        // End time in nanoseconds since epoch
-    pub active: Option<bool>,         // Whether this schedule is active
-    pub whitelist_only: Option<bool>, // Whether this schedule is only for whitelisted users
+// Remove a batch's staged pieces, in order
+fn take_staged_pieces(batch_id: u64) -> Vec<(u32, AssetChunk)> {
+    let keys: Vec<(u64, u32)> = UPLOAD_CHUNKS.with(|chunks| {
+        chunks.borrow()
+            .range((batch_id, 0)..=(batch_id, u32::MAX))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    UPLOAD_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        keys.into_iter()
+            .filter_map(|key| chunks.remove(&key).map(|piece| (key.1, piece)))
+            .collect()
+    })
 }
 
-// Update a mint schedule or add a new one
+// Abandon an upload session and drop its staged chunks
 #[update]
-fn update_mint_schedule(args: UpdateMintScheduleArgs) -> Result<(), String> {
+fn cancel_upload_batch(batch_id: u64) -> Result<(), String> {
     let caller = caller();
-    
+    open_upload_batch(caller, batch_id)?;
+    remove_upload_batch(batch_id);
+    Ok(())
+}
+
+// A live batch the caller may still write to
+fn open_upload_batch(caller: Principal, batch_id: u64) -> Result<UploadBatch, String> {
     if !is_admin(caller) {
-        return Err("Unauthorized: Only admins can update mint schedules".to_string());
+        return Err("Unauthorized: Only admins can upload assets".to_string());
     }
     
-    if args.name.is_empty() {
-        return Err("Schedule name cannot be empty".to_string());
+    let batch = UPLOAD_BATCHES.with(|batches| batches.borrow().get(&batch_id))
+        .filter(|batch| batch.expires_at > time())
+        .ok_or_else(|| format!("Upload batch {} not found or expired", batch_id))?;
+    if batch.uploaded_by != caller {
+        return Err(format!("Upload batch {} belongs to another admin", batch_id));
+    }
+    Ok(batch)
+}
+
+fn remove_upload_batch(batch_id: u64) {
+    UPLOAD_BATCHES.with(|batches| batches.borrow_mut().remove(&batch_id));
+    UPLOAD_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        let keys: Vec<(u64, u32)> = chunks
+            .range((batch_id, 0)..=(batch_id, u32::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            chunks.remove(&key);
+        }
+    });
+}
+
+// Drop sessions that have seen no chunk for UPLOAD_BATCH_EXPIRY_NANOS
+fn prune_upload_batches(now: u64) {
+    let expired: Vec<u64> = UPLOAD_BATCHES.with(|batches| {
+        batches.borrow()
+            .iter()
+            .filter(|(_, batch)| batch.expires_at <= now)
+            .map(|(batch_id, _)| batch_id)
+            .collect()
+    });
+    for batch_id in expired {
+        remove_upload_batch(batch_id);
+    }
+}
+
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// ==== ASSET CERTIFICATION ====
+//
+// Asset responses are certified with HTTP response verification v2. Every asset
+// is reachable at /asset/<key> and /<key>; each path gets an entry under
+// "http_expr" of the form <segments>/<$>/<expression hash>/""/<response hash>,
+// and that tree is certified next to the ICRC-3 tip. Encoded variants are
+// certified as responses of their own, under a second expression that also
+// covers Content-Encoding.
+
+// Only the Content-Type header is certified, requests are not
+const ASSET_CERTIFICATE_EXPRESSION: &str = "default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{certified_response_headers:ResponseHeaderList{headers:[\"content-type\"]}}}})";
+const ENCODED_ASSET_CERTIFICATE_EXPRESSION: &str = "default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{certified_response_headers:ResponseHeaderList{headers:[\"content-type\",\"content-encoding\"]}}}})";
+
+fn asset_certificate_expression(encoding: Option<ContentEncoding>) -> &'static str {
+    match encoding {
+        Some(_) => ENCODED_ASSET_CERTIFICATE_EXPRESSION,
+        None => ASSET_CERTIFICATE_EXPRESSION,
+    }
+}
+
+// Node of the http_expr tree: a path segment or, at the bottom, the empty leaf
+enum CertNode {
+    Branch(RbTree<Vec<u8>, CertNode>),
+    Leaf,
+}
+
+impl AsHashTree for CertNode {
+    fn root_hash(&self) -> Hash {
+        match self {
+            CertNode::Branch(tree) => tree.root_hash(),
+            CertNode::Leaf => leaf_hash(b""),
+        }
     }
     
-    // Validate time range if both are provided
-    if let (Some(start), Some(end)) = (args.start_time, args.end_time) {
-        if end <= start {
-            return Err("End time must be after start time".to_string());
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        match self {
+            CertNode::Branch(tree) => tree.as_hash_tree(),
+            CertNode::Leaf => HashTree::Leaf(Cow::Borrowed(b"")),
+        }
+    }
+}
+
+fn cert_insert(tree: &mut RbTree<Vec<u8>, CertNode>, path: &[Vec<u8>]) {
+    let Some((label, rest)) = path.split_first() else { return };
+    if rest.is_empty() {
+        tree.insert(label.clone(), CertNode::Leaf);
+        return;
+    }
+    if tree.get(label).is_none() {
+        tree.insert(label.clone(), CertNode::Branch(RbTree::new()));
+    }
+    tree.modify(label, |node| {
+        if let CertNode::Branch(child) = node {
+            cert_insert(child, rest);
+        }
+    });
+}
+
+// Remove the entry at path along with any branches left empty
+fn cert_remove(tree: &mut RbTree<Vec<u8>, CertNode>, path: &[Vec<u8>]) {
+    let Some((label, rest)) = path.split_first() else { return };
+    if rest.is_empty() {
+        tree.delete(label);
+        return;
+    }
+    let mut now_empty = false;
+    tree.modify(label, |node| {
+        if let CertNode::Branch(child) = node {
+            cert_remove(child, rest);
+            now_empty = child.iter().next().is_none();
+        }
+    });
+    if now_empty {
+        tree.delete(label);
+    }
+}
+
+fn cert_witness<'a>(tree: &'a RbTree<Vec<u8>, CertNode>, path: &[Vec<u8>]) -> HashTree<'a> {
+    match path.split_first() {
+        None => tree.as_hash_tree(),
+        Some((label, rest)) => tree.nested_witness(label, |node| match node {
+            CertNode::Branch(child) => cert_witness(child, rest),
+            CertNode::Leaf => node.as_hash_tree(),
+        }),
+    }
+}
+
+// URL paths an asset is served from, as segments
+fn asset_url_paths(key: &str) -> [Vec<String>; 2] {
+    let segments: Vec<String> = key.split('/').map(str::to_string).collect();
+    let mut prefixed = vec!["asset".to_string()];
+    prefixed.extend(segments.iter().cloned());
+    [prefixed, segments]
+}
+
+// http_expr path of an exact URL path, ending in "<$>"
+fn asset_expr_path(url_path: &[String]) -> Vec<String> {
+    let mut path = Vec::with_capacity(url_path.len() + 2);
+    path.push("http_expr".to_string());
+    path.extend(url_path.iter().cloned());
+    path.push("<$>".to_string());
+    path
+}
+
+fn asset_response_hash(content_type: &str, encoding: Option<ContentEncoding>, body_sha256: &[u8]) -> Hash {
+    let mut headers = vec![("content-type".to_string(), Value::Text(content_type.to_string()))];
+    if let Some(encoding) = encoding {
+        headers.push(("content-encoding".to_string(), Value::Text(encoding.name().to_string())));
+    }
+    headers.push(("ic-certificateexpression".to_string(), Value::Text(asset_certificate_expression(encoding).to_string())));
+    headers.push((":ic-cert-status".to_string(), Value::Nat(Nat::from(200u16))));
+    let headers_hash = hash_value(&Value::Map(headers));
+    let mut hasher = Sha256::new();
+    hasher.update(headers_hash);
+    hasher.update(body_sha256);
+    hasher.finalize().into()
+}
+
+// SHA-256 of the body http_request serves for an asset: its stored bytes
+fn asset_body_sha256(metadata: &AssetMetadata) -> Vec<u8> {
+    metadata.sha256.clone().unwrap_or_else(|| {
+        let mut hasher = Sha256::new();
+        let asset_key = AssetKey::new(&metadata.key);
+        ASSET_CHUNKS.with(|chunks| {
+            for (_, chunk) in chunks.borrow().range((asset_key.clone(), 0)..=(asset_key.clone(), u32::MAX)) {
+                hasher.update(&chunk.0);
+            }
+        });
+        hasher.finalize().to_vec()
+    })
+}
+
+// (Re)certify the responses for an asset and its encoded variants, or drop them
+// if it no longer exists. Only the asset expressions' branches are touched, other
+// entries at the same path are left alone.
+fn certify_asset(key: &str) {
+    let mut responses: Vec<(Option<ContentEncoding>, Hash)> = Vec::new();
+    if let Some(metadata) = asset_metadata(key) {
+        responses.push((None, asset_response_hash(&metadata.content_type, None, &asset_body_sha256(&metadata))));
+        for variant in metadata.encodings.iter().flatten() {
+            let hash = asset_response_hash(&metadata.content_type, Some(variant.encoding), &variant.sha256);
+            responses.push((Some(variant.encoding), hash));
         }
     }
+    let expr_hash = |expression: &str| Sha256::digest(expression.as_bytes()).to_vec();
     
-    COLLECTION_DETAILS.with(|details| {
-        let mut details_ref = details.borrow_mut();
-        
-        // Try to find an existing schedule with this name
-        let existing_schedule = details_ref.mint_schedules.iter_mut().find(|s| s.name == args.name);
-        
-        if let Some(schedule) = existing_schedule {
-            // Update existing schedule
-            schedule.bundle_prices = args.bundle_prices;
-            
-            if let Some(start) = args.start_time {
-                schedule.start_time = Some(start);
-            }
-            
-            if let Some(end) = args.end_time {
-                schedule.end_time = Some(end);
-            }
-            
-            if let Some(active) = args.active {
-                schedule.active = active;
+    ASSET_CERT_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        for url_path in asset_url_paths(key) {
+            let mut path: Vec<Vec<u8>> = url_path.into_iter().map(String::into_bytes).collect();
+            path.push(b"<$>".to_vec());
+            for expression in [ASSET_CERTIFICATE_EXPRESSION, ENCODED_ASSET_CERTIFICATE_EXPRESSION] {
+                let mut expr_path = path.clone();
+                expr_path.push(expr_hash(expression));
+                cert_remove(&mut tree, &expr_path);
             }
             
-            if let Some(whitelist_only) = args.whitelist_only {
-                schedule.whitelist_only = whitelist_only;
+            for (encoding, response_hash) in &responses {
+                let mut response_path = path.clone();
+                response_path.push(expr_hash(asset_certificate_expression(*encoding)));
+                response_path.push(Vec::new());
+                response_path.push(response_hash.to_vec());
+                cert_insert(&mut tree, &response_path);
             }
-        } else {
-            // Add new schedule
-            details_ref.mint_schedules.push(MintSchedule {
-                name: args.name,
-                bundle_prices: args.bundle_prices,
-                start_time: args.start_time,
-                end_time: args.end_time,
-                active: args.active.unwrap_or(false),
-                whitelist_only: args.whitelist_only.unwrap_or(false),
-            });
         }
     });
     
-    Ok(())
+    update_certified_data();
 }
 
-// Remove a mint schedule
-#[update]
-fn remove_mint_schedule(name: String) -> Result<(), String> {
-    let caller = caller();
-    
-    if !is_admin(caller) {
-        return Err("Unauthorized: Only admins can remove mint schedules".to_string());
-    }
-    
-    if name.is_empty() {
-        return Err("Schedule name cannot be empty".to_string());
+// Rebuild the heap-only certification tree, e.g. after an upgrade
+fn certify_all_assets() {
+    certify_metadata_routes();
+    for key in asset_keys() {
+        certify_asset(&key);
     }
-    
-    COLLECTION_DETAILS.with(|details| {
-        let mut details_ref = details.borrow_mut();
-        let initial_len = details_ref.mint_schedules.len();
-        
-        details_ref.mint_schedules.retain(|s| s.name != name);
-        
-        if details_ref.mint_schedules.len() == initial_len {
-            return Err(format!("No schedule with name '{}' found", name));
-        }
-        
-        Ok(())
-    });
-    
-    Ok(())
 }
 
-// Helper function to generate a UUID-like string
-fn generate_uuid() -> String {
-    let timestamp = ic_cdk::api::time();
-    let random = ic_cdk::api::call::arg_data_raw();
-    let mut hasher = std::collections::hash_map::DefaultHasher::new();
-    std::hash::Hash::hash(&(timestamp, random), &mut hasher);
-    let uuid = std::hash::Hasher::finish(&hasher);
-    format!("{:016x}", uuid)
+// IC-Certificate and IC-CertificateExpression headers for an asset, or one of its
+// encoded variants, served at url_path
+fn asset_certificate_headers(url_path: &[String], encoding: Option<ContentEncoding>) -> Vec<(String, String)> {
+    certificate_headers(asset_expr_path(url_path), asset_certificate_expression(encoding))
 }
 
-// Generate a new NFT
-fn mint_nft(owner: Principal, asset_id: String) -> Result<u64, String> {
-    // Generate a new token ID
-    let token_id = NFT_COUNTER.with(|counter| {
-        let mut counter = counter.borrow_mut();
-        counter.increment()
-    });
+// Certificate headers proving the http_expr entry at expr_path, whose expression is given
+fn certificate_headers(expr_path: Vec<String>, expression: &str) -> Vec<(String, String)> {
+    let certificate = match data_certificate() {
+        Some(certificate) => certificate,
+        None => return Vec::new(),
+    };
     
-    // Create a new token record
-    TOKENS.with(|tokens| {
-        let mut tokens = tokens.borrow_mut();
-        tokens.insert(token_id, owner);
-    });
+    let witness_path: Vec<Vec<u8>> = expr_path[1..].iter().map(|segment| segment.as_bytes().to_vec()).collect();
     
-    // Add token to owner's collection
-    OWNER_TOKENS.with(|owner_tokens| {
-        let mut owner_tokens = owner_tokens.borrow_mut();
-        let tokens = owner_tokens.entry(owner).or_insert_with(Vec::new);
-        tokens.push(token_id);
+    let tree = ASSET_CERT_TREE.with(|tree| {
+        let tree = tree.borrow();
+        let http = labeled(b"http_expr", cert_witness(&tree, &witness_path));
+        let witness = match with_tip_tree(|tip| tip.map(|tip| tip.reconstruct())) {
+            Some(tip_hash) => fork(http, HashTree::Pruned(tip_hash)),
+            None => http,
+        };
+        cbor_self_described(&witness)
     });
+    let expr_path = cbor_self_described(&expr_path);
     
-    // Add asset ID mapping if provided
-    if !asset_id.is_empty() {
-        TOKEN_ASSETS.with(|assets| {
-            let mut assets = assets.borrow_mut();
-            assets.insert(token_id, asset_id);
-        });
+    match (tree, expr_path) {
+        (Some(tree), Some(expr_path)) => vec![
+            ("IC-Certificate".to_string(), format!(
+                "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+                base64::encode(certificate), base64::encode(tree), base64::encode(expr_path)
+            )),
+            ("IC-CertificateExpression".to_string(), expression.to_string()),
+        ],
+        _ => Vec::new(),
     }
-    
-    Ok(token_id)
 }
 
-// Get the active mint price for a given quantity from the active schedules
-fn get_active_mint_price(quantity: u64, active_schedules: &[&MintSchedule]) -> Result<Option<Nat>, String> {
-    if active_schedules.is_empty() {
-        return Err("No active minting schedules available".to_string());
-    }
-    
-    // Choose the best price (lowest) from all active schedules
-    let mut best_price: Option<Nat> = None;
+fn cbor_self_described<T: Serialize>(value: &T) -> Option<Vec<u8>> {
+    let mut serializer = serde_cbor::ser::Serializer::new(Vec::new());
+    serializer.self_describe().ok()?;
+    value.serialize(&mut serializer).ok()?;
+    Some(serializer.into_inner())
+}
+
+// ==== SVG COMPOSITING ====
+//
+// Layered tokens reference their layers through /asset/<key> hrefs, which some
+// marketplaces never fetch. The composite inlines every referenced layer as a data
+// URI so the token renders from a single self-contained SVG.
+
+// Largest composite returned, keeps it well inside the query response limit
+const MAX_COMPOSITE_SVG_SIZE: usize = 2 * 1024 * 1024;
+// How deep SVG layers referencing further layers are inlined
+const MAX_COMPOSITE_DEPTH: usize = 4;
+
+// Self-contained SVG of a token with its layers inlined
+#[query]
+fn get_composite_svg(token_id: u64) -> Result<String, String> {
+    composite_svg(token_id)
+}
+
+fn composite_svg(token_id: u64) -> Result<String, String> {
+    let nft = NFTS.with(|nfts| nfts.borrow().get(&token_id))
+        .ok_or_else(|| format!("Token {} not found", token_id))?;
+    let base = TOKEN_ASSETS.with(|assets| assets.borrow().get(&token_id))
+        .and_then(|asset_key| load_asset(&asset_key.0))
+        .filter(|asset| asset.content_type == "image/svg+xml");
     
-    for schedule in active_schedules {
-        // Find the closest bundle size that is <= requested quantity
-        let closest_bundle = schedule.bundle_prices.iter()
-            .filter(|b| b.quantity <= quantity)
-            .max_by_key(|b| b.quantity);
-        
-        if let Some(bundle) = closest_bundle {
-            let bundle_count = (quantity + bundle.quantity - 1) / bundle.quantity; // Ceiling division
-            let total_price = bundle.price.clone() * Nat::from(bundle_count);
-            
-            // Update best price if this is better
-            if let Some(ref current_best) = best_price {
-                if total_price < *current_best {
-                    best_price = Some(total_price);
-                }
-            } else {
-                best_price = Some(total_price);
+    let svg = match (base, &nft.metadata.layers) {
+        (Some(asset), _) => {
+            let text = String::from_utf8(asset.data)
+                .map_err(|_| format!("Asset '{}' is not valid UTF-8", asset.key))?;
+            inline_svg_layers(&text, 0)?
+        }
+        // Layers set without an SVG to place them: stack them in order
+        (None, Some(layers)) if !layers.is_empty() => {
+            let mut svg = String::from("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"100%\" height=\"100%\">");
+            for key in layers {
+                svg.push_str(&format!("<image href=\"{}\" width=\"100%\" height=\"100%\"/>", layer_data_uri(key, 0)?));
+                check_composite_size(svg.len())?;
             }
+            svg.push_str("</svg>");
+            svg
         }
-    }
+        _ => return Err(format!("Token {} has no SVG or layers to compose", token_id)),
+    };
     
-    Ok(best_price)
+    check_composite_size(svg.len())?;
+    Ok(svg)
 }
 
-// Get active mint price based on user status and available schedules
-fn get_user_mint_price(user: Principal, quantity: u64) -> Result<Nat, String> {
-    let current_time = ic_cdk::api::time();
-    
-    COLLECTION_DETAILS.with(|details| {
-        let details = details.borrow();
-        
-        if !details.pricing_enabled {
-            return Err("Minting is not enabled".to_string());
-        }
-        
-        // Check if user is in whitelist
-        let user_in_whitelist = WHITELIST.with(|whitelist| {
-            whitelist.borrow().get(&user).copied().unwrap_or(false)
-        });
-        
-        // Find active schedules that match the user's status
-        let active_schedules: Vec<&MintSchedule> = details.mint_schedules.iter()
-            .filter(|s| s.active)
-            .filter(|s| {
-                // Check if time constraints are met
-                let time_valid = match (s.start_time, s.end_time) {
-                    (Some(start), Some(end)) => current_time >= start && current_time <= end,
-                    (Some(start), None) => current_time >= start,
-                    (None, Some(end)) => current_time <= end,
-                    (None, None) => true,
-                };
-                
-                // Check if user status matches the schedule
-                let status_matches = if s.whitelist_only {
-                    user_in_whitelist
-                } else {
-                    true // Non-whitelist schedules apply to everyone
-                };
-                
-                time_valid && status_matches
-            })
-            .collect();
+// Replace /asset/<key> hrefs (see svg_layer_keys) with data URIs of the assets
+fn inline_svg_layers(svg: &str, depth: usize) -> Result<String, String> {
+    let mut output = String::with_capacity(svg.len());
+    let mut rest = svg;
+    while let Some(start) = rest.find("href=\"") {
+        let value_start = start + "href=\"".len();
+        output.push_str(&rest[..value_start]);
+        rest = &rest[value_start..];
         
-        if active_schedules.is_empty() {
-            return Err("No active minting schedules available for this user".to_string());
+        let end = rest.find('"').unwrap_or(rest.len());
+        let href = &rest[..end];
+        let key = href.split('?').next().unwrap_or_default().strip_prefix("/asset/");
+        match key.filter(|key| !key.is_empty() && asset_exists(key)) {
+            Some(key) => output.push_str(&layer_data_uri(key, depth)?),
+            None => output.push_str(href),
         }
-        
-        // Get the price for this quantity from active schedules
-        get_active_mint_price(quantity, &active_schedules)?
-            .ok_or_else(|| format!("No price available for quantity {}", quantity))
-    })
+        rest = &rest[end..];
+        check_composite_size(output.len())?;
+    }
+    output.push_str(rest);
+    Ok(output)
 }
 
-// ==== ASSET MANAGEMENT FUNCTIONS ====
-
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-struct Asset {
-    key: String,
-    content_type: String,
-    data: Vec<u8>,
-    description: Option<String>,
-    uploaded_by: Principal,
-    created_at: u64,
-    modified_at: u64,
+// A layer asset as a data URI, with an SVG layer's own layers inlined first
+fn layer_data_uri(key: &str, depth: usize) -> Result<String, String> {
+    let asset = load_asset(key).ok_or_else(|| format!("Layer asset '{}' not found", key))?;
+    check_composite_size(asset.data.len() * 4 / 3)?;
+    
+    let data = match std::str::from_utf8(&asset.data) {
+        Ok(text) if asset.content_type == "image/svg+xml" && depth < MAX_COMPOSITE_DEPTH => {
+            inline_svg_layers(text, depth + 1)?.into_bytes()
+        }
+        _ => asset.data,
+    };
+    Ok(format!("data:{};base64,{}", asset.content_type, base64::encode(data)))
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-struct AssetMetadata {
-    key: String,
-    content_type: String,
-    size: usize,
-    created_at: u64,
-    modified_at: u64,
-    description: Option<String>,
-    uploaded_by: Principal,
+fn check_composite_size(size: usize) -> Result<(), String> {
+    if size > MAX_COMPOSITE_SVG_SIZE {
+        return Err(format!("Composite SVG exceeds {} bytes", MAX_COMPOSITE_SVG_SIZE));
+    }
+    Ok(())
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-struct UploadArgs {
-    key: Option<String>,          // Optional, will be generated if not provided
-    content_type: String,         // MIME type (e.g., "image/png")
-    data: Vec<u8>,               // Binary content
-    description: Option<String>,  // Optional description stored in metadata
+// ==== METADATA HTTP ROUTES ====
+//
+// JSON views of the collection and its tokens, so base_url can point straight at
+// the canister:
+//   /collection.json             collection metadata
+//   /token/{id}, /token/{id}.json  ERC-721 / OpenSea style token metadata
+//   /token/{id}/image            redirect to the token's linked asset or image_url
+//   /token/{id}/composite.svg    the token's SVG with its layers inlined
+// They are rendered from live state on every request, so they are certified with
+// a no_certification expression instead of by response hash.
+
+const SKIP_CERTIFICATE_EXPRESSION: &str = "default_certification(ValidationArgs{no_certification:Empty{}})";
+
+// http_expr paths covering the routes
+fn metadata_route_expr_paths() -> [Vec<String>; 2] {
+    [
+        vec!["http_expr".to_string(), "collection.json".to_string(), "<$>".to_string()],
+        vec!["http_expr".to_string(), "token".to_string(), "<*>".to_string()],
+    ]
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-struct DownloadResult {
-    data: Vec<u8>,
-    content_type: String,
-    metadata: AssetMetadata,
+// Keys an asset cannot take: their /<key> path would be shadowed by a metadata
+// route, or by the /asset/<key> path of another asset
+fn is_reserved_route_key(key: &str) -> bool {
+    key == "collection.json" || key.starts_with("token/") || key.starts_with("asset/")
 }
 
-// System functions for stable storage
-#[pre_upgrade]
-fn pre_upgrade() {
-    // Save all state to stable storage
-    TOKEN_ID_COUNTER.with(|counter| {
-        NFTS.with(|nfts| {
-            OWNER_TOKENS.with(|owner_tokens| {
-                WHITELIST.with(|whitelist| {
-                    ADMINS.with(|admins| {
-                        COLLECTION_DETAILS.with(|details| {
-                            ASSETS.with(|assets| {
-                                MINTED_ASSETS.with(|minted| {
-                                    TOKEN_APPROVALS.with(|token_approvals| {
-                                        COLLECTION_APPROVALS.with(|collection_approvals| {
-                                            TRANSACTIONS.with(|transactions| {
-                                                TRANSACTION_ID_COUNTER.with(|tx_counter| {
-                                                    ARCHIVES.with(|archives| {
-                                                        
-                                                        // Clone all the values
-                                                        let counter_ref = *counter.borrow();
-                                                        let nfts_ref = nfts.borrow().clone();
-                                                        let owner_tokens_ref = owner_tokens.borrow().clone();
-                                                        let whitelist_ref = whitelist.borrow().clone();
-                                                        let admins_ref = admins.borrow().clone();
-                                                        let details_ref = details.borrow().clone();
-                                                        let assets_ref = assets.borrow().clone();
-                                                        let minted_ref = minted.borrow().clone();
-                                                        let token_approvals_ref = token_approvals.borrow().clone();
-                                                        let collection_approvals_ref = collection_approvals.borrow().clone();
-                                                        let transactions_ref = transactions.borrow().clone();
-                                                        let tx_counter_ref = *tx_counter.borrow();
-                                                        let archives_ref = archives.borrow().clone();
-                                                        
-                                                        // Save everything to stable storage
-                                                        ic_cdk::storage::stable_save((
-                                                            counter_ref,
-                                                            nfts_ref,
-                                                            owner_tokens_ref,
-                                                            whitelist_ref,
-                                                            admins_ref,
-                                                            details_ref,
-                                                            assets_ref,
-                                                            minted_ref,
-                                                            token_approvals_ref,
-                                                            collection_approvals_ref,
-                                                            transactions_ref,
-                                                            tx_counter_ref,
-                                                            archives_ref,
-                                                        ))
-                                                        .unwrap();
-                                                        
-                                                        ic_cdk::println!("Pre-upgrade: Saved all state to stable storage");
-                                                    })
-                                                })
-                                            })
-                                        })
-                                    })
-                                })
-                            })
-                        })
-                    })
-                })
-            })
-        })
+fn certify_metadata_routes() {
+    let expr_hash = Sha256::digest(SKIP_CERTIFICATE_EXPRESSION.as_bytes()).to_vec();
+    ASSET_CERT_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        for expr_path in metadata_route_expr_paths() {
+            let mut path: Vec<Vec<u8>> = expr_path[1..].iter().map(|segment| segment.as_bytes().to_vec()).collect();
+            path.push(expr_hash.clone());
+            path.push(Vec::new());
+            path.push(Vec::new());
+            cert_insert(&mut tree, &path);
+        }
     });
+    update_certified_data();
 }
 
-#[post_upgrade]
-fn post_upgrade() {
-    // Try to restore full state (newest format with timeframes and pricing)
-    let full_restore_result = ic_cdk::storage::stable_restore::<(
-        u64, // TOKEN_ID_COUNTER
-        HashMap<u64, NFT>, // NFTS
-        HashMap<Principal, Vec<u64>>, // OWNER_TOKENS
-        HashMap<Principal, bool>, // WHITELIST
-        HashMap<Principal, AdminType>, // ADMINS
-        CollectionDetails, // COLLECTION_DETAILS with new fields
-        HashMap<String, Asset>, // ASSETS
-        HashMap<String, bool>, // MINTED_ASSETS
-        HashMap<u64, HashMap<Principal, ApprovalInfo>>, // TOKEN_APPROVALS
-        HashMap<Principal, HashMap<Principal, ApprovalInfo>>, // COLLECTION_APPROVALS
-        Vec<Transaction>, // TRANSACTIONS
-        u64, // TRANSACTION_ID_COUNTER
-        Vec<ArchiveInfo>, // ARCHIVES
-    )>();
-
-    if let Ok((token_id_counter, nfts, owner_tokens, whitelist, admins, collection_details, 
-               assets, minted_assets, token_approvals, collection_approvals, 
-               transactions, tx_counter, archives)) = full_restore_result {
-        
-        // Save stats before moving variables
-        let nfts_count = nfts.len();
-        let owners_count = owner_tokens.len();
-        let transactions_count = transactions.len();
-        
-        // Restore all data
-        TOKEN_ID_COUNTER.with(|c| {
-            *c.borrow_mut() = token_id_counter;
-        });
-        
-        NFTS.with(|n| {
-            *n.borrow_mut() = nfts;
-        });
-        
-        OWNER_TOKENS.with(|o| {
-            *o.borrow_mut() = owner_tokens;
-        });
-        
-        WHITELIST.with(|w| {
-            *w.borrow_mut() = whitelist;
-        });
-        
-        ADMINS.with(|a| {
-            *a.borrow_mut() = admins;
-        });
-        
-        COLLECTION_DETAILS.with(|c| {
-            *c.borrow_mut() = collection_details;
-        });
-        
-        ASSETS.with(|a| {
-            *a.borrow_mut() = assets;
-        });
-        
-        MINTED_ASSETS.with(|m| {
-            *m.borrow_mut() = minted_assets;
-        });
-        
-        TOKEN_APPROVALS.with(|t| {
-            *t.borrow_mut() = token_approvals;
-        });
-        
-        COLLECTION_APPROVALS.with(|c| {
-            *c.borrow_mut() = collection_approvals;
-        });
-        
-        TRANSACTIONS.with(|t| {
-            *t.borrow_mut() = transactions;
-        });
-        
-        TRANSACTION_ID_COUNTER.with(|c| {
-            *c.borrow_mut() = tx_counter;
-        });
-        
-        ARCHIVES.with(|a| {
-            *a.borrow_mut() = archives;
-        });
-        
-        ic_cdk::println!("Post-upgrade: Successfully restored all state");
-        ic_cdk::println!("Stats: {} NFTs, {} owners, {} transactions", 
-                         nfts_count, owners_count, transactions_count);
-        return;
-    }
-    
-    // Try to restore from previous format (with just assets, admins, minted_assets)
-    if let Ok((assets, admins, minted_assets)) = ic_cdk::storage::stable_restore::<(
-        HashMap<String, Asset>,
-        HashMap<Principal, AdminType>,
-        HashMap<String, bool>,
-    )>() {
-        // Restore the data we have
-        ASSETS.with(|a| {
-            *a.borrow_mut() = assets;
-        });
-        
-        ADMINS.with(|a| {
-            *a.borrow_mut() = admins;
-        });
-        
-        MINTED_ASSETS.with(|m| {
-            *m.borrow_mut() = minted_assets;
-        });
-        
-        ic_cdk::println!("Post-upgrade: Restored partial state (legacy format)");
-        ic_cdk::println!("IMPORTANT: Only assets, admins, and minted assets were restored. Other data initialized as empty.");
-        return;
-    }
-    
-    // Try backward compatibility - older version without minted assets tracking
-    if let Ok((assets, admins)) = ic_cdk::storage::stable_restore::<(
-        HashMap<String, Asset>,
-        HashMap<Principal, AdminType>,
-    )>() {
-        // Restore the data we have
-        ASSETS.with(|a| {
-            *a.borrow_mut() = assets;
-        });
-        
-        ADMINS.with(|a| {
-            *a.borrow_mut() = admins;
-        });
-        
-        ic_cdk::println!("Post-upgrade: Restored partial state (older legacy format)");
-        ic_cdk::println!("IMPORTANT: Only assets and admins were restored. Other data initialized as empty.");
-        return;
-    }
-    
-    // Handle oldest backward compatibility - old format had only assets
-    if let Ok((assets,)) = ic_cdk::storage::stable_restore::<(HashMap<String, Asset>,)>() {
-        // Restore the data we have
-        ASSETS.with(|a| {
-            *a.borrow_mut() = assets;
-        });
-        
-        ic_cdk::println!("Post-upgrade: Restored only assets (oldest legacy format)");
-        ic_cdk::println!("IMPORTANT: Only assets were restored. Other data initialized as empty.");
-        return;
-    }
+// Response for a metadata route, or None if the path is not one
+fn metadata_route(path: &str, mut headers: Vec<(String, String)>) -> Option<HttpResponse> {
+    let segments: Vec<&str> = path.split('/').collect();
+    let (status_code, body) = match segments.as_slice() {
+        ["collection.json"] => (200, collection_json()),
+        ["token", id] => {
+            let token_id: u64 = id.strip_suffix(".json").unwrap_or(id).parse().ok()?;
+            match NFTS.with(|nfts| nfts.borrow().get(&token_id)) {
+                Some(nft) => (200, token_json(&nft)),
+                None => (404, serde_json::json!({ "error": format!("Token {} not found", token_id) })),
+            }
+        }
+        ["token", id, "image"] => {
+            let token_id: u64 = id.parse().ok()?;
+            return Some(token_image_redirect(token_id, headers));
+        }
+        ["token", id, "composite.svg"] => {
+            let token_id: u64 = id.parse().ok()?;
+            return Some(composite_svg_response(token_id, path, headers));
+        }
+        _ => return None,
+    };
+    
+    let [collection_path, token_path] = metadata_route_expr_paths();
+    let expr_path = if segments[0] == "token" { token_path } else { collection_path };
+    let body = serde_json::to_vec(&body).unwrap_or_default();
+    headers.push(("Content-Type".to_string(), "application/json".to_string()));
+    headers.push(("Content-Length".to_string(), body.len().to_string()));
+    headers.push(("Cache-Control".to_string(), cache_control(path)));
+    headers.extend(certificate_headers(expr_path, SKIP_CERTIFICATE_EXPRESSION));
     
-    ic_cdk::println!("Post-upgrade: No data restored during upgrade. Initializing with empty state.");
+    Some(HttpResponse {
+        status_code,
+        headers,
+        body,
+        streaming_strategy: None,
+    })
 }
 
-// Helper function to get asset metadata
-fn get_asset_metadata(key: &str) -> Option<AssetMetadata> {
-    ASSETS.with(|assets| {
-        let assets_ref = assets.borrow();
-        let asset = assets_ref.get(key)?;
-        
-        Some(AssetMetadata {
-            key: key.to_string(),
-            content_type: asset.content_type.clone(),
-            size: asset.data.len(),
-            created_at: asset.created_at,
-            modified_at: asset.modified_at,
-            description: asset.description.clone(),
-            uploaded_by: asset.uploaded_by,
-        })
+fn collection_json() -> serde_json::Value {
+    let metadata = icrc7_collection_metadata();
+    let field = |key: &str| {
+        metadata.iter()
+            .find(|(name, _)| name == key)
+            .map_or(serde_json::Value::Null, |(_, value)| value_json(value))
+    };
+    
+    serde_json::json!({
+        "name": field("icrc7:name"),
+        "symbol": field("icrc7:symbol"),
+        "description": field("icrc7:description"),
+        "image": field("logo"),
+        "metadata": metadata.iter()
+            .map(|(key, value)| (key.clone(), value_json(value)))
+            .collect::<serde_json::Map<String, serde_json::Value>>(),
     })
 }
 
-// Upload a file (PNG or other) - admin only
-#[update]
-fn upload(args: UploadArgs) -> Result<String, String> {
-    let caller = caller();
+fn token_json(nft: &NFT) -> serde_json::Value {
+    let metadata = &nft.metadata;
+    let attributes = metadata.properties.as_ref()
+        .and_then(trait_attributes)
+        .map_or(serde_json::Value::Array(Vec::new()), |attributes| value_json(&attributes));
     
-    // Check if caller is an admin (either type)
-    if !is_admin(caller) {
-        return Err("Unauthorized: Only admins can upload assets".to_string());
+    let mut json = serde_json::json!({
+        "token_id": nft.token_id,
+        "name": metadata.name,
+        "description": metadata.description,
+        "image": metadata.image_url,
+        "attributes": attributes,
+    });
+    if let Some(content_url) = &metadata.content_url {
+        json["animation_url"] = serde_json::Value::String(content_url.clone());
     }
-    
-    // Generate key if not provided (default to png for backward compatibility)
-    let key = match args.key {
-        Some(key) => key,
-        None => {
-            let extension = if args.content_type == "image/png" { "png" } 
-                         else { args.content_type.split("/").last().unwrap_or("bin") };
-            format!("asset-{}.{}", time(), extension)
-        }
+    if let Some(layers) = &metadata.layers {
+        json["layers"] = serde_json::json!(layers);
+    }
+    json
+}
+
+fn composite_svg_response(token_id: u64, path: &str, mut headers: Vec<(String, String)>) -> HttpResponse {
+    let (status_code, content_type, body) = match composite_svg(token_id) {
+        Ok(svg) => (200, "image/svg+xml", svg.into_bytes()),
+        Err(error) => (404, "text/plain; charset=UTF-8", error.into_bytes()),
     };
     
+    let [_, token_path] = metadata_route_expr_paths();
+    headers.push(("Content-Type".to_string(), content_type.to_string()));
+    headers.push(("Content-Length".to_string(), body.len().to_string()));
+    headers.push(("Cache-Control".to_string(), cache_control(path)));
+    headers.extend(certificate_headers(token_path, SKIP_CERTIFICATE_EXPRESSION));
+    HttpResponse { status_code, headers, body, streaming_strategy: None }
+}
 
-    // Process SVG content if applicable
-    let processed_data = if args.content_type == "image/svg+xml" {
-        // For SVG files, always treat as hex-encoded and decode
-        match decode_hex(&args.data) {
-            Ok(decoded) => decoded,
-            Err(_) => {
-                // If decoding fails, use original data
-                // This provides a fallback for direct UTF-8 uploads
-                args.data.clone()
-            }
-        }
-    } else {
-        args.data.clone()
+// Point at the token's linked asset, falling back to its image_url
+fn token_image_redirect(token_id: u64, mut headers: Vec<(String, String)>) -> HttpResponse {
+    let asset_key = TOKEN_ASSETS.with(|assets| assets.borrow().get(&token_id));
+    let location = match asset_key {
+        Some(asset_key) => Some(format!("/asset/{}", asset_key.0)),
+        None => NFTS.with(|nfts| nfts.borrow().get(&token_id))
+            .map(|nft| nft.metadata.image_url)
+            .filter(|image_url| !image_url.is_empty()),
     };
     
-    // Get a copy of the SVG content as a string if possible
-    let content_as_string = if args.content_type == "image/svg+xml" {
-        match String::from_utf8(processed_data.clone()) {
-            Ok(text) => text,
-            Err(_) => format!("Invalid SVG content for: {}", key)
+    let [_, token_path] = metadata_route_expr_paths();
+    headers.extend(certificate_headers(token_path, SKIP_CERTIFICATE_EXPRESSION));
+    match location {
+        Some(location) => {
+            headers.push(("Location".to_string(), location));
+            headers.push(("Cache-Control".to_string(), "no-cache".to_string()));
+            HttpResponse { status_code: 307, headers, body: vec![], streaming_strategy: None }
         }
-    } else {
-        format!("Uploaded binary file with key: {}", key)
-    };
+        None => HttpResponse { status_code: 404, headers, body: vec![], streaming_strategy: None },
+    }
+}
+
+// ICRC-3 style Value as JSON: Nat and Int become numbers when they fit 64 bits
+// and decimal strings otherwise, blobs become hex strings
+fn value_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Nat(n) => u64::try_from(n.0.clone())
+            .map_or_else(|_| serde_json::Value::String(n.0.to_string()), serde_json::Value::from),
+        Value::Int(i) => i64::try_from(i.0.clone())
+            .map_or_else(|_| serde_json::Value::String(i.0.to_string()), serde_json::Value::from),
+        Value::Text(text) => serde_json::Value::String(text.clone()),
+        Value::Blob(bytes) => serde_json::Value::String(hex_string(bytes)),
+        Value::Map(entries) => serde_json::Value::Object(
+            entries.iter().map(|(key, value)| (key.clone(), value_json(value))).collect(),
+        ),
+        Value::Array(values) => serde_json::Value::Array(values.iter().map(value_json).collect()),
+    }
+}
+
+// ==== HTTP CACHING ====
+
+// Used for assets no cache policy prefix matches
+const DEFAULT_CACHE_MAX_AGE_SECONDS: u64 = 300;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct CachePolicy {
+    max_age_seconds: u64,
+    immutable: Option<bool>, // Content under the prefix never changes
+    no_cache: Option<bool>,  // Always revalidate with the ETag, ignores max_age_seconds
+}
+
+candid_storable!(CachePolicy, 256);
+
+impl CachePolicy {
+    fn cache_control(&self) -> String {
+        if self.no_cache.unwrap_or(false) {
+            return "no-cache".to_string();
+        }
+        let mut value = format!("public, max-age={}", self.max_age_seconds);
+        if self.immutable.unwrap_or(false) {
+            value.push_str(", immutable");
+        }
+        value
+    }
+}
+
+// Set or clear (None) the cache policy for asset keys starting with a prefix - admin only.
+// The longest matching prefix wins; the empty prefix applies to every asset.
+#[update]
+fn set_cache_policy(prefix: String, policy: Option<CachePolicy>) -> Result<(), String> {
+    let caller = caller();
     
-    // Create the asset with processed data
-    let asset = Asset {
-        key: key.clone(),
-        content_type: args.content_type,
-        data: processed_data,  // Use the processed data (decoded if needed)
-        description: args.description,
-        uploaded_by: caller,
-        created_at: time(),
-        modified_at: time(),
-    };
+    if !is_admin(caller) {
+        return Err("Unauthorized: Only admins can set cache policies".to_string());
+    }
+    if prefix.len() > MAX_ASSET_KEY_LENGTH {
+        return Err(format!("Prefix must be at most {} bytes", MAX_ASSET_KEY_LENGTH));
+    }
     
-    // Store the asset
-    ASSETS.with(|assets| {
-        assets.borrow_mut().insert(key.clone(), asset);
+    CACHE_POLICIES.with(|policies| {
+        let mut policies = policies.borrow_mut();
+        match policy {
+            Some(policy) => policies.insert(AssetKey(prefix), policy),
+            None => policies.remove(&AssetKey(prefix)),
+        };
     });
-    
-    // Record the upload in the transaction log
-    let _transaction_id = record_transaction("upload", 0, caller, ic_cdk::api::id(), 
-                                           None, format!("upload_file:{}", key));
-    
-    // Return the content as a string
-    Ok(content_as_string)
+    Ok(())
 }
 
-// Download a file 
 #[query]
-fn download(key: String) -> Result<DownloadResult, String> {
-    // No need to check caller for downloads
+fn list_cache_policies() -> Vec<(String, CachePolicy)> {
+    CACHE_POLICIES.with(|policies| {
+        policies.borrow().iter().map(|(prefix, policy)| (prefix.0, policy)).collect()
+    })
+}
 
-    // Retrieve the file
-    ASSETS.with(|assets| {
-        let assets_ref = assets.borrow();
-        let asset = assets_ref.get(&key)
-            .ok_or_else(|| format!("Asset with key '{}' not found", key))?;
-        
-        // Get metadata
-        let metadata = get_asset_metadata(&key)
-            .ok_or_else(|| "Failed to get asset metadata".to_string())?;
-        
-        // Check if it's an SVG file - if so, return it as text content
-        if asset.content_type == "image/svg+xml" {
-            // For SVG, first check if the data is hex-encoded
-            let svg_data = if is_hex_encoded(&asset.data) {
-                // Decode the hex content to get the raw binary
-                match decode_hex(&asset.data) {
-                    Ok(decoded) => decoded,
-                    Err(_) => return Err("Failed to decode hex-encoded SVG content".to_string()),
-                }
-            } else {
-                // Not hex-encoded, use as is
-                asset.data.clone()
-            };
-            
-            // Now convert the binary data to UTF-8 text
-            let svg_text = match String::from_utf8(svg_data.clone()) {
-                Ok(text) => text,
-                Err(_) => return Err("Failed to decode SVG content as UTF-8 text".to_string()),
-            };
-            
-            // Return the SVG content directly as a string
-            return Ok(DownloadResult {
-                data: svg_text.into_bytes(), // Still need to convert to bytes for the Result type
-                content_type: asset.content_type.clone(),
-                metadata,
-            });
+fn cache_control(key: &str) -> String {
+    let policy = CACHE_POLICIES.with(|policies| {
+        let policies = policies.borrow();
+        (0..=key.len()).rev()
+            .filter(|end| key.is_char_boundary(*end))
+            .find_map(|end| policies.get(&AssetKey::new(&key[..end])))
+    });
+    match policy {
+        Some(policy) => policy.cache_control(),
+        None => format!("public, max-age={}", DEFAULT_CACHE_MAX_AGE_SECONDS),
+    }
+}
+
+// Strong ETag derived from the content hash
+fn asset_etag(metadata: &AssetMetadata) -> String {
+    format!("\"{}\"", hex_string(&asset_body_sha256(metadata)))
+}
+
+fn request_header<'a>(request: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    request.headers.iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+// If-None-Match matches when it lists the ETag (weakly compared) or is "*"
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+// Byte range requested by a single-range `Range: bytes=...` header, as inclusive
+// bounds. None for headers we don't handle (they get the full body), Err if the
+// range lies outside the asset.
+fn parse_range(header: &str, size: usize) -> Option<Result<(usize, usize), ()>> {
+    let spec = header.strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: usize = suffix.parse().ok()?;
+            if suffix == 0 || size == 0 {
+                return Some(Err(()));
+            }
+            (size.saturating_sub(suffix), size - 1)
         }
-        
-        // For non-SVG files, return binary data as before
-        Ok(DownloadResult {
-            data: asset.data.clone(),
-            content_type: asset.content_type.clone(),
-            metadata,
-        })
-    })
+        (start, "") => (start.parse().ok()?, size.saturating_sub(1)),
+        (start, end) => (start.parse().ok()?, end.parse::<usize>().ok()?.min(size.saturating_sub(1))),
+    };
+    if start >= size || start > end {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end)))
 }
 
-// List all assets - admin only
-#[query]
-fn list_assets() -> Result<Vec<AssetMetadata>, String> {
-    // We don't need caller for this query function
+// Bytes start..=end of a stored asset, read from the chunks that hold them
+fn read_asset_range(key: &str, start: usize, end: usize) -> Vec<u8> {
+    let asset_key = AssetKey::new(key);
+    let first = (start / ASSET_CHUNK_SIZE) as u32;
+    let last = (end / ASSET_CHUNK_SIZE) as u32;
     
-    // Get all asset metadata
-    ASSETS.with(|assets| {
-        let assets_ref = assets.borrow();
-        
-        Ok(assets_ref.keys()
-            .filter_map(|key| get_asset_metadata(key))
-            .collect())
-    })
+    let mut data: Vec<u8> = ASSET_CHUNKS.with(|chunks| {
+        chunks.borrow()
+            .range((asset_key.clone(), first)..=(asset_key.clone(), last))
+            .flat_map(|(_, chunk)| chunk.0)
+            .collect()
+    });
+    let offset = start - first as usize * ASSET_CHUNK_SIZE;
+    data.truncate(offset + end - start + 1);
+    data.drain(..offset);
+    data
 }
 
-#[query]
-fn get_asset_info(key: String) -> Option<AssetMetadata> {
-    get_asset_metadata(&key)
+// 206 for a satisfiable range, 416 otherwise. Partial responses are capped at
+// STREAMING_CHUNK_SIZE; clients continue from the Content-Range they get back.
+fn range_response(metadata: &AssetMetadata, range: Result<(usize, usize), ()>, mut headers: Vec<(String, String)>) -> HttpResponse {
+    let (start, end) = match range {
+        Ok((start, end)) => (start, end.min(start + STREAMING_CHUNK_SIZE - 1)),
+        Err(()) => {
+            headers.push(("Content-Range".to_string(), format!("bytes */{}", metadata.size)));
+            return HttpResponse {
+                status_code: 416,
+                headers,
+                body: vec![],
+                streaming_strategy: None,
+            };
+        }
+    };
+    
+    let body = read_asset_range(&metadata.key, start, end);
+    headers.push(("Content-Type".to_string(), metadata.content_type.clone()));
+    headers.push(("Content-Range".to_string(), format!("bytes {}-{}/{}", start, end, metadata.size)));
+    headers.push(("Content-Length".to_string(), body.len().to_string()));
+    HttpResponse {
+        status_code: 206,
+        headers,
+        body,
+        streaming_strategy: None,
+    }
+}
+
+// RFC 7231 IMF-fixdate, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+fn http_date(nanos: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    
+    let secs = nanos / 1_000_000_000;
+    let days = secs / 86_400;
+    
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize], day, MONTHS[(month - 1) as usize], year,
+        secs % 86_400 / 3_600, secs % 3_600 / 60, secs % 60
+    )
 }
 
 // Enhanced HTTP handler for asset serving and downloading with /asset/ path pattern
@@ -2173,7 +6113,7 @@ fn http_request(request: HttpRequest) -> HttpResponse {
     let mut cors_headers = vec![
         ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
         ("Access-Control-Allow-Methods".to_string(), "GET, OPTIONS".to_string()),
-        ("Access-Control-Allow-Headers".to_string(), "Content-Type".to_string()),
+        ("Access-Control-Allow-Headers".to_string(), "Content-Type, Range, If-None-Match".to_string()),
     ];
     
     // Handle OPTIONS requests for CORS preflight
@@ -2186,77 +6126,67 @@ fn http_request(request: HttpRequest) -> HttpResponse {
         };
     }
     
+    // Path segments the response is certified under
+    let url_path: Vec<String> = clean_path.split('/').map(str::to_string).collect();
+    
+    if let Some(response) = metadata_route(clean_path, cors_headers.clone()) {
+        return response;
+    }
+    
+    // Validators and caching headers go on every asset response. Only full 200
+    // responses are certified, so clients that verify certificates (the HTTP
+    // gateway asks for version 2) never get a 304 or 206 and Range and
+    // If-None-Match are ignored for them.
+    if let Some(metadata) = asset_metadata(key) {
+        let verifies = request.certificate_version.is_some_and(|version| version >= 2);
+        
+        // Ranges address the identity bytes, so partial responses are never encoded
+        let range = request_header(&request, "Range")
+            .filter(|_| !verifies)
+            .and_then(|value| parse_range(value, metadata.size));
+        let variant = match range {
+            Some(_) => None,
+            None => request_header(&request, "Accept-Encoding").and_then(|value| negotiate_encoding(value, &metadata)),
+        };
+        
+        let etag = match variant {
+            Some(variant) => format!("\"{}\"", hex_string(&variant.sha256)),
+            None => asset_etag(&metadata),
+        };
+        cors_headers.push(("ETag".to_string(), etag.clone()));
+        cors_headers.push(("Cache-Control".to_string(), cache_control(key)));
+        cors_headers.push(("Last-Modified".to_string(), http_date(metadata.modified_at)));
+        if !verifies {
+            cors_headers.push(("Accept-Ranges".to_string(), "bytes".to_string()));
+        }
+        cors_headers.push(("Vary".to_string(), "Accept-Encoding".to_string()));
+        
+        if !verifies && request_header(&request, "If-None-Match").is_some_and(|value| etag_matches(value, &etag)) {
+            return HttpResponse {
+                status_code: 304,
+                headers: cors_headers,
+                body: vec![],
+                streaming_strategy: None,
+            };
+        }
+        
+        if let Some(range) = range {
+            return range_response(&metadata, range, cors_headers);
+        }
+        
+        // Encoded variants and large assets are served from their chunks as stored,
+        // without reassembling them in one response
+        if variant.is_some() || metadata.size > STREAMING_CHUNK_SIZE {
+            cors_headers.extend(asset_certificate_headers(&url_path, variant.map(|variant| variant.encoding)));
+            return stream_asset(&metadata, variant, cors_headers, is_download);
+        }
+    }
+    
     // Try to get the asset
-    match ASSETS.with(|assets| assets.borrow().get(key).cloned()) {
+    match load_asset(key) {
         Some(asset) => {
-            // Check if the asset requires decoding (SVG or PNG)
-            let needs_decoding = asset.content_type == "image/svg+xml" || 
-                                 asset.content_type == "image/png";
-                
-            if needs_decoding {
-                // For files requiring decoding, try multiple approaches
-                let decoded_data = if is_base64(&asset.data) {
-                    // Try to decode as base64
-                    match decode_base64(&asset.data) {
-                        Ok(decoded) => decoded,
-                        Err(_) => asset.data.clone(), // Fallback to original data if decoding fails
-                    }
-                } else {
-                    // Try hex decoding as fallback
-                    match decode_hex(&asset.data) {
-                        Ok(decoded) => decoded,
-                        Err(_) => asset.data.clone(), // Fallback to original data if decoding fails
-                    }
-                };
-                
-                // For SVG files, we need to convert to text
-                if asset.content_type == "image/svg+xml" {
-                    // Now convert the processed binary data to UTF-8 text
-                    let svg_content = match String::from_utf8(decoded_data) {
-                        Ok(text) => text,
-                        Err(_) => "<svg>Error: Could not decode SVG content</svg>".to_string(),
-                    };
-                    
-                    // Set content type to SVG
-                    cors_headers.push(("Content-Type".to_string(), "image/svg+xml; charset=UTF-8".to_string()));
-                    
-                    // Add content disposition header for downloads
-                    if is_download {
-                        cors_headers.push(("Content-Disposition".to_string(), 
-                                         format!("attachment; filename=\"{}\"", key)));
-                    }
-                    
-                    return HttpResponse {
-                        status_code: 200,
-                        headers: cors_headers,
-                        body: svg_content.into_bytes(),
-                        streaming_strategy: None,
-                    };
-                } else {
-                    // For PNG and other binary files that need decoding
-                    cors_headers.push(("Content-Type".to_string(), asset.content_type.clone()));
-                    
-                    // Add content disposition header for downloads
-                    if is_download {
-                        cors_headers.push(("Content-Disposition".to_string(), 
-                                         format!("attachment; filename=\"{}\"", key)));
-                    }
-                    
-                    // Add content length header
-                    cors_headers.push(("Content-Length".to_string(), 
-                                     decoded_data.len().to_string()));
-                    
-                    return HttpResponse {
-                        status_code: 200,
-                        headers: cors_headers,
-                        body: decoded_data,
-                        streaming_strategy: None,
-                    };
-                }
-            }
-            
-            // For non-SVG files, set the proper content type
-            cors_headers.push(("Content-Type".to_string(), asset.content_type.clone()));
+            let body = asset.data;
+            cors_headers.push(("Content-Type".to_string(), asset.content_type));
             
             // Add content disposition header for downloads
             if is_download {
@@ -2265,14 +6195,13 @@ fn http_request(request: HttpRequest) -> HttpResponse {
             }
             
             // Add content length header
-            cors_headers.push(("Content-Length".to_string(), 
-                             asset.data.len().to_string()));
+            cors_headers.push(("Content-Length".to_string(), body.len().to_string()));
+            cors_headers.extend(asset_certificate_headers(&url_path, None));
             
-            // For other file types, return as binary data
             HttpResponse {
                 status_code: 200,
                 headers: cors_headers,
-                body: asset.data,
+                body,
                 streaming_strategy: None,
             }
         },
@@ -2319,7 +6248,7 @@ struct HttpRequest {
     certificate_version: Option<u16>,
 }
 
-#[derive(Clone, Debug, CandidType, Serialize)]
+#[derive(Clone, Debug, CandidType)]
 struct HttpResponse {
     status_code: u16,
     headers: Vec<(String, String)>,
@@ -2327,21 +6256,423 @@ struct HttpResponse {
     streaming_strategy: Option<StreamingStrategy>,
 }
 
-#[derive(Clone, Debug, CandidType, Serialize)]
+#[derive(Clone, Debug, CandidType)]
 enum StreamingStrategy {
-    Callback { callback: StreamingCallback, token: StreamingCallbackToken },
-}
-
-#[derive(Clone, Debug, CandidType, Serialize)]
-struct StreamingCallback {
-    function: [u8; 16], // Function ID
-    token: StreamingCallbackToken,
+    Callback { callback: Func, token: StreamingCallbackToken },
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
 struct StreamingCallbackToken {
     key: String,
     content_encoding: String,
-    index: usize,
-    sha256: Option<[u8; 32]>,
+    index: Nat,              // STREAMING_CHUNK_SIZE piece to send next
+    sha256: Option<Vec<u8>>, // Content hash the stream started with
+}
+#[derive(Clone, Debug, CandidType)]
+struct StreamingCallbackHttpResponse {
+    body: Vec<u8>,
+    token: Option<StreamingCallbackToken>,
+}
+
+// Serve the remaining pieces of a streamed asset
+#[query]
+fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    let metadata = asset_metadata(&token.key)
+        .unwrap_or_else(|| ic_cdk::trap(&format!("Asset '{}' not found", token.key)));
+    let variant = ContentEncoding::from_name(&token.content_encoding).map(|encoding| {
+        metadata.encoded(encoding)
+            .unwrap_or_else(|| ic_cdk::trap(&format!("Asset '{}' has no {} variant", token.key, token.content_encoding)))
+    });
+    
+    // Pieces of a replaced asset must not be mixed into an ongoing response
+    let sha256 = variant.map_or(metadata.sha256.clone(), |variant| Some(variant.sha256.clone()));
+    if token.sha256.is_some() && token.sha256 != sha256 {
+        ic_cdk::trap(&format!("Asset '{}' changed while streaming", token.key));
+    }
+    
+    let index: usize = token.index.0.clone().try_into()
+        .unwrap_or_else(|_| ic_cdk::trap("Invalid streaming index"));
+    
+    StreamingCallbackHttpResponse {
+        body: streaming_chunk(&token.key, variant.map(|variant| variant.encoding), index),
+        token: streaming_token(&metadata, variant, index + 1),
+    }
+}
+
+// Serve an asset, or one of its encoded variants, from the stored chunks. Anything
+// past the first STREAMING_CHUNK_SIZE bytes is streamed.
+fn stream_asset(metadata: &AssetMetadata, variant: Option<&AssetEncoding>, mut headers: Vec<(String, String)>, is_download: bool) -> HttpResponse {
+    headers.push(("Content-Type".to_string(), metadata.content_type.clone()));
+    if let Some(variant) = variant {
+        headers.push(("Content-Encoding".to_string(), variant.encoding.name().to_string()));
+    }
+    if is_download {
+        headers.push(("Content-Disposition".to_string(),
+                      format!("attachment; filename=\"{}\"", metadata.key)));
+    }
+    let size = variant.map_or(metadata.size, |variant| variant.size);
+    headers.push(("Content-Length".to_string(), size.to_string()));
+    
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body: streaming_chunk(&metadata.key, variant.map(|variant| variant.encoding), 0),
+        streaming_strategy: streaming_token(metadata, variant, 1).map(|token| StreamingStrategy::Callback {
+            callback: Func {
+                principal: canister_id(),
+                method: "http_request_streaming_callback".to_string(),
+            },
+            token,
+        }),
+    }
+}
+
+// Token for the given piece, or None once the whole representation has been sent
+fn streaming_token(metadata: &AssetMetadata, variant: Option<&AssetEncoding>, index: usize) -> Option<StreamingCallbackToken> {
+    let size = variant.map_or(metadata.size, |variant| variant.size);
+    (index * STREAMING_CHUNK_SIZE < size).then(|| StreamingCallbackToken {
+        key: metadata.key.clone(),
+        content_encoding: variant.map_or("identity", |variant| variant.encoding.name()).to_string(),
+        index: Nat::from(index),
+        sha256: variant.map_or(metadata.sha256.clone(), |variant| Some(variant.sha256.clone())),
+    })
+}
+
+// Bytes of the index-th STREAMING_CHUNK_SIZE piece of an asset or encoded variant
+fn streaming_chunk(key: &str, encoding: Option<ContentEncoding>, index: usize) -> Vec<u8> {
+    let pieces_per_chunk = (STREAMING_CHUNK_SIZE / ASSET_CHUNK_SIZE) as u32;
+    let first = index as u32 * pieces_per_chunk;
+    let asset_key = AssetKey::new(key);
+    
+    match encoding {
+        Some(encoding) => {
+            let chunk_key = (asset_key, encoding.id());
+            ENCODED_CHUNKS.with(|chunks| {
+                chunks.borrow()
+                    .range((chunk_key.clone(), first)..(chunk_key.clone(), first + pieces_per_chunk))
+                    .flat_map(|(_, chunk)| chunk.0)
+                    .collect()
+            })
+        }
+        None => ASSET_CHUNKS.with(|chunks| {
+            chunks.borrow()
+                .range((asset_key.clone(), first)..(asset_key.clone(), first + pieces_per_chunk))
+                .flat_map(|(_, chunk)| chunk.0)
+                .collect()
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // Stand-ins for the system API: a fixed clock, caller and canister id, and
+    // no certificate
+    pub(super) mod system {
+        use candid::Principal;
+        
+        pub const NOW: u64 = 1_700_000_000_000_000_000;
+        
+        pub fn caller() -> Principal {
+            Principal::anonymous()
+        }
+        
+        pub fn canister_id() -> Principal {
+            Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1])
+        }
+        
+        pub fn time() -> u64 {
+            NOW
+        }
+        
+        pub fn instruction_counter() -> u64 {
+            0
+        }
+        
+        pub fn set_certified_data(_data: &[u8]) {}
+        
+        pub fn data_certificate() -> Option<Vec<u8>> {
+            None
+        }
+    }
+    
+    fn hex(hash: Hash) -> String {
+        hex_string(&hash)
+    }
+    
+    // Test vectors from the ICRC-3 specification
+    #[test]
+    fn hash_value_matches_icrc3_vectors() {
+        assert_eq!(hex(hash_value(&Value::Nat(Nat::from(42u8)))),
+                   "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1");
+        assert_eq!(hex(hash_value(&Value::Int(Int::from(-42)))),
+                   "de5a6f78116eca62d7fc5ce159d23ae6b889b365a1739ad2cf36f925a140d0cc");
+        assert_eq!(hex(hash_value(&Value::Text("Hello, World!".to_string()))),
+                   "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f");
+        assert_eq!(hex(hash_value(&Value::Blob(vec![1, 2, 3, 4]))),
+                   "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a");
+        assert_eq!(hex(hash_value(&Value::Array(vec![
+                       Value::Nat(Nat::from(3u8)),
+                       Value::Text("foo".to_string()),
+                       Value::Blob(vec![5, 6]),
+                   ]))),
+                   "514a04011caa503990d446b7dec5d79e19c221ae607fb08b2848c67734d468d6");
+        assert_eq!(hex(hash_value(&Value::Map(vec![
+                       ("from".to_string(), Value::Blob(vec![
+                           0x00, 0xab, 0xcd, 0xef, 0x00, 0x12, 0x34, 0x00, 0x56, 0x78, 0x9a, 0x00, 0xbc,
+                           0xde, 0xf0, 0x00, 0x01, 0x23, 0x45, 0x67, 0x89, 0x00, 0xab, 0xcd, 0xef, 0x01,
+                       ])),
+                       ("to".to_string(), Value::Blob(vec![
+                           0x00, 0xab, 0x0d, 0xef, 0x00, 0x12, 0x34, 0x00, 0x56, 0x78, 0x9a, 0x00, 0xbc,
+                           0xde, 0xf0, 0x00, 0x01, 0x23, 0x45, 0x67, 0x89, 0x00, 0xab, 0xcd, 0xef, 0x01,
+                       ])),
+                       ("amount".to_string(), Value::Nat(Nat::from(42u8))),
+                       ("created_at".to_string(), Value::Nat(Nat::from(1699218263u64))),
+                       ("memo".to_string(), Value::Nat(Nat::from(0u8))),
+                   ]))),
+                   "c56ece650e1de4269c5bdeff7875949e3e2033f85b2d193c2ff4f7f78bdcfc75");
+    }
+    
+    #[test]
+    fn hash_value_uses_leb128_for_numbers() {
+        // 624485 = e5 8e 26, -123456 = c0 bb 78
+        assert_eq!(hash_value(&Value::Nat(Nat::from(624485u32))), <Hash>::from(Sha256::digest([0xe5, 0x8e, 0x26])));
+        assert_eq!(hash_value(&Value::Int(Int::from(-123456))), <Hash>::from(Sha256::digest([0xc0, 0xbb, 0x78])));
+        assert_eq!(leb128_u64(624485), vec![0xe5, 0x8e, 0x26]);
+    }
+    
+    #[test]
+    fn legacy_canonical_bytes_decodes_only_matching_images() {
+        let jpeg = vec![0xff, 0xd8, 0xff, 0xe0, 0x00, 0x10];
+        assert_eq!(legacy_canonical_bytes("image/jpeg", base64::encode(&jpeg).as_bytes()), Some(jpeg.clone()));
+        assert_eq!(legacy_canonical_bytes("image/jpeg", hex_string(&jpeg).as_bytes()), Some(jpeg.clone()));
+        assert_eq!(legacy_canonical_bytes("image/jpeg", &jpeg), None);
+        assert_eq!(legacy_canonical_bytes("image/svg+xml", b"<svg/>"), None);
+        assert_eq!(legacy_canonical_bytes("image/svg+xml", base64::encode("<svg/>").as_bytes()), Some(b"<svg/>".to_vec()));
+        // Text that decodes to something else stays as it is
+        assert_eq!(legacy_canonical_bytes("image/png", b"deadbeef"), None);
+        assert_eq!(legacy_canonical_bytes("text/plain", base64::encode("hello").as_bytes()), None);
+    }
+    
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR";
+    
+    fn test_asset(key: &str, content_type: &str, data: &[u8]) -> Asset {
+        Asset {
+            key: key.to_string(),
+            content_type: content_type.to_string(),
+            data: data.to_vec(),
+            description: None,
+            uploaded_by: Principal::anonymous(),
+            created_at: system::NOW,
+            modified_at: system::NOW,
+        }
+    }
+    
+    fn v5_snapshot(assets: Vec<Asset>) -> FullState {
+        (
+            0,
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+            COLLECTION_DETAILS.with(|c| c.borrow().clone()),
+            assets.into_iter().map(|asset| (asset.key.clone(), asset)).collect(),
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+            Vec::new(),
+            0,
+            Vec::new(),
+            None,
+            None,
+            None,
+        )
+    }
+    
+    #[test]
+    fn legacy_snapshot_migration_decodes_encoded_images() {
+        migrate_legacy_state(LegacyState::AccountKeyed(v5_snapshot(vec![
+            test_asset("logo.png", "image/png", base64::encode(PNG).as_bytes()),
+            test_asset("notes.txt", "text/plain", base64::encode("hello").as_bytes()),
+        ])));
+        
+        assert_eq!(load_asset("logo.png").unwrap().data, PNG);
+        let metadata = asset_metadata("logo.png").unwrap();
+        assert_eq!(metadata.size, PNG.len());
+        assert_eq!(metadata.sha256, Some(Sha256::digest(PNG).to_vec()));
+        assert_eq!(load_asset("notes.txt").unwrap().data, base64::encode("hello").as_bytes());
+        
+        // The follow-up migration leaves the repaired asset alone
+        migrate_v6_to_v7();
+        assert_eq!(load_asset("logo.png").unwrap().data, PNG);
+        assert_eq!(asset_metadata("logo.png").unwrap().version, metadata.version);
+    }
+    
+    #[test]
+    fn legacy_migration_rekeys_principal_keyed_state() {
+        let owner = Principal::from_slice(&[7]);
+        let spender = Principal::from_slice(&[8]);
+        let admin = Principal::from_slice(&[9]);
+        let nft = NFT {
+            token_id: 1,
+            owner,
+            owner_subaccount: None,
+            metadata: NFTMetadata {
+                name: "Token #1".to_string(),
+                description: String::new(),
+                image_url: "https://example.com/a.png".to_string(),
+                content_url: None,
+                content_type: None,
+                properties: None,
+                is_layered: false,
+                svg_id: None,
+                layers: None,
+            },
+            created_at: system::NOW,
+            transfer_history: Vec::new(),
+        };
+        let approval = ApprovalInfo { spender, token_id: 1, expires_at: None, created_at: system::NOW, memo: None };
+        let assets = HashMap::from([("a.png".to_string(), test_asset("a.png", "image/png", PNG))]);
+        
+        migrate_legacy_state(LegacyState::PrincipalKeyed((
+            1,
+            HashMap::from([(1, nft)]),
+            HashMap::from([(owner, vec![1])]),
+            HashMap::new(),
+            HashMap::from([(admin, AdminType::System)]),
+            COLLECTION_DETAILS.with(|c| c.borrow().clone()),
+            assets,
+            HashMap::from([("a.png".to_string(), true)]),
+            HashMap::from([(1, HashMap::from([(spender, approval)]))]),
+            HashMap::new(),
+            Vec::new(),
+            0,
+            Vec::new(),
+        )));
+        
+        assert_eq!(TOKEN_ID_COUNTER.with(|counter| *counter.borrow()), 1);
+        assert!(NFTS.with(|nfts| nfts.borrow().contains_key(&1)));
+        assert!(OWNER_TOKENS.with(|tokens| tokens.borrow().contains_key(&(default_account(owner), 1))));
+        assert!(TOKEN_APPROVALS.with(|approvals| approvals.borrow().contains_key(&(1, default_account(spender)))));
+        assert!(is_system_admin(admin));
+        assert!(is_asset_minted("a.png"));
+        assert_eq!(load_asset("a.png").unwrap().data, PNG);
+    }
+    
+    #[test]
+    fn legacy_migration_starts_the_oldest_layout_from_defaults() {
+        let assets = HashMap::from([("a.png".to_string(), test_asset("a.png", "image/png", PNG))]);
+        migrate_legacy_state(LegacyState::AssetsOnly(assets));
+        
+        assert_eq!(TOKEN_ID_COUNTER.with(|counter| *counter.borrow()), 0);
+        assert_eq!(NFTS.with(|nfts| nfts.borrow().len()), 0);
+        assert!(ADMINS.with(|admins| admins.borrow().is_empty()));
+        assert!(!is_asset_minted("a.png"));
+        assert_eq!(load_asset("a.png").unwrap().data, PNG);
+    }
+    
+    #[test]
+    fn v7_migration_decodes_images_the_v6_layout_hashed() {
+        store_asset(test_asset("logo.png", "image/png", hex_string(PNG).as_bytes()));
+        store_asset(test_asset("plain.png", "image/png", PNG));
+        migrate_v6_to_v7();
+        
+        assert_eq!(load_asset("logo.png").unwrap().data, PNG);
+        assert_eq!(asset_metadata("logo.png").unwrap().sha256, Some(Sha256::digest(PNG).to_vec()));
+        assert_eq!(load_asset("plain.png").unwrap().data, PNG);
+    }
+    
+    #[test]
+    fn mint_reserved_assets_stores_nothing_when_one_token_is_too_large() {
+        let owner = Principal::from_slice(&[7]);
+        let layers: String = (0..2000)
+            .map(|i| format!("<image href=\"/asset/layer-{:05}.png\"/>", i))
+            .collect();
+        store_asset(test_asset("small.png", "image/png", PNG));
+        store_asset(test_asset("huge.svg", "image/svg+xml", format!("<svg>{}</svg>", layers).as_bytes()));
+        
+        let assets = ["small.png".to_string(), "huge.svg".to_string()];
+        assert!(mint_reserved_assets(owner, &assets).is_err());
+        assert_eq!(TOKEN_ID_COUNTER.with(|counter| *counter.borrow()), 0);
+        assert_eq!(NFTS.with(|nfts| nfts.borrow().len()), 0);
+        assert_eq!(OWNER_TOKENS.with(|tokens| tokens.borrow().len()), 0);
+        assert!(!is_asset_minted("small.png"));
+        assert_eq!(BLOCKS.with(|blocks| blocks.borrow().len()), 0);
+        
+        assert_eq!(mint_reserved_assets(owner, &assets[..1]), Ok(vec![1]));
+        assert!(is_asset_minted("small.png"));
+    }
+    
+    #[test]
+    fn atomic_batch_commits_nothing_when_one_item_fails() {
+        let owner = Principal::from_slice(&[7]);
+        let recipient = Account { owner: Principal::from_slice(&[8]), subaccount: None };
+        store_asset(test_asset("a.png", "image/png", PNG));
+        store_asset(test_asset("b.png", "image/png", PNG));
+        mint_reserved_assets(owner, &["a.png".to_string(), "b.png".to_string()]).unwrap();
+        let minted_blocks = BLOCKS.with(|blocks| blocks.borrow().len());
+        
+        let transfer = |token_id| TransferArgs {
+            from_subaccount: None,
+            to: recipient.clone(),
+            token_id,
+            memo: None,
+            created_at_time: None,
+        };
+        let result = atomic_batch(vec![transfer(1), transfer(3), transfer(2)], |arg| prepare_transfer(owner, arg));
+        let Err(errors) = result else { panic!("batch with a missing token committed") };
+        assert!(matches!(errors.as_slice(), [None, Some(TransferError::NotFound), None]));
+        for token_id in [1, 2] {
+            assert_eq!(NFTS.with(|nfts| nfts.borrow().get(&token_id)).unwrap().owner_account(), default_account(owner));
+        }
+        assert_eq!(BLOCKS.with(|blocks| blocks.borrow().len()), minted_blocks);
+        
+        let result = atomic_batch(vec![transfer(1), transfer(2)], |arg| prepare_transfer(owner, arg));
+        assert!(matches!(result.as_deref(), Ok([_, _])));
+        for token_id in [1, 2] {
+            assert_eq!(NFTS.with(|nfts| nfts.borrow().get(&token_id)).unwrap().owner_account(), recipient);
+        }
+    }
+    
+    #[test]
+    fn parse_range_handles_suffix_open_and_invalid_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 99))));
+        assert_eq!(parse_range("bytes=500-", 1000), Some(Ok((500, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some(Ok((0, 999))));
+        assert_eq!(parse_range("bytes=900-5000", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=50-10", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+        assert_eq!(parse_range("bytes=-10", 0), Some(Err(())));
+        // Multiple ranges and other units get the full body
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+    }
+    
+    #[test]
+    fn etag_matches_lists_weak_tags_and_wildcard() {
+        assert!(etag_matches("\"abc\"", "\"abc\""));
+        assert!(etag_matches("\"x\", W/\"abc\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"abd\"", "\"abc\""));
+        assert!(!etag_matches("abc", "\"abc\""));
+    }
+    
+    #[test]
+    fn http_date_formats_imf_fixdate() {
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(784_111_777 * 1_000_000_000), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(951_782_400 * 1_000_000_000), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+    
+    #[test]
+    fn hash_value_ignores_map_order() {
+        let a = ("a".to_string(), Value::Nat(Nat::from(1u8)));
+        let b = ("b".to_string(), Value::Text("x".to_string()));
+        assert_eq!(hash_value(&Value::Map(vec![a.clone(), b.clone()])), hash_value(&Value::Map(vec![b, a])));
+    }
 }
@@ -51,7 +51,10 @@ class AssetManager {
         key: filename ? [filename] : [],
         content_type: "text/plain",
         data,
-        description: description ? [description] : []
+        description: description ? [description] : [],
+        expected_version: [],
+        encoding: [], // Raw bytes
+        content_encodings: []
       };
       
       const result = await this.backend.upload(args);
@@ -72,7 +75,10 @@ class AssetManager {
         key: [filename],
         content_type: file.type || "application/octet-stream",
         data,
-        description: description ? [description] : []
+        description: description ? [description] : [],
+        expected_version: [],
+        encoding: [], // Raw bytes
+        content_encodings: []
       };
       
       const result = await this.backend.upload(args);
@@ -0,0 +1,332 @@
+// Mock ICRC-1/ICRC-2 ledger for local payment testing
+//
+// Balances, allowances and blocks live in heap memory only. `mint` is open to
+// everyone so test scripts can fund buyers without a minting account.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::{caller, time};
+use ic_cdk_macros::*;
+use std::{cell::RefCell, collections::HashMap};
+
+const FEE: u64 = 10_000;
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq, Hash)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    // The all-zero subaccount is the default one
+    fn normalized(&self) -> Account {
+        let subaccount = self.subaccount.clone().filter(|s| s.iter().any(|b| *b != 0));
+        Account { owner: self.owner, subaccount }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct TransferArgs {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ApproveArgs {
+    from_subaccount: Option<Vec<u8>>,
+    spender: Account,
+    amount: Nat,
+    expected_allowance: Option<Nat>,
+    expires_at: Option<u64>,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Transfer {
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+    spender: Option<Account>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Mint {
+    to: Account,
+    amount: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Transaction {
+    kind: String,
+    mint: Option<Mint>,
+    transfer: Option<Transfer>,
+    timestamp: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct GetTransactionsRequest {
+    start: Nat,
+    length: Nat,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct GetTransactionsResponse {
+    log_length: Nat,
+    first_index: Nat,
+    transactions: Vec<Transaction>,
+}
+
+#[derive(Clone, Debug)]
+struct Allowance {
+    amount: Nat,
+    expires_at: Option<u64>,
+}
+
+thread_local! {
+    static BALANCES: RefCell<HashMap<Account, Nat>> = RefCell::new(HashMap::new());
+    static ALLOWANCES: RefCell<HashMap<(Account, Account), Allowance>> = RefCell::new(HashMap::new());
+    static BLOCKS: RefCell<Vec<Transaction>> = const { RefCell::new(Vec::new()) };
+}
+
+fn balance(account: &Account) -> Nat {
+    BALANCES.with(|b| b.borrow().get(account).cloned().unwrap_or_else(|| Nat::from(0u8)))
+}
+
+fn set_balance(account: Account, amount: Nat) {
+    BALANCES.with(|b| {
+        b.borrow_mut().insert(account, amount);
+    });
+}
+
+fn append_block(tx: Transaction) -> Nat {
+    BLOCKS.with(|blocks| {
+        let mut blocks = blocks.borrow_mut();
+        blocks.push(tx);
+        Nat::from(blocks.len() as u64 - 1)
+    })
+}
+
+fn check_fee(fee: &Option<Nat>) -> Result<(), Nat> {
+    match fee {
+        Some(fee) if *fee != FEE => Err(Nat::from(FEE)),
+        _ => Ok(()),
+    }
+}
+
+#[query]
+fn icrc1_fee() -> Nat {
+    Nat::from(FEE)
+}
+
+#[query]
+fn icrc1_decimals() -> u8 {
+    8
+}
+
+#[query]
+fn icrc1_symbol() -> String {
+    "MOCK".to_string()
+}
+
+#[query]
+fn icrc1_balance_of(account: Account) -> Nat {
+    balance(&account.normalized())
+}
+
+// Credit an account out of thin air - test helper, not part of ICRC-1
+#[update]
+fn mint(to: Account, amount: Nat) -> Nat {
+    let to = to.normalized();
+    set_balance(to.clone(), balance(&to) + amount.clone());
+    append_block(Transaction {
+        kind: "mint".to_string(),
+        mint: Some(Mint { to, amount, memo: None, created_at_time: None }),
+        transfer: None,
+        timestamp: time(),
+    })
+}
+
+#[update]
+fn icrc1_transfer(args: TransferArgs) -> Result<Nat, TransferError> {
+    check_fee(&args.fee).map_err(|expected_fee| TransferError::BadFee { expected_fee })?;
+    
+    let from = Account { owner: caller(), subaccount: args.from_subaccount }.normalized();
+    let to = args.to.normalized();
+    let total = args.amount.clone() + Nat::from(FEE);
+    let from_balance = balance(&from);
+    if from_balance < total {
+        return Err(TransferError::InsufficientFunds { balance: from_balance });
+    }
+    
+    set_balance(from.clone(), from_balance - total);
+    set_balance(to.clone(), balance(&to) + args.amount.clone());
+    
+    Ok(append_block(Transaction {
+        kind: "transfer".to_string(),
+        mint: None,
+        transfer: Some(Transfer {
+            from,
+            to,
+            amount: args.amount,
+            fee: Some(Nat::from(FEE)),
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+            spender: None,
+        }),
+        timestamp: time(),
+    }))
+}
+
+#[update]
+fn icrc2_approve(args: ApproveArgs) -> Result<Nat, ApproveError> {
+    check_fee(&args.fee).map_err(|expected_fee| ApproveError::BadFee { expected_fee })?;
+    
+    let owner = Account { owner: caller(), subaccount: args.from_subaccount }.normalized();
+    let spender = args.spender.normalized();
+    let owner_balance = balance(&owner);
+    if owner_balance < FEE {
+        return Err(ApproveError::InsufficientFunds { balance: owner_balance });
+    }
+    
+    let key = (owner.clone(), spender);
+    let current = ALLOWANCES.with(|a| a.borrow().get(&key).map(|a| a.amount.clone()))
+        .unwrap_or_else(|| Nat::from(0u8));
+    if let Some(expected) = args.expected_allowance {
+        if expected != current {
+            return Err(ApproveError::AllowanceChanged { current_allowance: current });
+        }
+    }
+    
+    set_balance(owner, owner_balance - Nat::from(FEE));
+    ALLOWANCES.with(|a| {
+        a.borrow_mut().insert(key, Allowance { amount: args.amount, expires_at: args.expires_at });
+    });
+    
+    Ok(append_block(Transaction {
+        kind: "approve".to_string(),
+        mint: None,
+        transfer: None,
+        timestamp: time(),
+    }))
+}
+
+#[update]
+fn icrc2_transfer_from(args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    check_fee(&args.fee).map_err(|expected_fee| TransferFromError::BadFee { expected_fee })?;
+    
+    let spender = Account { owner: caller(), subaccount: args.spender_subaccount }.normalized();
+    let from = args.from.normalized();
+    let to = args.to.normalized();
+    let total = args.amount.clone() + Nat::from(FEE);
+    
+    let key = (from.clone(), spender.clone());
+    let allowance = ALLOWANCES.with(|a| a.borrow().get(&key).cloned())
+        .filter(|a| a.expires_at.is_none_or(|expires_at| expires_at > time()))
+        .map(|a| a.amount)
+        .unwrap_or_else(|| Nat::from(0u8));
+    if allowance < total {
+        return Err(TransferFromError::InsufficientAllowance { allowance });
+    }
+    
+    let from_balance = balance(&from);
+    if from_balance < total {
+        return Err(TransferFromError::InsufficientFunds { balance: from_balance });
+    }
+    
+    set_balance(from.clone(), from_balance - total.clone());
+    set_balance(to.clone(), balance(&to) + args.amount.clone());
+    ALLOWANCES.with(|a| {
+        if let Some(entry) = a.borrow_mut().get_mut(&key) {
+            entry.amount = entry.amount.clone() - total;
+        }
+    });
+    
+    Ok(append_block(Transaction {
+        kind: "transfer".to_string(),
+        mint: None,
+        transfer: Some(Transfer {
+            from,
+            to,
+            amount: args.amount,
+            fee: Some(Nat::from(FEE)),
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+            spender: Some(spender),
+        }),
+        timestamp: time(),
+    }))
+}
+
+#[query]
+fn get_transactions(request: GetTransactionsRequest) -> GetTransactionsResponse {
+    let start: usize = request.start.0.clone().try_into().unwrap_or(usize::MAX);
+    let length: usize = request.length.0.try_into().unwrap_or(usize::MAX);
+    
+    BLOCKS.with(|blocks| {
+        let blocks = blocks.borrow();
+        GetTransactionsResponse {
+            log_length: Nat::from(blocks.len() as u64),
+            first_index: request.start.clone(),
+            transactions: blocks.iter().skip(start).take(length).cloned().collect(),
+        }
+    })
+}
@@ -0,0 +1,65 @@
+#!/bin/bash
+
+# Payment test script for ICRC37+ NFT minting against the local mock ledger:
+# 1. Deploy the mock ledger and fund the current identity
+# 2. Point the collection at the mock ledger and enable a priced schedule
+# 3. Mint with an ICRC-2 approval
+# 4. Mint with a prior ICRC-1 transfer block
+
+# Configuration
+CANISTER_ID=${1:-"bkyz2-fmaaa-aaaaa-qaaaq-cai"}
+ASSET_A=${2:-"1.png"}
+ASSET_B=${3:-"2.png"}
+PRICE=100000000
+FEE=10000
+
+echo "====== ICRC37+ Payment Test Script ======"
+echo "Using canister ID: $CANISTER_ID"
+echo "========================================="
+
+PRINCIPAL=$(dfx identity get-principal)
+
+# Step 1: Deploy and fund the mock ledger
+echo -e "\n1. Deploying mock ledger..."
+dfx deploy mock_ledger
+LEDGER_ID=$(dfx canister id mock_ledger)
+dfx canister call mock_ledger mint '(record { owner = principal "'$PRINCIPAL'"; subaccount = null }, 1_000_000_000 : nat)'
+
+# Step 2: Configure the collection
+echo -e "\n2. Configuring payment ledger and mint schedule..."
+dfx canister call $CANISTER_ID update_collection_details '(record {
+  pricing_enabled = opt true;
+})'
+dfx canister call $CANISTER_ID update_mint_schedule '(record {
+  name = "Standard";
+  bundle_prices = vec { record { quantity = 1 : nat64; price = '$PRICE' : nat } };
+  start_time = null;
+  end_time = null;
+  active = opt true;
+  whitelist_only = opt false;
+  payment_ledger = opt principal "'$LEDGER_ID'";
+  payment_symbol = opt "MOCK";
+  payment_decimals = opt (8 : nat8);
+})'
+
+# Step 3: Pay with an ICRC-2 approval
+echo -e "\n3. Minting $ASSET_A with an ICRC-2 approval..."
+dfx canister call mock_ledger icrc2_approve '(record {
+  spender = record { owner = principal "'$CANISTER_ID'"; subaccount = null };
+  amount = '$((PRICE + FEE))' : nat;
+})'
+dfx canister call $CANISTER_ID mint '(record { asset_id = "'$ASSET_A'"; payment = null; schedule = opt "Standard" })'
+
+# Step 4: Pay with a prior transfer
+echo -e "\n4. Minting $ASSET_B with an ICRC-1 transfer block..."
+block=$(dfx canister call mock_ledger icrc1_transfer '(record {
+  to = record { owner = principal "'$CANISTER_ID'"; subaccount = null };
+  amount = '$PRICE' : nat;
+})' | grep -o -E '[0-9_]+ : nat' | grep -o -E '[0-9_]+')
+echo "Payment sent in block $block"
+dfx canister call $CANISTER_ID mint '(record { asset_id = "'$ASSET_B'"; payment = opt variant { Icrc1Transfer = record { block_index = '$block' : nat } }; schedule = opt "Standard" })'
+
+echo -e "\n5. Treasury balance:"
+dfx canister call mock_ledger icrc1_balance_of '(record { owner = principal "'$CANISTER_ID'"; subaccount = null })'
+
+echo -e "\n====== Test completed ======"